@@ -0,0 +1,113 @@
+#include <chrono>
+#include <cmath>
+#include <cstring>
+#include <iostream>
+#include <map>
+#include <string>
+
+#include "anyroot.h"
+
+/// same query as `TreeQuery` in main.cpp, but decoded by anyroot and read
+/// through the Arrow C Data Interface
+
+static double read_f64(const struct ArrowArray *col, int64_t row, bool native) {
+  const auto *values = static_cast<const uint64_t *>(col->buffers[1]);
+  uint64_t bits = values[col->offset + row];
+  if (!native) bits = __builtin_bswap64(bits);
+  double v;
+  std::memcpy(&v, &bits, sizeof(v));
+  return v;
+}
+
+static int TreeQuery(AnyrootTree *tree, bool show) {
+  auto ts_init = std::chrono::steady_clock::now();
+
+  const char *wanted[] = {"H1_PX", "H1_PY", "H1_PZ"};
+  uint64_t colmask = 0;
+  for (auto name : wanted) {
+    bool found = false;
+    for (size_t i = 0; i != anyroot_tree_branch_count(tree); ++i) {
+      if (std::strcmp(anyroot_tree_branch_name(tree, i), name) == 0) {
+        colmask |= uint64_t{1} << i;
+        found = true;
+      }
+    }
+    if (!found) {
+      std::cerr << "branch " << name << " not found" << std::endl;
+      return 1;
+    }
+  }
+  bool native = anyroot_native_endian();
+
+  std::chrono::steady_clock::time_point ts_first = std::chrono::steady_clock::now();
+  std::map<long, long> buckets;
+  for (size_t rg = 0; rg != anyroot_tree_rowgroup_count(tree); ++rg) {
+    struct ArrowArray batch;
+    struct ArrowSchema schema;
+    if (anyroot_tree_read_rowgroup(tree, rg, colmask, &batch, &schema) != 0) {
+      std::cerr << "failed to read row group " << rg << ": " << anyroot_last_error() << std::endl;
+      return 1;
+    }
+    // children are in branch order; look them up by name
+    const struct ArrowArray *cols[3];
+    for (int c = 0; c != 3; ++c) {
+      for (int64_t i = 0; i != schema.n_children; ++i) {
+        if (std::strcmp(schema.children[i]->name, wanted[c]) == 0) cols[c] = batch.children[i];
+      }
+    }
+    for (int64_t row = 0; row != batch.length; ++row) {
+      double h1_px = read_f64(cols[0], row, native);
+      double h1_py = read_f64(cols[1], row, native);
+      double h1_pz = read_f64(cols[2], row, native);
+      double magnitude = sqrt((h1_px * h1_px) + (h1_py * h1_py) + (h1_pz * h1_pz));
+      long bucket = static_cast<long>(round(magnitude / 10000)) * 10000;
+      buckets[bucket] += 1;
+    }
+    batch.release(&batch);
+    schema.release(&schema);
+  }
+  if (show) {
+    for (auto &[bucket, cnt] : buckets) {
+      std::cout << bucket << "," << cnt << std::endl;
+    }
+  }
+  std::cout << "found " << buckets.size() << " buckets with cnt[0] " << buckets[0] << std::endl;
+  auto ts_end = std::chrono::steady_clock::now();
+  auto runtime_init =
+      std::chrono::duration_cast<std::chrono::microseconds>(ts_first - ts_init).count();
+  auto runtime_analyze =
+      std::chrono::duration_cast<std::chrono::microseconds>(ts_end - ts_first).count();
+
+  std::cout << "Runtime-Initialization: " << runtime_init << "us" << std::endl;
+  std::cout << "Runtime-Analysis: " << runtime_analyze << "us" << std::endl;
+  return 0;
+}
+
+auto main(int argc, char *argv[]) -> int {
+  if (argc < 2) {
+    std::cerr << "No file provided" << std::endl;
+    return 1;
+  }
+  auto file = anyroot_open_file(argv[1]);
+  if (!file) {
+    std::cerr << "failed to open " << argv[1] << ": " << anyroot_last_error() << std::endl;
+    return 1;
+  }
+  auto tree = anyroot_tree_open(file, "DecayTree");
+  if (!tree) {
+    std::cerr << "failed to open DecayTree: " << anyroot_last_error() << std::endl;
+    anyroot_file_close(file);
+    return 1;
+  }
+  std::cout << "DecayTree: " << anyroot_tree_entries(tree) << " entries, "
+            << anyroot_tree_rowgroup_count(tree) << " row groups" << std::endl;
+  using clock = std::chrono::steady_clock;
+  for (auto i = 0; i != 3; ++i) {
+    auto start = clock::now();
+    if (TreeQuery(tree, argc >= 3) != 0) break;
+    auto end = clock::now();
+    std::cout << "run " << i << " total time: " << std::chrono::duration_cast<std::chrono::milliseconds>(end - start).count() << "ms" << std::endl;
+  }
+  anyroot_tree_free(tree);
+  anyroot_file_close(file);
+}
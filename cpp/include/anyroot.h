@@ -0,0 +1,163 @@
+#ifndef ANYROOT_H
+#define ANYROOT_H
+
+/* Generated by cbindgen from src/capi.rs; do not edit by hand. */
+
+#include <stdbool.h>
+#include <stdint.h>
+#include <stddef.h>
+
+#ifndef ARROW_C_DATA_INTERFACE
+#define ARROW_C_DATA_INTERFACE
+
+#define ARROW_FLAG_DICTIONARY_ORDERED 1
+#define ARROW_FLAG_NULLABLE 2
+#define ARROW_FLAG_MAP_KEYS_SORTED 4
+
+struct ArrowSchema {
+  const char* format;
+  const char* name;
+  const char* metadata;
+  int64_t flags;
+  int64_t n_children;
+  struct ArrowSchema** children;
+  struct ArrowSchema* dictionary;
+  void (*release)(struct ArrowSchema*);
+  void* private_data;
+};
+
+struct ArrowArray {
+  int64_t length;
+  int64_t null_count;
+  int64_t offset;
+  int64_t n_buffers;
+  int64_t n_children;
+  const void** buffers;
+  struct ArrowArray** children;
+  struct ArrowArray* dictionary;
+  void (*release)(struct ArrowArray*);
+  void* private_data;
+};
+
+#endif  /* ARROW_C_DATA_INTERFACE */
+
+
+// Opaque handle to an open ROOT file
+typedef struct AnyrootFile AnyrootFile;
+
+// Opaque handle to a `TTree` of an open file, including its row group layout
+typedef struct AnyrootTree AnyrootTree;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+// Message describing the last error on this thread, or `NULL` if there was none.
+// The string stays valid until the next failing call on the same thread.
+const char *anyroot_last_error(void);
+
+// Whether exported numeric buffers are in native byte order (see module docs)
+bool anyroot_native_endian(void);
+
+// Open and memory-map the ROOT file at `path`
+//
+// # Safety
+// `path` must be a valid, NUL-terminated string.
+struct AnyrootFile *anyroot_open_file(const char *path);
+
+// Open a ROOT file from an in-memory buffer without copying it
+//
+// # Safety
+// `data` must point to `len` readable bytes that stay valid and unchanged
+// until the file, every tree opened from it and every array exported from
+// these trees are released: uncompressed baskets are exported without
+// copying them.
+struct AnyrootFile *anyroot_open_buffer(const uint8_t *data, size_t len);
+
+// Close a file opened with `anyroot_open_file` or `anyroot_open_buffer`
+//
+// # Safety
+// `file` must be `NULL` or a handle that was not closed yet.
+void anyroot_file_close(struct AnyrootFile *file);
+
+// Number of distinct `TTree`s in the file
+//
+// # Safety
+// `file` must be a valid file handle.
+size_t anyroot_file_tree_count(const struct AnyrootFile *file);
+
+// Name of the `idx`-th tree, or `NULL` if out of range. Owned by the file handle.
+//
+// # Safety
+// `file` must be a valid file handle.
+const char *anyroot_file_tree_name(const struct AnyrootFile *file, size_t idx);
+
+// Parse the tree called `name` and discover its row groups. `name` refers
+// to the highest cycle of the tree, `name;N` to cycle `N`.
+//
+// # Safety
+// `file` must be a valid file handle and `name` a NUL-terminated string.
+struct AnyrootTree *anyroot_tree_open(const struct AnyrootFile *file, const char *name);
+
+// Free a tree opened with `anyroot_tree_open`
+//
+// # Safety
+// `tree` must be `NULL` or a handle that was not freed yet.
+void anyroot_tree_free(struct AnyrootTree *tree);
+
+// Number of entries (rows) in the tree
+//
+// # Safety
+// `tree` must be a valid tree handle.
+int64_t anyroot_tree_entries(const struct AnyrootTree *tree);
+
+// Number of top-level branches (columns) in the tree
+//
+// # Safety
+// `tree` must be a valid tree handle.
+size_t anyroot_tree_branch_count(const struct AnyrootTree *tree);
+
+// Name of the `idx`-th branch, or `NULL` if out of range. Owned by the tree handle.
+//
+// # Safety
+// `tree` must be a valid tree handle.
+const char *anyroot_tree_branch_name(const struct AnyrootTree *tree, size_t idx);
+
+// Element type (e.g. `f64`) of the `idx`-th branch, or `NULL` if out of range
+//
+// # Safety
+// `tree` must be a valid tree handle.
+const char *anyroot_tree_branch_type(const struct AnyrootTree *tree, size_t idx);
+
+// Number of row groups (aligned basket clusters) in the tree
+//
+// # Safety
+// `tree` must be a valid tree handle.
+size_t anyroot_tree_rowgroup_count(const struct AnyrootTree *tree);
+
+// First entry and entry count of the `idx`-th row group
+//
+// # Safety
+// `tree` must be a valid tree handle; `start` and `count` must be writable.
+int anyroot_tree_rowgroup_range(const struct AnyrootTree *tree, size_t idx, int64_t *start, int64_t *count);
+
+// Export the Arrow schema of the columns selected by `colmask` (bit `i` selects branch `i`)
+//
+// # Safety
+// `tree` must be a valid tree handle and `out` point to writable, uninitialized
+// `ArrowSchema` storage. The caller must release the schema.
+int anyroot_tree_schema(const struct AnyrootTree *tree, uint64_t colmask, struct ArrowSchema *out);
+
+// Decode the `idx`-th row group into a struct array with one child per selected column
+//
+// # Safety
+// `tree` must be a valid tree handle, `out_array` and `out_schema` must point
+// to writable, uninitialized `ArrowArray`/`ArrowSchema` storage. The caller
+// must release both.
+int anyroot_tree_read_rowgroup(const struct AnyrootTree *tree, size_t idx, uint64_t colmask, struct ArrowArray *out_array, struct ArrowSchema *out_schema);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* ANYROOT_H */
@@ -7,17 +7,32 @@
 
 use std::{sync::Arc};
 
-use crate::{anyblox::{ColumnProjection, rowgroup::RowGroup}, tree_reader::Tree};
+use std::ptr::NonNull;
+
+use crate::{anyblox::{ColumnProjection, FetchedBaskets, ReadPlan, rowgroup::{RowGroup, RowGroupDecodeCursor}}, core::Source, tree_reader::{basket_header, Tree}};
+use aligned_vec::AVec;
+use bytes::Bytes;
+use failure::Error;
+#[cfg(all(feature = "parallel", not(target_arch = "wasm32")))]
+use crate::anyblox::{DecodedColumn, ParallelDecoder};
 use arrow::{
     array::*,
+    buffer::Buffer,
     datatypes::*,
-    record_batch::RecordBatch,
+    record_batch::{RecordBatch, RecordBatchOptions},
 };
 use nom::number::complete::*;
+#[cfg(not(feature = "slow_endian_parsing"))]
+use arrow::buffer::ScalarBuffer;
 
 pub fn string_to_arrow_type(s: &str) -> DataType {
+    try_string_to_arrow_type(s).unwrap_or_else(|| panic!("unknown data type {}", s))
+}
+
+/// Arrow type of a column of type `s`, if it can be decoded
+pub fn try_string_to_arrow_type(s: &str) -> Option<DataType> {
     // TODO more types
-    match s {
+    Some(match s {
         "f64" => DataType::Float64,
         "u64" => DataType::UInt64,
         "i64" => DataType::Int64,
@@ -25,8 +40,15 @@ pub fn string_to_arrow_type(s: &str) -> DataType {
         "u32" => DataType::UInt32,
         "i32" => DataType::Int32,
         "bool" => DataType::Boolean,
-       _ => panic!("unknown data type {}", s)
-    }
+        _ => return None,
+    })
+}
+
+/// Mask of the columns among `branches` whose type can be decoded
+pub fn supported_columns(branches: &[(String, String)]) -> u64 {
+    branches.iter().take(64).enumerate()
+        .filter(|(_, (_, ty))| try_string_to_arrow_type(ty).is_some())
+        .fold(0u64, |mask, (idx, _)| mask | 1 << idx)
 }
 
 pub fn branches_to_arrow_schema(branches: &[(String, String)], cols: u64) -> Schema {
@@ -41,7 +63,7 @@ pub fn branches_to_arrow_schema(branches: &[(String, String)], cols: u64) -> Sch
 }
 
 pub fn tree_to_arrow_schema(tree: &Tree, cols: u64) -> Schema {
-    branches_to_arrow_schema(&tree.main_branch_names_and_types().as_slice(), cols)
+    branches_to_arrow_schema(tree.main_branch_names_and_types().as_slice(), cols)
 }
 
 fn be_bool<I, E>(input: I) -> nom::IResult<I, bool, E>
@@ -52,80 +74,319 @@ where
     nom::combinator::map(le_u8::<I, E>, |b| b != 0)(input)
 }
 
-pub fn rowgroup_to_record_batch(mmap: &[u8], colmask: u64, rg: &RowGroup, sc: Arc<Schema>) -> RecordBatch {
-    let mut arrays: Vec<ArrayRef> = Vec::new();
-    arrays.reserve(colmask.count_ones() as usize);
-    let arrays = rg.decode(mmap, colmask, arrays, |mut cols, cursor, data| {
-        let coltype = sc.field(cursor.projected_col_idx).data_type();
-        let cnt = rg.count as usize;
-        macro_rules! parse_array_nom(
-            ($arr:ident, $parser:ident, $cnt:expr) => {
-                nom::multi::fold_many_m_n(
-                    $cnt, $cnt, $parser::<&[u8], nom::error::Error<&[u8]>>,
-                    move || $arr::builder($cnt), |mut bld, val| {
-                        bld.append_value(val);
-                        bld
-                    })(data).unwrap().1.finish()
-            }
-        );
-        macro_rules! unsafe_cast_array(
-            ($arr:ident, $type:ident, $cnt:expr) => {
-                // append_slice (transmute(data))
-                (|| { // let bytes = bytes::Bytes::copy_from_slice(data);
-                    // let buf = $arr::new(arrow::buffer::ScalarBuffer::from(arrow::buffer::Buffer::from(bytes)), None);
-                    let ptr = data.as_ptr() as *const <$type as arrow::array::ArrowPrimitiveType>::Native;
-                    let mut bld = $arr::builder($cnt);
-                    bld.append_slice(unsafe { std::slice::from_raw_parts(ptr, $cnt) });
-                    bld.finish()
-                })()
+/// Convert the decoded bytes of one column to an array of `coltype`
+fn column_to_array(data: &[u8], cursor: &RowGroupDecodeCursor, coltype: &DataType, cnt: usize) -> ArrayRef {
+    macro_rules! parse_array_nom(
+        ($arr:ident, $parser:ident, $cnt:expr) => {
+            nom::multi::fold_many_m_n(
+                $cnt, $cnt, $parser::<&[u8], nom::error::Error<&[u8]>>,
+                move || $arr::builder($cnt), |mut bld, val| {
+                    bld.append_value(val);
+                    bld
+                })(data).unwrap().1.finish()
+        }
+    );
+    #[cfg(not(feature = "slow_endian_parsing"))]
+    macro_rules! unsafe_cast_array(
+        ($arr:ident, $type:ident, $cnt:expr) => {
+            // append_slice (transmute(data))
+            { // let bytes = bytes::Bytes::copy_from_slice(data);
+                // let buf = $arr::new(arrow::buffer::ScalarBuffer::from(arrow::buffer::Buffer::from(bytes)), None);
+                let ptr = data.as_ptr() as *const <$type as arrow::array::ArrowPrimitiveType>::Native;
+                let mut bld = $arr::builder($cnt);
+                bld.append_slice(unsafe { std::slice::from_raw_parts(ptr, $cnt) });
+                bld.finish()
             }
-        );
-        // slower variant returning native-endian arrays
-        #[cfg(feature = "slow_endian_parsing")]
-        macro_rules! parse_array(
-            ($type:ident, $arr:ident, $parser:ident, $cnt:expr) => {parse_array_nom!($arr, $parser, $cnt)}
-        );
-        // unsafe variant returning big-endian arrays (root format)
+        }
+    );
+    // slower variant returning native-endian arrays
+    #[cfg(feature = "slow_endian_parsing")]
+    macro_rules! parse_array(
+        ($type:ident, $arr:ident, $parser:ident, $cnt:expr) => {parse_array_nom!($arr, $parser, $cnt)}
+    );
+    // unsafe variant returning big-endian arrays (root format)
+    #[cfg(not(feature = "slow_endian_parsing"))]
+    macro_rules! parse_array(
+        ($type:ident, $arr:ident, $parser:ident, $cnt:expr) => {unsafe_cast_array!($arr, $type, $cnt)}
+    );
+    match coltype {
+        DataType::UInt32 => {
+            assert!(cursor.byte_count/4 == cnt);
+            Arc::new(parse_array!(UInt32Type, UInt32Array, be_u32, cnt))
+        }
+        DataType::Int32 => {
+            assert!(cursor.byte_count/4 == cnt);
+            Arc::new(parse_array!(Int32Type, Int32Array, be_i32, cnt))
+        }
+        DataType::Float32 => {
+            assert!(cursor.byte_count/4 == cnt);
+            Arc::new(parse_array!(Float32Type, Float32Array, be_f32, cnt))
+        }
+        DataType::UInt64 => {
+            assert!(cursor.byte_count/8 == cnt);
+            Arc::new(parse_array!(UInt64Type, UInt64Array, be_u64, cnt))
+        }
+        DataType::Int64 => {
+            assert!(cursor.byte_count/8 == cnt);
+            Arc::new(parse_array!(Int64Type, Int64Array, be_i64, cnt))
+        }
+        DataType::Float64 => {
+            assert!(cursor.byte_count/8 == cnt);
+            Arc::new(parse_array!(Float64Type, Float64Array, be_f64, cnt))
+        }
+        DataType::Boolean => {
+            assert!(cursor.byte_count == cnt);
+            // XXX arrow primitive type boolean
+            Arc::new(parse_array_nom!(BooleanArray, be_bool, cnt))
+        }
+        _ => panic!("unsupported data type in rowgroup_to_record_batch"),
+    }
+}
+
+/// Hands a decoded column chunk to Arrow without copying; the buffer
+/// frees the `AVec` when the last array using it is dropped
+fn decoded_to_buffer(data: AVec<u8>, len: usize) -> Buffer {
+    assert!(len <= data.len());
+    let ptr = NonNull::new(data.as_ptr() as *mut u8).unwrap();
+    // Safety: the allocation behind `ptr` is owned by `data` and lives as long as the buffer
+    unsafe { Buffer::from_custom_allocation(ptr, len, Arc::new(data)) }
+}
+
+/// Convert a column chunk held in `buf` to an array of `coltype`. Primitive
+/// arrays use `buf` as their values buffer, see `column_to_array` for
+/// the endianness caveats; other types are parsed into a new array.
+fn buffer_to_array(buf: Buffer, cursor: &RowGroupDecodeCursor, coltype: &DataType, cnt: usize) -> ArrayRef {
+    #[cfg(not(feature = "slow_endian_parsing"))]
+    macro_rules! wrap_buffer(
+        ($arr:ident, $width:expr) => {{
+            assert!(cursor.byte_count/$width == cnt);
+            Arc::new($arr::new(ScalarBuffer::new(buf, 0, cnt), None))
+        }}
+    );
+    match coltype {
         #[cfg(not(feature = "slow_endian_parsing"))]
-        macro_rules! parse_array(
-            ($type:ident, $arr:ident, $parser:ident, $cnt:expr) => {unsafe_cast_array!($arr, $type, $cnt)}
-        );
-        let arr: ArrayRef = match coltype {
-            DataType::UInt32 => {
-                assert!(cursor.byte_count/4 == cnt);
-                Arc::new(parse_array!(UInt32Type, UInt32Array, be_u32, cnt))
-            }
-            DataType::Int32 => {
-                assert!(cursor.byte_count/4 == cnt);
-                Arc::new(parse_array!(Int32Type, Int32Array, le_i32, cnt))
-            }
-            DataType::Float32 => {
-                assert!(cursor.byte_count/4 == cnt);
-                Arc::new(parse_array!(Float32Type, Float32Array, be_f32, cnt))
-            }
-            DataType::UInt64 => {
-                assert!(cursor.byte_count/8 == cnt);
-                Arc::new(parse_array!(UInt64Type, UInt64Array, be_u64, cnt))
-            }
-            DataType::Int64 => {
-                assert!(cursor.byte_count/8 == cnt);
-                Arc::new(parse_array!(Int64Type, Int64Array, be_i64, cnt))
-            }
-            DataType::Float64 => {
-                assert!(cursor.byte_count/8 == cnt);
-                Arc::new(parse_array!(Float64Type, Float64Array, be_f64, cnt))
+        DataType::UInt32 => wrap_buffer!(UInt32Array, 4),
+        #[cfg(not(feature = "slow_endian_parsing"))]
+        DataType::Int32 => wrap_buffer!(Int32Array, 4),
+        #[cfg(not(feature = "slow_endian_parsing"))]
+        DataType::Float32 => wrap_buffer!(Float32Array, 4),
+        #[cfg(not(feature = "slow_endian_parsing"))]
+        DataType::UInt64 => wrap_buffer!(UInt64Array, 8),
+        #[cfg(not(feature = "slow_endian_parsing"))]
+        DataType::Int64 => wrap_buffer!(Int64Array, 8),
+        #[cfg(not(feature = "slow_endian_parsing"))]
+        DataType::Float64 => wrap_buffer!(Float64Array, 8),
+        _ => column_to_array(buf.as_slice(), cursor, coltype, cnt),
+    }
+}
+
+/// Column `colid` of `rg` as a buffer sharing the fetched data instead of
+/// decoding it. Only possible if the column chunk is a single
+/// uncompressed basket whose payload is aligned for `coltype`.
+fn borrowed_column(fetched: &FetchedBaskets, rg: &RowGroup, colid: usize, coltype: &DataType) -> Option<Buffer> {
+    if cfg!(feature = "slow_endian_parsing") {
+        return None;
+    }
+    let width = coltype.primitive_width()?;
+    let [basket] = rg.containers[colid].as_slice() else {
+        return None;
+    };
+    let meta = basket_header(fetched.bytes(basket).ok()?).ok()?.1;
+    let payload = meta.buf.get(..meta.useful_bytes())?;
+    if meta.is_compressed() || payload.as_ptr().align_offset(width) != 0 {
+        return None;
+    }
+    trace!("column {} of row group at {} is used without copying", colid, rg.start_tid);
+    fetched.slice_ref(basket, payload).map(Buffer::from)
+}
+
+/// Record batch of the columns of `rg` in `colmask`, read from `mmap`
+/// holding the whole file. Every column is decoded into a buffer of its
+/// own, see `rowgroup_to_record_batch_shared` to avoid the copies.
+/// Fails if a basket is damaged.
+pub fn rowgroup_to_record_batch(mmap: &[u8], colmask: u64, rg: &RowGroup, sc: Arc<Schema>) -> Result<RecordBatch, Error> {
+    columns_to_record_batch(colmask, rg, sc, |cursor, _| {
+        let (data, written) = rg.decode_column(cursor.global_col_idx, &|basket| basket.bytes(mmap))?;
+        Ok(decoded_to_buffer(data, written))
+    })
+}
+
+/// Like `rowgroup_to_record_batch`, but single uncompressed baskets are
+/// shared with `data` instead of copied, see
+/// `rowgroup_to_record_batch_from_fetched`
+pub fn rowgroup_to_record_batch_shared(data: &Bytes, colmask: u64, rg: &RowGroup, sc: Arc<Schema>) -> Result<RecordBatch, Error> {
+    rowgroup_to_record_batch_from_fetched(&FetchedBaskets::whole_file(data.clone()), colmask, rg, sc)
+}
+
+/// Record batch of the columns of `rg` in `colmask`, with the buffer of
+/// each column provided by `column`
+fn columns_to_record_batch<F>(colmask: u64, rg: &RowGroup, sc: Arc<Schema>, mut column: F) -> Result<RecordBatch, Error>
+    where F: FnMut(&RowGroupDecodeCursor, &DataType) -> Result<Buffer, Error>
+{
+    let mut arrays: Vec<ArrayRef> = Vec::with_capacity(colmask.count_ones() as usize);
+    for cursor in projected_columns(rg, colmask) {
+        let coltype = sc.field(cursor.projected_col_idx).data_type();
+        let buf = column(&cursor, coltype)?;
+        let cursor = RowGroupDecodeCursor { byte_count: buf.len(), ..cursor };
+        assert!(cursor.projected_col_idx == arrays.len());
+        arrays.push(buffer_to_array(buf, &cursor, coltype, rg.count as usize));
+    }
+    Ok(RecordBatch::try_new(sc, arrays)?)
+}
+
+/// Cursors of the columns of `rg` in `colmask`, with a `byte_count` of 0
+fn projected_columns(rg: &RowGroup, colmask: u64) -> impl Iterator<Item = RowGroupDecodeCursor> {
+    let mask = ColumnProjection::from_u64(colmask);
+    (0..rg.containers.len())
+        .filter(move |colid| mask.contains(*colid as u32))
+        .enumerate()
+        .map(|(projected_col_idx, global_col_idx)| RowGroupDecodeCursor { global_col_idx, projected_col_idx, byte_count: 0 })
+}
+
+/// Like `rowgroup_to_record_batch`, but reads the baskets from `source`,
+/// merging reads closer than `max_gap` bytes (see `ReadPlan`)
+pub fn rowgroup_to_record_batch_from_source(source: &Source, colmask: u64, rg: &RowGroup, sc: Arc<Schema>, max_gap: u64) -> Result<RecordBatch, Error> {
+    let fetched = ReadPlan::new(rg, colmask, max_gap).execute(source)?;
+    rowgroup_to_record_batch_from_fetched(&fetched, colmask, rg, sc)
+}
+
+/// Like `rowgroup_to_record_batch`, with baskets read beforehand by a
+/// `ReadPlan` for `colmask`. Single uncompressed baskets are not copied,
+/// the arrays share the fetched `Bytes` (e.g. the file mapping).
+pub fn rowgroup_to_record_batch_from_fetched(fetched: &FetchedBaskets, colmask: u64, rg: &RowGroup, sc: Arc<Schema>) -> Result<RecordBatch, Error> {
+    columns_to_record_batch(colmask, rg, sc, |cursor, coltype| {
+        Ok(match borrowed_column(fetched, rg, cursor.global_col_idx, coltype) {
+            Some(buf) => buf,
+            None => {
+                let (data, written) = rg.decode_column(cursor.global_col_idx, &|basket| fetched.bytes(basket))?;
+                decoded_to_buffer(data, written)
             }
-            DataType::Boolean => {
-                assert!(cursor.byte_count == cnt);
-                // XXX arrow primitive type boolean
-                Arc::new(parse_array_nom!(BooleanArray, be_bool, cnt))
+        })
+    })
+}
+
+/// Like `rowgroup_to_record_batch`, decompressing the columns on the threads of `decoder`
+#[cfg(all(feature = "parallel", not(target_arch = "wasm32")))]
+pub fn rowgroup_to_record_batch_parallel(decoder: &ParallelDecoder, mmap: &[u8], colmask: u64, rg: &RowGroup, sc: Arc<Schema>) -> Result<RecordBatch, Error> {
+    let columns = decoder.decode_columns(rg, |basket| basket.bytes(mmap), colmask)?;
+    decoded_columns_to_record_batch(columns, rg, sc)
+}
+
+/// Record batch of the columns of `rg` decoded by a `ParallelDecoder`,
+/// handing their buffers to Arrow
+#[cfg(all(feature = "parallel", not(target_arch = "wasm32")))]
+pub fn decoded_columns_to_record_batch(columns: Vec<DecodedColumn>, rg: &RowGroup, sc: Arc<Schema>) -> Result<RecordBatch, Error> {
+    let arrays = columns.into_iter()
+        .map(|c| {
+            let coltype = sc.field(c.cursor.projected_col_idx).data_type();
+            buffer_to_array(decoded_to_buffer(c.data, c.cursor.byte_count), &c.cursor, coltype, rg.count as usize)
+        })
+        .collect();
+    Ok(RecordBatch::try_new(sc, arrays)?)
+}
+
+/// Swap the big-endian values of the numeric columns of `batch` (see
+/// `column_to_array`) to native byte order
+pub fn to_native_endian(batch: &RecordBatch) -> Result<RecordBatch, Error> {
+    if cfg!(feature = "slow_endian_parsing") {
+        return Ok(batch.clone());
+    }
+    macro_rules! swap(
+        ($col:expr, $type:ident, $conv:expr) => {
+            Arc::new($col.as_primitive::<$type>().unary::<_, $type>($conv)) as ArrayRef
+        }
+    );
+    let columns = batch.columns().iter().map(|col| match col.data_type() {
+        DataType::UInt32 => swap!(col, UInt32Type, u32::from_be),
+        DataType::Int32 => swap!(col, Int32Type, i32::from_be),
+        DataType::Float32 => swap!(col, Float32Type, |v: f32| f32::from_bits(u32::from_be(v.to_bits()))),
+        DataType::UInt64 => swap!(col, UInt64Type, u64::from_be),
+        DataType::Int64 => swap!(col, Int64Type, i64::from_be),
+        DataType::Float64 => swap!(col, Float64Type, |v: f64| f64::from_bits(u64::from_be(v.to_bits()))),
+        _ => col.clone(),
+    }).collect();
+    // keeps the row count of batches without columns
+    let options = RecordBatchOptions::new().with_row_count(Some(batch.num_rows()));
+    Ok(RecordBatch::try_new_with_options(batch.schema(), columns, &options)?)
+}
+
+// with `slow_endian_parsing` every column is copied while swapping its bytes
+#[cfg(all(test, not(target_arch = "wasm32"), not(feature = "slow_endian_parsing")))]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+    use crate::anyblox::{RowGroupIndex, DEFAULT_MAX_GAP};
+    use crate::core::RootFile;
+
+    /// Whether the values of column `col` of `batch` lie within `data`
+    fn shares(batch: &RecordBatch, col: usize, data: &[u8]) -> bool {
+        let ptr = batch.column(col).to_data().buffers()[0].as_ptr() as usize;
+        (data.as_ptr() as usize..data.as_ptr() as usize + data.len()).contains(&ptr)
+    }
+
+    #[test]
+    fn uncompressed_basket_is_not_copied() {
+        let data = Bytes::from(std::fs::read("./src/test_data/simple.root").unwrap());
+        let file = RootFile::new(Source::from(data.clone())).unwrap();
+        let index = RowGroupIndex::from_file(&file).unwrap();
+        assert_eq!(index.columns[0], ("one".to_string(), "i32".to_string()));
+        let schema = Arc::new(branches_to_arrow_schema(&index.columns, 1));
+        let rg = &index.rowgroups[0];
+        let batch = rowgroup_to_record_batch_from_source(file.source(), 1, rg, schema.clone(), DEFAULT_MAX_GAP).unwrap();
+        assert!(shares(&batch, 0, &data));
+        let mapped = rowgroup_to_record_batch_shared(&data, 1, rg, schema.clone()).unwrap();
+        assert!(shares(&mapped, 0, &data));
+        assert_eq!(batch, mapped);
+        let copied = rowgroup_to_record_batch(&data, 1, rg, schema.clone()).unwrap();
+        assert!(!shares(&copied, 0, &data));
+        assert_eq!(batch, copied);
+        // the batch keeps the file data alive
+        drop((file, data));
+        assert_eq!(batch.num_rows(), 4);
+    }
+
+    #[test]
+    fn values_in_place() {
+        let data = Bytes::from(std::fs::read("./src/test_data/foriter.root").unwrap());
+        let file = RootFile::new(Source::from(data.clone())).unwrap();
+        let index = RowGroupIndex::from_file(&file).unwrap();
+        let schema = Arc::new(branches_to_arrow_schema(&index.columns, 1));
+        let mut next = 0;
+        for rg in &index.rowgroups {
+            let batch = rowgroup_to_record_batch_from_source(file.source(), 1, rg, schema.clone(), DEFAULT_MAX_GAP).unwrap();
+            // decoded into a buffer of its own unless borrowed
+            let ptr = batch.column(0).to_data().buffers()[0].as_ptr();
+            assert!(shares(&batch, 0, &data) || ptr.align_offset(8) == 0);
+            let values = batch.column(0).as_primitive::<Int32Type>();
+            for v in values.values() {
+                assert_eq!(i32::from_be(*v), next);
+                next += 1;
             }
-            _ => panic!("unsupported data type in rowgroup_to_record_batch"),
-        };
-        assert!(cursor.projected_col_idx == cols.len());
-        cols.push(arr);
-        cols
-    });
-    // XXX reuse schema by passing in arc into this fn?
-    RecordBatch::try_new(sc, arrays).unwrap()
+        }
+        assert_eq!(next, 46);
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32"), feature = "slow_endian_parsing"))]
+mod native_endian_tests {
+    use super::*;
+    use crate::anyblox::RowGroupIndex;
+    use crate::core::RootFile;
+
+    #[test]
+    fn values_are_byte_swapped() {
+        let data = Bytes::from(std::fs::read("./src/test_data/small-flat-tree.root").unwrap());
+        let index = RowGroupIndex::from_file(&RootFile::new(data.clone()).unwrap()).unwrap();
+        let schema = Arc::new(branches_to_arrow_schema(&index.columns, 0b111111));
+        let batch = rowgroup_to_record_batch(&data, 0b111111, &index.rowgroups[0], schema).unwrap();
+        // the first entries of every numeric column count up from 0
+        let n = batch.num_rows();
+        assert!(n > 1);
+        assert_eq!(batch.column(0).as_primitive::<Int32Type>().values().to_vec(), (0..n as i32).collect::<Vec<_>>());
+        assert_eq!(batch.column(1).as_primitive::<Int64Type>().values().to_vec(), (0..n as i64).collect::<Vec<_>>());
+        assert_eq!(batch.column(2).as_primitive::<UInt32Type>().values().to_vec(), (0..n as u32).collect::<Vec<_>>());
+        assert_eq!(batch.column(3).as_primitive::<UInt64Type>().values().to_vec(), (0..n as u64).collect::<Vec<_>>());
+        assert_eq!(batch.column(4).as_primitive::<Float32Type>().values().to_vec(), (0..n).map(|i| i as f32).collect::<Vec<_>>());
+        assert_eq!(batch.column(5).as_primitive::<Float64Type>().values().to_vec(), (0..n).map(|i| i as f64).collect::<Vec<_>>());
+    }
 }
@@ -0,0 +1,149 @@
+//! Reading a whole tree as a sequence of `RecordBatch`es, one per row
+//! group, from any `Source`.
+
+use std::sync::Arc;
+
+use arrow::{datatypes::Schema, record_batch::RecordBatch};
+use failure::Error;
+
+use crate::anyblox::{branches_to_arrow_schema, rowgroup_to_record_batch_from_source, ReadPlan, RowGroupIndex, DEFAULT_MAX_GAP};
+use crate::core::{RootFile, Source};
+#[cfg(all(feature = "parallel", not(target_arch = "wasm32")))]
+use crate::anyblox::{decoded_columns_to_record_batch, ParallelDecoder};
+
+/// Iterator over the row groups of a tree as `RecordBatch`es. Before a
+/// row group is decoded, the reads of the next one are announced to the
+/// source with `Source::prefetch`, so e.g. a memory mapping can read ahead.
+pub struct RecordBatches {
+    source: Source,
+    index: RowGroupIndex,
+    colmask: u64,
+    schema: Arc<Schema>,
+    max_gap: u64,
+    next: usize,
+    #[cfg(all(feature = "parallel", not(target_arch = "wasm32")))]
+    decoder: Option<Arc<ParallelDecoder>>,
+}
+
+impl RecordBatches {
+    pub fn new(source: Source, index: RowGroupIndex, colmask: u64) -> Self {
+        let schema = Arc::new(branches_to_arrow_schema(&index.columns, colmask));
+        RecordBatches {
+            source,
+            index,
+            colmask,
+            schema,
+            max_gap: DEFAULT_MAX_GAP,
+            next: 0,
+            #[cfg(all(feature = "parallel", not(target_arch = "wasm32")))]
+            decoder: None,
+        }
+    }
+
+    /// Batches of the biggest tree in `file`
+    pub fn from_file(file: &RootFile, colmask: u64) -> Result<Self, Error> {
+        Ok(Self::new(file.source().clone(), RowGroupIndex::from_file(file)?, colmask))
+    }
+
+    /// Gap up to which basket reads are merged, see `ReadPlan`
+    pub fn with_max_gap(mut self, max_gap: u64) -> Self {
+        self.max_gap = max_gap;
+        self
+    }
+
+    /// Decompress the columns of each row group on the threads of `decoder`
+    #[cfg(all(feature = "parallel", not(target_arch = "wasm32")))]
+    pub fn with_decoder(mut self, decoder: Arc<ParallelDecoder>) -> Self {
+        self.decoder = Some(decoder);
+        self
+    }
+
+    pub fn schema(&self) -> Arc<Schema> {
+        self.schema.clone()
+    }
+}
+
+impl Iterator for RecordBatches {
+    type Item = Result<RecordBatch, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let rg = self.index.rowgroups.get(self.next)?;
+        if let Some(upcoming) = self.index.rowgroups.get(self.next + 1) {
+            ReadPlan::new(upcoming, self.colmask, self.max_gap).prefetch(&self.source);
+        }
+        self.next += 1;
+        #[cfg(all(feature = "parallel", not(target_arch = "wasm32")))]
+        if let Some(decoder) = &self.decoder {
+            return Some(ReadPlan::new(rg, self.colmask, self.max_gap).execute(&self.source).and_then(|fetched| {
+                let columns = decoder.decode_columns(rg, |basket| fetched.bytes(basket), self.colmask)?;
+                decoded_columns_to_record_batch(columns, rg, self.schema.clone())
+            }));
+        }
+        Some(rowgroup_to_record_batch_from_source(&self.source, self.colmask, rg, self.schema.clone(), self.max_gap))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let left = self.index.rowgroups.len() - self.next;
+        (left, Some(left))
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use super::*;
+    use crate::core::DataSource;
+    use bytes::Bytes;
+    use std::sync::Mutex;
+
+    /// In-memory backend recording prefetch hints
+    #[derive(Debug)]
+    struct Hinted(Bytes, Arc<Mutex<Vec<u64>>>);
+
+    impl DataSource for Hinted {
+        fn fetch(&self, start: u64, len: u64) -> Result<Bytes, Error> {
+            Ok(self.0.slice(start as usize..(start + len) as usize))
+        }
+
+        fn len(&self) -> Result<u64, Error> {
+            Ok(self.0.len() as u64)
+        }
+
+        fn prefetch(&self, start: u64, _len: u64) {
+            self.1.lock().unwrap().push(start);
+        }
+    }
+
+    #[test]
+    fn batches_with_readahead() {
+        let data: &'static [u8] = std::fs::read("./src/test_data/foriter.root").unwrap().leak();
+        let hints = Arc::new(Mutex::new(Vec::new()));
+        let source = Source::with_backend(Hinted(Bytes::from_static(data), hints.clone()));
+        let file = RootFile::new(source).unwrap();
+        let batches = RecordBatches::from_file(&file, 1).unwrap();
+        let schema = batches.schema();
+        let index = RowGroupIndex::from_file(&file).unwrap();
+        assert_eq!(batches.size_hint(), (8, Some(8)));
+        let mut rows = 0;
+        for (batch, rg) in batches.zip(&index.rowgroups) {
+            let expected = crate::anyblox::rowgroup_to_record_batch(data, 1, rg, schema.clone()).unwrap();
+            assert_eq!(batch.unwrap(), expected);
+            rows += rg.count;
+        }
+        assert_eq!(rows, 46);
+        // every row group but the first was announced before it was needed
+        let expected: Vec<u64> = index.rowgroups[1..].iter()
+            .map(|rg| ReadPlan::new(rg, 1, DEFAULT_MAX_GAP).reads[0].start)
+            .collect();
+        assert_eq!(*hints.lock().unwrap(), expected);
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn parallel_batches() {
+        let file = RootFile::new(std::path::Path::new("./src/test_data/foriter.root")).unwrap();
+        let sequential: Vec<_> = RecordBatches::from_file(&file, 1).unwrap().map(Result::unwrap).collect();
+        let decoder = Arc::new(ParallelDecoder::new(2).unwrap());
+        let parallel: Vec<_> = RecordBatches::from_file(&file, 1).unwrap().with_decoder(decoder).map(Result::unwrap).collect();
+        assert_eq!(parallel, sequential);
+    }
+}
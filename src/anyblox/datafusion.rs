@@ -0,0 +1,237 @@
+//! Querying trees with DataFusion. `RootTableProvider` exposes a tree as
+//! a table, e.g. to run the magnitude histogram of `cpp/main.cpp`:
+//!
+//! ```sql
+//! WITH m AS (SELECT SQRT("H1_PX" * "H1_PX" + "H1_PY" * "H1_PY" + "H1_PZ" * "H1_PZ") AS magnitude FROM file)
+//! SELECT ROUND(magnitude / 10000, 0) * 10000 AS bucket, COUNT(*) FROM m GROUP BY bucket ORDER BY bucket
+//! ```
+//!
+//! Scans are planned by `ScanPlan`: the projection becomes the column
+//! mask, the row groups are split into partitions read in parallel, and a
+//! `LIMIT` only reads the row groups covering the first entries. Numeric
+//! columns are swapped to native byte order (see `to_native_endian`).
+//! Columns of types which cannot be decoded are not part of the table.
+
+use std::any::Any;
+use std::fmt;
+use std::sync::Arc;
+
+use arrow::datatypes::SchemaRef;
+use async_trait::async_trait;
+use datafusion::catalog::{Session, TableProvider};
+use datafusion::error::{DataFusionError, Result as DataFusionResult};
+use datafusion::execution::{SendableRecordBatchStream, TaskContext};
+use datafusion::logical_expr::{Expr, TableType};
+use datafusion::physical_expr::EquivalenceProperties;
+use datafusion::physical_plan::execution_plan::{Boundedness, EmissionType};
+use datafusion::physical_plan::stream::RecordBatchStreamAdapter;
+use datafusion::physical_plan::{DisplayAs, DisplayFormatType, ExecutionPlan, Partitioning, PlanProperties};
+use failure::Error;
+
+use crate::anyblox::{branches_to_arrow_schema, export_tree_item, supported_columns, to_native_endian, RowGroupIndex, ScanPlan};
+use crate::core::{RootFile, Source};
+
+fn to_datafusion_err<E: fmt::Display>(e: E) -> DataFusionError {
+    DataFusionError::Execution(e.to_string())
+}
+
+/// A tree as a DataFusion table
+#[derive(Debug, Clone)]
+pub struct RootTableProvider {
+    source: Source,
+    index: Arc<RowGroupIndex>,
+    /// column ids of the columns of the table
+    columns: Vec<usize>,
+    schema: SchemaRef,
+}
+
+impl RootTableProvider {
+    /// Table of the tree `spec` (see `RootFile::get`), or without one the
+    /// biggest tree, of `file`
+    pub fn new(file: &RootFile, tree: Option<&str>) -> Result<Self, Error> {
+        let item = export_tree_item(file, tree)?;
+        let index = RowGroupIndex::from_tree(&item.as_tree()?)?;
+        if index.columns.len() > 64 {
+            return Err(format_err!("tree has {} columns, at most 64 can be scanned", index.columns.len()));
+        }
+        let colmask = supported_columns(&index.columns);
+        let columns = (0..index.columns.len()).filter(|col| colmask & 1 << col != 0).collect();
+        Ok(RootTableProvider {
+            source: file.source().clone(),
+            schema: Arc::new(branches_to_arrow_schema(&index.columns, colmask)),
+            index: Arc::new(index),
+            columns,
+        })
+    }
+}
+
+#[async_trait]
+impl TableProvider for RootTableProvider {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn schema(&self) -> SchemaRef {
+        self.schema.clone()
+    }
+
+    fn table_type(&self) -> TableType {
+        TableType::Base
+    }
+
+    async fn scan(
+        &self,
+        state: &dyn Session,
+        projection: Option<&Vec<usize>>,
+        _filters: &[Expr],
+        limit: Option<usize>,
+    ) -> DataFusionResult<Arc<dyn ExecutionPlan>> {
+        let projection = match projection {
+            Some(fields) => fields.iter().map(|field| self.columns[*field]).collect::<Vec<_>>(),
+            None => self.columns.clone(),
+        };
+        let plan = ScanPlan::new(&self.index, Some(&projection), limit, state.config().target_partitions())
+            .map_err(to_datafusion_err)?;
+        Ok(Arc::new(RootScanExec::new(plan, self.source.clone(), self.index.clone())))
+    }
+}
+
+/// Physical scan of a tree; every partition of its `ScanPlan` is one
+/// DataFusion partition
+#[derive(Debug)]
+pub struct RootScanExec {
+    plan: ScanPlan,
+    source: Source,
+    index: Arc<RowGroupIndex>,
+    properties: PlanProperties,
+}
+
+impl RootScanExec {
+    pub fn new(plan: ScanPlan, source: Source, index: Arc<RowGroupIndex>) -> Self {
+        let schema: SchemaRef = Arc::new(plan.schema(&index));
+        let properties = PlanProperties::new(
+            EquivalenceProperties::new(schema),
+            // at least one, even if a `LIMIT 0` leaves nothing to read
+            Partitioning::UnknownPartitioning(plan.partitions.len().max(1)),
+            EmissionType::Incremental,
+            Boundedness::Bounded,
+        );
+        RootScanExec { plan, source, index, properties }
+    }
+
+    pub fn scan_plan(&self) -> &ScanPlan {
+        &self.plan
+    }
+}
+
+impl DisplayAs for RootScanExec {
+    fn fmt_as(&self, _t: DisplayFormatType, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "RootScanExec: partitions={}, colmask={:#x}, entries={}",
+            self.plan.partitions.len(), self.plan.colmask, self.plan.entries())
+    }
+}
+
+impl ExecutionPlan for RootScanExec {
+    fn name(&self) -> &str {
+        "RootScanExec"
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn properties(&self) -> &PlanProperties {
+        &self.properties
+    }
+
+    fn children(&self) -> Vec<&Arc<dyn ExecutionPlan>> {
+        vec![]
+    }
+
+    fn with_new_children(self: Arc<Self>, children: Vec<Arc<dyn ExecutionPlan>>) -> DataFusionResult<Arc<dyn ExecutionPlan>> {
+        if !children.is_empty() {
+            return Err(DataFusionError::Internal("RootScanExec has no children".to_string()));
+        }
+        Ok(self)
+    }
+
+    /// Batches of one partition, decoded on the thread polling the stream
+    fn execute(&self, partition: usize, _context: Arc<TaskContext>) -> DataFusionResult<SendableRecordBatchStream> {
+        let schema = self.schema();
+        if partition >= self.plan.partitions.len() {
+            // the placeholder partition of an empty scan
+            return Ok(Box::pin(RecordBatchStreamAdapter::new(schema, futures::stream::empty())));
+        }
+        let batches = self.plan.execute(partition, self.source.clone(), self.index.clone())
+            .map(|batch| batch.and_then(|batch| to_native_endian(&batch)).map_err(to_datafusion_err));
+        Ok(Box::pin(RecordBatchStreamAdapter::new(schema, futures::stream::iter(batches))))
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use super::*;
+    use arrow::array::{AsArray, Int64Array};
+    use arrow::datatypes::{Float64Type, Int32Type};
+    use datafusion::prelude::SessionContext;
+    use std::path::Path;
+
+    fn context(path: &str, partitions: usize) -> SessionContext {
+        let file = RootFile::new(Path::new(path)).unwrap();
+        let config = datafusion::prelude::SessionConfig::new().with_target_partitions(partitions);
+        let ctx = SessionContext::new_with_config(config);
+        ctx.register_table("events", Arc::new(RootTableProvider::new(&file, None).unwrap())).unwrap();
+        ctx
+    }
+
+    #[tokio::test]
+    async fn projection_and_limit() {
+        let ctx = context("./src/test_data/Zmumu.root", 4);
+        // `Type` is a string column, which is not part of the table
+        let provider = ctx.table_provider("events").await.unwrap();
+        assert!(provider.schema().field_with_name("Type").is_err());
+        assert_eq!(provider.schema().field(0).name(), "Run");
+
+        let df = ctx.sql(r#"SELECT "E1", "Run" FROM events LIMIT 5"#).await.unwrap();
+        let plan = df.clone().create_physical_plan().await.unwrap();
+        let plan = datafusion::physical_plan::displayable(plan.as_ref()).indent(false).to_string();
+        assert!(plan.contains("RootScanExec: partitions=1, colmask=0xa, entries=5"), "{}", plan);
+        let batches = df.collect().await.unwrap();
+        let batch = arrow::compute::concat_batches(&batches[0].schema(), &batches).unwrap();
+        assert_eq!(batch.num_rows(), 5);
+        assert_eq!(batch.schema().field(0).name(), "E1");
+        assert_eq!(batch.schema().field(1).name(), "Run");
+        // values in native byte order
+        assert!(batch.column(1).as_primitive::<Int32Type>().values().iter().all(|run| *run == 148031));
+        assert!(batch.column(0).as_primitive::<Float64Type>().values().iter().all(|e| (1.0..1000.0).contains(e)));
+
+        let count = ctx.sql("SELECT COUNT(*) FROM events").await.unwrap().collect().await.unwrap();
+        assert_eq!(count[0].column(0).as_any().downcast_ref::<Int64Array>().unwrap().value(0), 2304);
+    }
+
+    #[tokio::test]
+    async fn rowgroups_as_partitions() {
+        let ctx = context("./src/test_data/foriter.root", 3);
+        let df = ctx.sql("SELECT SUM(data) FROM events").await.unwrap();
+        let plan = df.clone().create_physical_plan().await.unwrap();
+        let plan = datafusion::physical_plan::displayable(plan.as_ref()).indent(false).to_string();
+        assert!(plan.contains("RootScanExec: partitions=3, colmask=0x1, entries=46"), "{}", plan);
+        let sum = df.collect().await.unwrap();
+        assert_eq!(sum[0].column(0).as_any().downcast_ref::<Int64Array>().unwrap().value(0), (0..46).sum::<i64>());
+    }
+
+    #[tokio::test]
+    async fn magnitude_histogram() {
+        let sql = r#"
+            WITH m AS (SELECT SQRT("px1" * "px1" + "py1" * "py1" + "pz1" * "pz1") AS magnitude FROM events)
+            SELECT ROUND(magnitude / 10, 0) * 10 AS bucket, COUNT(*) AS n FROM m GROUP BY bucket ORDER BY bucket"#;
+        let partitioned = context("./src/test_data/Zmumu.root", 4).sql(sql).await.unwrap().collect().await.unwrap();
+        let single = context("./src/test_data/Zmumu.root", 1).sql(sql).await.unwrap().collect().await.unwrap();
+        let concat = |batches: &[arrow::record_batch::RecordBatch]| arrow::compute::concat_batches(&batches[0].schema(), batches).unwrap();
+        let (partitioned, single) = (concat(&partitioned), concat(&single));
+        assert_eq!(partitioned, single);
+        let total: i64 = partitioned.column(1).as_any().downcast_ref::<Int64Array>().unwrap().values().iter().sum();
+        assert_eq!(total, 2304);
+        assert!(partitioned.num_rows() > 1);
+    }
+}
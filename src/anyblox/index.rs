@@ -0,0 +1,366 @@
+//! Precomputed row group metadata. Finding the row groups of a tree
+//! requires parsing the file header, the key list, the `TTree` and all
+//! its `TBranch`es; the `RowGroupIndex` stores the outcome of that in a
+//! compact binary format so a decoder can start from it directly, e.g.
+//! from a file stored next to the data or from the AnyBlox metadata blob.
+//!
+//! Format (all integers big-endian, strings as in ROOT: `u8` length,
+//! or `255` followed by a `u32` length):
+//!
+//! | Field                     | Type                                   |
+//! |---------------------------|----------------------------------------|
+//! | magic                     | `b"ARIX"`                              |
+//! | version                   | `u16`                                  |
+//! | tuples                    | `i32`                                  |
+//! | column count              | `u32`                                  |
+//! | columns                   | (name: string, type: string)*          |
+//! | row group count           | `u32`                                  |
+//! | row groups                | (start: `i32`, count: `i32`, columns)* |
+//!
+//! where each row group holds, for every column, a `u32` basket count
+//! followed by (location, entries: `u32`) per basket. The location is
+//! either `0u8` followed by seek (`u64`) and length (`u32`) of an on-disk
+//! basket, or `1u8` followed by a `u32` length and the bytes of a basket
+//! embedded in its `TBranch`.
+
+use std::io::{self, Write};
+use std::ops::Range;
+use std::sync::Arc;
+
+use failure::Error;
+use nom::{
+    bytes::complete::tag,
+    combinator::{map, verify},
+    multi::{length_count, length_data},
+    number::complete::{be_i32, be_u16, be_u32, be_u64, be_u8},
+    sequence::tuple,
+    IResult,
+};
+
+use crate::anyblox::{supported_columns, BasketLocation, RowGroup};
+use crate::core::{parsers::string, types::Tid, FileItem, RootFile};
+use crate::tree_reader::Tree;
+
+const INDEX_MAGIC: &[u8] = b"ARIX";
+const INDEX_VERSION: u16 = 1;
+const LOCATION_ON_DISK: u8 = 0;
+const LOCATION_IN_MEMORY: u8 = 1;
+
+/// Schema and row group layout of a single tree
+#[derive(Debug)]
+pub struct RowGroupIndex {
+    pub tuples: Tid,
+    /// name/type pairs of the top-level branches
+    pub columns: Vec<(String, String)>,
+    pub rowgroups: Vec<RowGroup>,
+    /// number of entries in each basket of `rowgroups[rg].containers[col]`
+    pub basket_entries: Vec<Vec<Vec<u32>>>,
+}
+
+/// Pick the tree the decoder works on: the biggest `TTree` in the file,
+/// considering only the latest cycle of each tree
+pub fn main_tree_item(file: &RootFile) -> Option<&FileItem> {
+    file.latest_items().into_iter()
+        .filter(|item| item.root_class() == "TTree")
+        .fold(None, |prev, item| {
+            match prev {
+                None => {
+                    debug_print!("found TTree named {} of size {}", item.name(), item.uncompressed_size());
+                    Some(item)
+                }
+                Some(prev_item) => {
+                    debug_print!("multiple TTrees in file, found : {}", item.name());
+                    if item.uncompressed_size() > prev_item.uncompressed_size() {
+                        debug_print!("found bigger TTree: {:?}, {} > {}", item, item.uncompressed_size(), prev_item.uncompressed_size());
+                        Some(item)
+                    } else {
+                        Some(prev_item)
+                    }
+                }
+            }
+        })
+}
+
+impl RowGroupIndex {
+    pub fn from_tree(tree: &Tree) -> Result<Self, Error> {
+        let rowgroups = RowGroup::find_rowgroups(tree)?;
+        // entry count of every basket, in the order the row groups consume them
+        let mut entries = tree.main_branches().iter().map(|b| {
+            let starts = b.container_start_indices();
+            let ends = starts.iter().skip(1).copied().chain(std::iter::once(b.entries() as Tid));
+            starts.iter().zip(ends).map(|(start, end)| (end - start) as u32).collect::<Vec<_>>().into_iter()
+        }).collect::<Vec<_>>();
+        let basket_entries = rowgroups.iter().map(|rg| {
+            rg.containers.iter().zip(entries.iter_mut())
+                .map(|(baskets, entries)| entries.by_ref().take(baskets.len()).collect())
+                .collect()
+        }).collect();
+        Ok(RowGroupIndex {
+            tuples: tree.entries() as Tid,
+            columns: tree.main_branch_names_and_types(),
+            rowgroups,
+            basket_entries,
+        })
+    }
+
+    /// Build the index of the biggest tree in `file`
+    pub fn from_file(file: &RootFile) -> Result<Self, Error> {
+        let item = main_tree_item(file).ok_or_else(|| format_err!("no TTree found in file"))?;
+        Self::from_tree(&item.as_tree()?)
+    }
+
+    /// Ids of the columns `names`, in that order, or of all columns of a
+    /// type `rowgroup_to_record_batch` can decode if `None`. Fails for a
+    /// name which is missing or of another type.
+    pub fn column_ids<S: AsRef<str>>(&self, names: Option<&[S]>) -> Result<Vec<usize>, Error> {
+        let supported = supported_columns(&self.columns);
+        let is_supported = |id: usize| id < 64 && supported & 1 << id != 0;
+        match names {
+            None => Ok((0..self.columns.len()).filter(|id| is_supported(*id)).collect()),
+            Some(names) => names.iter().map(|name| {
+                self.columns.iter().position(|(col, _)| col == name.as_ref())
+                    .filter(|id| is_supported(*id))
+                    .ok_or_else(|| format_err!("no column `{}` of a decodable type in tree", name.as_ref()))
+            }).collect(),
+        }
+    }
+
+    /// Column mask of `column_ids(names)`
+    pub fn colmask<S: AsRef<str>>(&self, names: Option<&[S]>) -> Result<u64, Error> {
+        Ok(self.column_ids(names)?.iter().fold(0u64, |mask, id| mask | 1 << id))
+    }
+
+    /// The row groups overlapping `entries`, each with the rows within it
+    /// that belong to `entries`. Entries past the end of the tree are
+    /// ignored, a reversed range selects nothing.
+    pub fn slices(&self, entries: Range<Tid>) -> impl Iterator<Item = (&RowGroup, Range<usize>)> {
+        let (first, end) = (entries.start, entries.end.min(self.tuples));
+        self.rowgroups.iter()
+            .filter(move |rg| first < end && rg.start_tid < end && first < rg.end_tid())
+            .map(move |rg| {
+                let start = first.max(rg.start_tid) - rg.start_tid;
+                let stop = end.min(rg.end_tid()) - rg.start_tid;
+                (rg, start as usize..stop as usize)
+            })
+    }
+
+    pub fn write<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        fn write_string<W: Write>(w: &mut W, s: &str) -> io::Result<()> {
+            if s.len() < 255 {
+                w.write_all(&[s.len() as u8])?;
+            } else {
+                w.write_all(&[255])?;
+                w.write_all(&(s.len() as u32).to_be_bytes())?;
+            }
+            w.write_all(s.as_bytes())
+        }
+        w.write_all(INDEX_MAGIC)?;
+        w.write_all(&INDEX_VERSION.to_be_bytes())?;
+        w.write_all(&self.tuples.to_be_bytes())?;
+        w.write_all(&(self.columns.len() as u32).to_be_bytes())?;
+        for (name, typ) in &self.columns {
+            write_string(w, name)?;
+            write_string(w, typ)?;
+        }
+        w.write_all(&(self.rowgroups.len() as u32).to_be_bytes())?;
+        for (rg, entries) in self.rowgroups.iter().zip(&self.basket_entries) {
+            w.write_all(&rg.start_tid.to_be_bytes())?;
+            w.write_all(&rg.count.to_be_bytes())?;
+            for (baskets, entries) in rg.containers.iter().zip(entries) {
+                w.write_all(&(baskets.len() as u32).to_be_bytes())?;
+                for (basket, n) in baskets.iter().zip(entries) {
+                    match basket {
+                        BasketLocation::OnDisk(seek, len) => {
+                            w.write_all(&[LOCATION_ON_DISK])?;
+                            w.write_all(&seek.to_be_bytes())?;
+                            w.write_all(&len.to_be_bytes())?;
+                        }
+                        BasketLocation::InMemory(buf) => {
+                            w.write_all(&[LOCATION_IN_MEMORY])?;
+                            w.write_all(&(buf.len() as u32).to_be_bytes())?;
+                            w.write_all(buf)?;
+                        }
+                    }
+                    w.write_all(&n.to_be_bytes())?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        self.write(&mut buf).expect("writing to a Vec cannot fail");
+        buf
+    }
+
+    /// Parse an index written by `RowGroupIndex::write`, failing if it is
+    /// inconsistent (see `from_bytes` for the reason)
+    pub fn parse(input: &[u8]) -> IResult<&[u8], Self> {
+        let (rest, index) = Self::parse_unchecked(input)?;
+        index.check()
+            .map_err(|_| nom::Err::Failure(nom::error::Error::new(input, nom::error::ErrorKind::Verify)))?;
+        Ok((rest, index))
+    }
+
+    fn parse_unchecked(input: &[u8]) -> IResult<&[u8], Self> {
+        let (input, _) = tag(INDEX_MAGIC)(input)?;
+        let (input, _) = verify(be_u16, |v| *v == INDEX_VERSION)(input)?;
+        let (input, tuples) = be_i32(input)?;
+        let (input, columns) = length_count(be_u32, tuple((string, string)))(input)?;
+        let ncols = columns.len();
+        let location = |i| match be_u8(i)? {
+            (i, LOCATION_ON_DISK) => map(tuple((be_u64, be_u32)), |(seek, len)| BasketLocation::OnDisk(seek, len))(i),
+            (i, LOCATION_IN_MEMORY) => map(length_data(be_u32), |buf: &[u8]| BasketLocation::InMemory(Arc::new(buf.to_vec())))(i),
+            _ => Err(nom::Err::Error(nom::error::Error::new(i, nom::error::ErrorKind::Tag))),
+        };
+        let basket = tuple((location, be_u32));
+        let rowgroup = tuple((be_i32, be_i32, nom::multi::count(length_count(be_u32, basket), ncols)));
+        let (input, rowgroups) = length_count(be_u32, rowgroup)(input)?;
+        let (rowgroups, basket_entries) = rowgroups.into_iter().map(|(start_tid, count, cols)| {
+            let entries = cols.iter().map(|b| b.iter().map(|(_, n)| *n).collect()).collect();
+            let containers = cols.into_iter().map(|b| b.into_iter().map(|(loc, _)| loc).collect()).collect();
+            (RowGroup { start_tid, count, containers }, entries)
+        }).unzip();
+        Ok((input, RowGroupIndex { tuples, columns, rowgroups, basket_entries }))
+    }
+
+    pub fn from_bytes(input: &[u8]) -> Result<Self, Error> {
+        let (_, index) = Self::parse_unchecked(input)
+            .map_err(|e| format_err!("Failed to parse row group index: {:?}", e))?;
+        index.check().map_err(|e| format_err!("Inconsistent row group index: {}", e))?;
+        Ok(index)
+    }
+
+    /// Check that the row groups are contiguous, cover the `tuples`
+    /// entries and that the baskets of every column hold the entries of
+    /// their row group
+    fn check(&self) -> Result<(), Error> {
+        let mut end = 0;
+        for (idx, (rg, entries)) in self.rowgroups.iter().zip(&self.basket_entries).enumerate() {
+            if rg.start_tid != end || rg.count <= 0 {
+                return Err(format_err!("row group {} holds entries {}..{}, expected it to start at {}", idx, rg.start_tid, rg.end_tid(), end));
+            }
+            if let Some(col) = entries.iter().position(|e| e.iter().map(|n| u64::from(*n)).sum::<u64>() != rg.count as u64) {
+                return Err(format_err!("baskets of column {} do not hold the {} entries of row group {}", col, rg.count, idx));
+            }
+            end = rg.start_tid.checked_add(rg.count)
+                .ok_or_else(|| format_err!("row group {} ends beyond the largest entry number", idx))?;
+        }
+        if end != self.tuples {
+            return Err(format_err!("row groups hold {} entries, the tree has {}", end, self.tuples));
+        }
+        Ok(())
+    }
+
+    /// Check that the on-disk baskets lie within a file of `len` bytes
+    pub fn check_locations(&self, len: u64) -> Result<(), Error> {
+        let baskets = self.rowgroups.iter().flat_map(|rg| rg.containers.iter().flatten());
+        for basket in baskets {
+            if let BasketLocation::OnDisk(seek, n) = basket {
+                if seek.checked_add(u64::from(*n)).is_none_or(|end| end > len) {
+                    return Err(format_err!("basket at {} ({} bytes) is beyond the {} bytes of the file", seek, n, len));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    #[test]
+    fn roundtrip() {
+        let file = RootFile::new(Path::new("./src/test_data/foriter.root")).unwrap();
+        let index = RowGroupIndex::from_file(&file).unwrap();
+        let parsed = RowGroupIndex::from_bytes(&index.to_bytes()).unwrap();
+        assert_eq!(parsed.tuples, index.tuples);
+        assert_eq!(parsed.columns, index.columns);
+        assert_eq!(parsed.basket_entries, index.basket_entries);
+        assert_eq!(parsed.rowgroups.len(), index.rowgroups.len());
+        for (a, b) in parsed.rowgroups.iter().zip(&index.rowgroups) {
+            assert_eq!((a.start_tid, a.count, &a.containers), (b.start_tid, b.count, &b.containers));
+        }
+        // every row group's entries are covered by its baskets
+        for (rg, entries) in index.rowgroups.iter().zip(&index.basket_entries) {
+            assert_eq!(entries[0].iter().sum::<u32>(), rg.count as u32);
+        }
+        assert!(RowGroupIndex::from_bytes(b"nope").is_err());
+    }
+
+    #[test]
+    fn columns_and_slices() {
+        let index = RowGroupIndex::from_file(&RootFile::new(Path::new("./src/test_data/Zmumu.root")).unwrap()).unwrap();
+        // `Type` is a string column
+        assert_eq!(index.columns[0].0, "Type");
+        assert_eq!(index.column_ids(Some(&["E1", "Run"])).unwrap(), [3, 1]);
+        assert_eq!(index.colmask(Some(&["E1", "Run"])).unwrap(), 0b1010);
+        assert_eq!(index.colmask::<&str>(None).unwrap(), u64::MAX >> (64 - index.columns.len()) & !1);
+        assert!(index.colmask(Some(&["Type"])).is_err());
+        assert!(index.colmask(Some(&["missing"])).is_err());
+
+        let index = RowGroupIndex::from_file(&RootFile::new(Path::new("./src/test_data/foriter.root")).unwrap()).unwrap();
+        let slices = |entries| index.slices(entries).map(|(rg, rows)| (rg.start_tid, rows)).collect::<Vec<_>>();
+        assert_eq!(slices(3..14), [(0, 3..6), (6, 0..6), (12, 0..2)]);
+        assert_eq!(slices(44..100), [(42, 2..4)]);
+        assert_eq!(slices(46..50), []);
+        assert_eq!(slices(Range { start: 30, end: 5 }), []);
+    }
+
+    #[test]
+    fn seeks_beyond_4gb() {
+        let index = RowGroupIndex {
+            tuples: 10,
+            columns: vec![("x".to_string(), "int32_t".to_string())],
+            rowgroups: vec![RowGroup { start_tid: 0, count: 10, containers: vec![vec![BasketLocation::OnDisk(5 << 30, 100)]] }],
+            basket_entries: vec![vec![vec![10]]],
+        };
+        let parsed = RowGroupIndex::from_bytes(&index.to_bytes()).unwrap();
+        assert_eq!(parsed.rowgroups[0].containers, index.rowgroups[0].containers);
+        assert!(parsed.check_locations(5 << 30).is_err());
+        assert!(parsed.check_locations((5 << 30) + 100).is_ok());
+    }
+
+    #[test]
+    fn inconsistent_index() {
+        let index = |tuples, start_tid, count, entries| RowGroupIndex {
+            tuples,
+            columns: vec![("x".to_string(), "int32_t".to_string())],
+            rowgroups: vec![RowGroup { start_tid, count, containers: vec![vec![BasketLocation::OnDisk(100, 100)]] }],
+            basket_entries: vec![vec![vec![entries]]],
+        }.to_bytes();
+        assert!(RowGroupIndex::from_bytes(&index(10, 0, 10, 10)).is_ok());
+        // entries missing at the end, the start or within the baskets
+        for bytes in [index(11, 0, 10, 10), index(10, 1, 9, 9), index(10, 0, 10, 9)] {
+            assert!(RowGroupIndex::from_bytes(&bytes).is_err());
+            assert!(RowGroupIndex::parse(&bytes).is_err());
+        }
+        let empty = RowGroupIndex { tuples: 0, columns: vec![], rowgroups: vec![], basket_entries: vec![] };
+        assert!(RowGroupIndex::from_bytes(&empty.to_bytes()).is_ok());
+        let empty = RowGroupIndex { tuples: 10, ..empty };
+        assert!(RowGroupIndex::from_bytes(&empty.to_bytes()).is_err());
+    }
+
+    #[test]
+    fn decode_from_index() {
+        let data = bytes::Bytes::from(std::fs::read("./src/test_data/foriter.root").unwrap());
+        let index = RowGroupIndex::from_file(&RootFile::new(data.clone()).unwrap()).unwrap().to_bytes();
+        let (mut parsed, mut indexed) = (None, None);
+        let expected = crate::anyblox::decode_batch_internal(&data, 10, 5, &mut parsed, 1).unwrap();
+        let batch = crate::anyblox::decode_batch_with_index(&data, &index, 10, 5, &mut indexed, 1).unwrap();
+        assert_eq!(batch, expected);
+
+        let mut state = None;
+        assert!(crate::anyblox::decode_batch_with_index(&data, &index[..index.len() / 2], 10, 5, &mut state, 1).is_err());
+        assert!(state.is_none());
+        // entries beyond the tree, baskets beyond the data
+        assert!(crate::anyblox::decode_batch_with_index(&data, &index, 46, 5, &mut state, 1).is_err());
+        assert!(crate::anyblox::decode_batch_with_index(&data.slice(..100), &index, 10, 5, &mut None, 1).is_err());
+        // the states and batches keep the data alive
+        drop(data);
+        assert_eq!(batch, expected);
+    }
+}
@@ -1,11 +1,13 @@
 use crate::{
-    anyblox::{branches_to_arrow_schema, rowgroup_to_record_batch, RowGroup},
+    anyblox::{branches_to_arrow_schema, main_tree_item, rowgroup_to_record_batch_shared, RowGroup, RowGroupIndex},
     core::{types::Tid, RootFile}
 };
 
 use std::{cmp::Ordering, sync::Arc};
 
 use arrow::record_batch::RecordBatch;
+use bytes::Bytes;
+use failure::Error;
 
 // decode_batch params
 // - i32 data, the pointer to the place in Decoder’s linear mem-
@@ -46,7 +48,6 @@ use arrow::record_batch::RecordBatch;
 
 #[derive(Debug)]
 struct DecoderFileState {
-    #[allow(dead_code)]
     tuples: Tid,
     rowgroups: Vec<RowGroup>,
     columns: Vec<(String, String)> // name/type pairs
@@ -61,52 +62,31 @@ impl DecoderFileState {
     //         ord => ord,
     //     }).ok()
     // }
-    pub fn find_rowgroup_containing_tid(&self, tuple: Tid) -> usize {
-        self.rowgroups.binary_search_by(|rg| match (rg.end_tid()-1).cmp(&tuple) {
+    pub fn find_rowgroup_containing_tid(&self, tuple: Tid) -> Option<usize> {
+        let idx = self.rowgroups.binary_search_by(|rg| match (rg.end_tid()-1).cmp(&tuple) {
             Ordering::Equal => Ordering::Greater, // lower bound
            ord => ord
-        }).unwrap_err()
+        }).unwrap_err();
+        self.rowgroups.get(idx).filter(|rg| rg.start_tid <= tuple).map(|_| idx)
     }
 
-    pub fn new(data: &'static [u8]) -> Self {
-        let file = match RootFile::new(data) {
-            Ok(f) => f,
-            _ => panic!("failed to parse root file")
-        };
-
-        let item = file.items().iter()
-            .filter(|item| item.root_class() == "TTree")
-            .fold(None, |prev, item| {
-                // find biggest TTree
-                match prev {
-                    None => {
-                        debug_print!("found TTree named {} of size {}", item.name(), item.uncompressed_size());
-                        Some(item)
-                    }
-                    Some(prev_item) => {
-                        debug_print!("multiple TTrees in file, found : {}", item.name());
-                        if item.uncompressed_size() > prev_item.uncompressed_size() {
-                            debug_print!("found bigger TTree: {:?}, {} > {}", item, item.uncompressed_size(), prev_item.uncompressed_size());
-                            Some(item)
-                        } else {
-                            Some(prev_item)
-                        }
-                    }
-                }
-            });
-        match item {
-            None => panic!("no TTree found in file"),
-            Some(item) => {
-                let tree = item.as_tree().unwrap();
-                Self {
-                    tuples: tree.entries() as Tid,
-                    rowgroups: RowGroup::find_rowgroups(&tree),
-                    columns: tree.main_branch_names_and_types(),
-                    // ttree_end_tids: tids
-                }
-            }
-        }
+    pub fn new(data: &Bytes) -> Result<Self, Error> {
+        let file = RootFile::new(data.clone()).map_err(|e| format_err!("failed to parse root file: {}", e))?;
+        let item = main_tree_item(&file).ok_or_else(|| format_err!("no TTree found in file"))?;
+        let index = RowGroupIndex::from_tree(&item.as_tree()?)
+            .map_err(|e| format_err!("failed to find row groups: {}", e))?;
+        Self::from_index(index, data)
+    }
 
+    /// start from a precomputed index instead of parsing the file; fails if
+    /// its baskets are not within `data`
+    pub fn from_index(index: RowGroupIndex, data: &Bytes) -> Result<Self, Error> {
+        index.check_locations(data.len() as u64)?;
+        Ok(Self {
+            tuples: index.tuples,
+            rowgroups: index.rowgroups,
+            columns: index.columns,
+        })
     }
 }
 
@@ -114,7 +94,7 @@ impl DecoderFileState {
 // there will be multiple kb of metadata
 // new plan:
 // - parse all the container headers in all tbranches
-// - save file offsets, tuple counts to metadata header
+// - save file offsets, tuple counts to metadata header (-> `RowGroupIndex`)
 // - decode_batch can reference that, doesn't need to keep it in thread-local state
 #[derive(Debug)]
 struct DecoderCache {
@@ -126,29 +106,30 @@ struct DecoderCache {
 }
 
 impl DecoderCache {
-    pub fn new(data: &[u8], global: &DecoderFileState, start_tuple: Tid, _tuple_count: Tid, columns: u64) -> Self {
-        let rg = global.find_rowgroup_containing_tid(start_tuple);
+    pub fn new(data: &Bytes, global: &DecoderFileState, start_tuple: Tid, _tuple_count: Tid, columns: u64) -> Result<Self, Error> {
+        let rg = global.find_rowgroup_containing_tid(start_tuple)
+            .ok_or_else(|| format_err!("entry {} is not within the {} entries of the tree", start_tuple, global.tuples))?;
         let group = &global.rowgroups[rg];
         let schema = Arc::new(branches_to_arrow_schema(global.columns.as_slice(), columns));
-        DecoderCache{
+        Ok(DecoderCache{
             prev_columns: columns,
             batch_tid_start: group.start_tid,
             batch_size: group.count,
-            batch: rowgroup_to_record_batch(data, columns, group, schema)
-        }
+            batch: rowgroup_to_record_batch_shared(data, columns, group, schema)?
+        })
     }
 
     /// potentially invalidates current cache, returns the record batch slice we can read
-    pub fn invalidate(&mut self, data: &[u8], global: &DecoderFileState, start_tuple: Tid, tuple_count: Tid, columns: u64) -> RecordBatch {
+    pub fn invalidate(&mut self, data: &Bytes, global: &DecoderFileState, start_tuple: Tid, tuple_count: Tid, columns: u64) -> Result<RecordBatch, Error> {
         let in_range = start_tuple >= self.batch_tid_start && start_tuple < self.batch_tid_end();
             // projection mask changed or cur row group does not have correct range
         if columns != self.prev_columns || !in_range {
-            *self = DecoderCache::new(data, global, start_tuple, tuple_count, columns);
+            *self = DecoderCache::new(data, global, start_tuple, tuple_count, columns)?;
         }
         let start = start_tuple - self.batch_tid_start;
         // (XXX make sure that this is does not copy the columns)
         // https://docs.rs/arrow/latest/arrow/array/struct.RecordBatch.html#method.slice
-        self.batch.slice(start as usize, tuple_count.min(self.batch_size - start) as usize)
+        Ok(self.batch.slice(start as usize, tuple_count.min(self.batch_size - start) as usize))
     }
 
     fn batch_tid_end(&self) -> Tid {
@@ -165,17 +146,40 @@ pub struct DecoderState {
 }
 
 impl DecoderState {
-    fn new(data: &'static [u8], start_tuple: Tid, tuple_count: Tid, columns: u64) -> Self {
-        let file = DecoderFileState::new(data);
-        let cache = DecoderCache::new(data, &file, start_tuple, tuple_count, columns);
-        DecoderState{file, cache}
+    fn new(data: &Bytes, start_tuple: Tid, tuple_count: Tid, columns: u64) -> Result<Self, Error> {
+        let file = DecoderFileState::new(data)?;
+        let cache = DecoderCache::new(data, &file, start_tuple, tuple_count, columns)?;
+        Ok(DecoderState{file, cache})
+    }
+
+    fn with_index(data: &Bytes, index: RowGroupIndex, start_tuple: Tid, tuple_count: Tid, columns: u64) -> Result<Self, Error> {
+        let file = DecoderFileState::from_index(index, data)?;
+        let cache = DecoderCache::new(data, &file, start_tuple, tuple_count, columns)?;
+        Ok(DecoderState{file, cache})
     }
 }
 
-pub fn decode_batch_internal<'a>(data: &'a [u8], start_tuple: Tid, tuple_count: Tid, state: &mut Option<DecoderState>, columns: u64) -> RecordBatch {
-    let s: &mut DecoderState = state.get_or_insert_with(|| {
-        let static_data: &'static [u8] = unsafe { std::mem::transmute(data) };
-        DecoderState::new(static_data, start_tuple, tuple_count, columns)
-    });
+/// Uncompressed columns of the cached and returned batches share `data`,
+/// keeping it alive. `state` has to be used with the same `data` on every
+/// call. Fails if the file or one of its baskets is damaged.
+pub fn decode_batch_internal(data: &Bytes, start_tuple: Tid, tuple_count: Tid, state: &mut Option<DecoderState>, columns: u64) -> Result<RecordBatch, Error> {
+    let s: &mut DecoderState = match state {
+        Some(s) => s,
+        None => state.insert(DecoderState::new(data, start_tuple, tuple_count, columns)?),
+    };
+    s.cache.invalidate(data, &s.file, start_tuple, tuple_count, columns)
+}
+
+/// like `decode_batch_internal`, but initializes the state from a serialized
+/// `RowGroupIndex` (e.g. the AnyBlox metadata blob) instead of parsing the file.
+/// Fails if the index is malformed or a basket is damaged.
+pub fn decode_batch_with_index(data: &Bytes, index: &[u8], start_tuple: Tid, tuple_count: Tid, state: &mut Option<DecoderState>, columns: u64) -> Result<RecordBatch, Error> {
+    let s: &mut DecoderState = match state {
+        Some(s) => s,
+        None => {
+            let index = RowGroupIndex::from_bytes(index)?;
+            state.insert(DecoderState::with_index(data, index, start_tuple, tuple_count, columns)?)
+        }
+    };
     s.cache.invalidate(data, &s.file, start_tuple, tuple_count, columns)
 }
@@ -0,0 +1,302 @@
+//! Exporting trees to Arrow IPC, either as a file (Feather v2) or as a
+//! stream. Row groups are decoded and written one at a time, so the
+//! whole tree never has to fit in memory. Numeric columns are written in
+//! native byte order, as IPC readers expect; the tree's name and title,
+//! its entry count and the ROOT type of every column are carried in the
+//! schema metadata.
+
+use std::collections::HashMap;
+use std::io::Write;
+use std::ops::Range;
+use std::sync::Arc;
+
+use arrow::{
+    datatypes::*,
+    ipc::{
+        writer::{FileWriter, IpcWriteOptions, StreamWriter},
+        CompressionType,
+    },
+    record_batch::RecordBatch,
+};
+use failure::Error;
+
+use crate::anyblox::{
+    branches_to_arrow_schema, main_tree_item, rowgroup_to_record_batch_from_source, supported_columns, to_native_endian,
+    ColumnProjection, RowGroupIndex, DEFAULT_MAX_GAP,
+};
+use crate::core::{types::Tid, FileItem, RootFile};
+
+/// Schema metadata key of the tree name
+pub const META_TREE_NAME: &str = "root.tree.name";
+/// Schema metadata key of the tree title
+pub const META_TREE_TITLE: &str = "root.tree.title";
+/// Schema metadata key of the number of entries of the tree
+pub const META_TREE_ENTRIES: &str = "root.tree.entries";
+/// Field metadata key of the ROOT type of a column
+pub const META_LEAF_TYPE: &str = "root.leaf.type";
+
+/// Container written by `IpcExport`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IpcFormat {
+    /// random-access IPC file, also known as Feather v2
+    File,
+    /// IPC stream, e.g. for pipes or sockets
+    Stream,
+}
+
+/// Compression of the record batch bodies. Compressed output requires the
+/// `ipc_compression` feature.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IpcCompression {
+    None,
+    Lz4,
+    Zstd,
+}
+
+/// Export of a tree, or a projection and entry range of it, to Arrow IPC
+#[derive(Debug, Clone)]
+pub struct IpcExport {
+    tree: Option<String>,
+    colmask: Option<u64>,
+    entries: Option<Range<Tid>>,
+    format: IpcFormat,
+    compression: IpcCompression,
+}
+
+impl Default for IpcExport {
+    fn default() -> Self {
+        IpcExport {
+            tree: None,
+            colmask: None,
+            entries: None,
+            format: IpcFormat::File,
+            compression: IpcCompression::None,
+        }
+    }
+}
+
+impl IpcExport {
+    /// Export of all columns of a supported type and all entries of the
+    /// biggest tree to an uncompressed IPC file
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Export the tree `spec` (`name` or `name;cycle`, see `RootFile::get`)
+    pub fn with_tree(mut self, spec: &str) -> Self {
+        self.tree = Some(spec.to_string());
+        self
+    }
+
+    /// Export only the columns in `colmask`. Writing fails if it selects a
+    /// column of a type that cannot be decoded.
+    pub fn with_columns(mut self, colmask: u64) -> Self {
+        self.colmask = Some(colmask);
+        self
+    }
+
+    /// Export only the entries in `entries`
+    pub fn with_entries(mut self, entries: Range<Tid>) -> Self {
+        self.entries = Some(entries);
+        self
+    }
+
+    pub fn with_format(mut self, format: IpcFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    pub fn with_compression(mut self, compression: IpcCompression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Schema of the exported batches, including the ROOT metadata
+    pub fn schema(&self, item: &FileItem, index: &RowGroupIndex) -> Result<Schema, Error> {
+        Ok(export_schema(item, index, export_colmask(index, self.colmask)?))
+    }
+
+    fn write_options(&self) -> Result<IpcWriteOptions, Error> {
+        let compression = match self.compression {
+            IpcCompression::None => return Ok(IpcWriteOptions::default()),
+            IpcCompression::Lz4 => CompressionType::LZ4_FRAME,
+            IpcCompression::Zstd => CompressionType::ZSTD,
+        };
+        if !cfg!(feature = "ipc_compression") {
+            return Err(format_err!("{:?} compression requires the `ipc_compression` feature", self.compression));
+        }
+        Ok(IpcWriteOptions::default().try_with_compression(Some(compression))?)
+    }
+
+    /// Decode the selected row groups of the tree in `file` and write them
+    /// to `out`. Returns the number of entries written.
+    pub fn write<W: Write>(&self, file: &RootFile, out: W) -> Result<u64, Error> {
+        let item = export_tree_item(file, self.tree.as_deref())?;
+        let index = RowGroupIndex::from_tree(&item.as_tree()?)?;
+        let colmask = export_colmask(&index, self.colmask)?;
+        let schema = Arc::new(export_schema(item, &index, colmask));
+        // decoding uses the plain schema, the metadata is only written
+        let decode_schema = Arc::new(branches_to_arrow_schema(&index.columns, colmask));
+        let entries = self.entries.clone().unwrap_or(0..index.tuples);
+        let options = self.write_options()?;
+        let mut writer = match self.format {
+            IpcFormat::File => IpcWriter::File(FileWriter::try_new_with_options(out, &schema, options)?),
+            IpcFormat::Stream => IpcWriter::Stream(StreamWriter::try_new_with_options(out, &schema, options)?),
+        };
+        let mut written = 0u64;
+        for (rg, rows) in index.slices(entries) {
+            let batch = rowgroup_to_record_batch_from_source(file.source(), colmask, rg, decode_schema.clone(), DEFAULT_MAX_GAP)?;
+            let batch = to_native_endian(&batch.slice(rows.start, rows.len()))?;
+            writer.write(&RecordBatch::try_new(schema.clone(), batch.columns().to_vec())?)?;
+            written += rows.len() as u64;
+        }
+        writer.finish()?;
+        debug!("exported {} entries of {} to Arrow IPC", written, item.name());
+        Ok(written)
+    }
+}
+
+/// The tree `spec` (see `RootFile::get`) or, without one, the biggest
+/// tree in `file`
+pub(crate) fn export_tree_item<'a>(file: &'a RootFile, spec: Option<&str>) -> Result<&'a FileItem, Error> {
+    let item = match spec {
+        Some(spec) => file.get(spec)?,
+        None => main_tree_item(file).ok_or_else(|| format_err!("no TTree found in file"))?,
+    };
+    if item.root_class() != "TTree" {
+        return Err(format_err!("{} is not a TTree", item.name()));
+    }
+    Ok(item)
+}
+
+/// `colmask`, checked to select only columns of a supported type, or
+/// without one the mask of all of them
+pub(crate) fn export_colmask(index: &RowGroupIndex, colmask: Option<u64>) -> Result<u64, Error> {
+    let supported = supported_columns(&index.columns);
+    let Some(colmask) = colmask else {
+        return Ok(supported);
+    };
+    let mask = ColumnProjection::from_u64(colmask);
+    match index.columns.iter().enumerate().find(|(idx, _)| mask.contains(*idx as u32) && supported & 1 << idx == 0) {
+        Some((_, (name, ty))) => Err(format_err!("column `{}` of type `{}` cannot be exported", name, ty)),
+        None => Ok(colmask),
+    }
+}
+
+/// Arrow schema of the columns `colmask` of the tree `item`, carrying its
+/// name, title and entry count and the ROOT type of every column as metadata
+pub fn export_schema(item: &FileItem, index: &RowGroupIndex, colmask: u64) -> Schema {
+    let mask = ColumnProjection::from_u64(colmask);
+    let types = index.columns.iter().enumerate()
+        .filter(|(idx, _)| mask.contains(*idx as u32))
+        .map(|(_, (_, ty))| ty);
+    let fields = branches_to_arrow_schema(&index.columns, colmask).fields().iter().zip(types)
+        .map(|(field, ty)| {
+            let meta = HashMap::from([(META_LEAF_TYPE.to_string(), ty.clone())]);
+            field.as_ref().clone().with_metadata(meta)
+        })
+        .collect::<Vec<_>>();
+    let meta = HashMap::from([
+        (META_TREE_NAME.to_string(), item.obj_name().to_string()),
+        (META_TREE_TITLE.to_string(), item.title().to_string()),
+        (META_TREE_ENTRIES.to_string(), index.tuples.to_string()),
+    ]);
+    Schema::new_with_metadata(fields, meta)
+}
+
+enum IpcWriter<W: Write> {
+    File(FileWriter<W>),
+    Stream(StreamWriter<W>),
+}
+
+impl<W: Write> IpcWriter<W> {
+    fn write(&mut self, batch: &RecordBatch) -> Result<(), Error> {
+        match self {
+            IpcWriter::File(w) => w.write(batch)?,
+            IpcWriter::Stream(w) => w.write(batch)?,
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), Error> {
+        match self {
+            IpcWriter::File(w) => w.finish()?,
+            IpcWriter::Stream(w) => w.finish()?,
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use super::*;
+    use arrow::array::AsArray;
+    use arrow::ipc::reader::{FileReader, StreamReader};
+    use std::io::Cursor;
+    use std::path::Path;
+
+    fn read_file(buf: Vec<u8>) -> (Arc<Schema>, Vec<RecordBatch>) {
+        let reader = FileReader::try_new(Cursor::new(buf), None).unwrap();
+        let schema = reader.schema();
+        (schema, reader.map(Result::unwrap).collect())
+    }
+
+    #[test]
+    fn export_with_metadata() {
+        let file = RootFile::new(Path::new("./src/test_data/foriter.root")).unwrap();
+        let mut buf = Vec::new();
+        assert_eq!(IpcExport::new().write(&file, &mut buf).unwrap(), 46);
+        let (schema, batches) = read_file(buf);
+        assert_eq!(schema.metadata()[META_TREE_NAME], "foriter");
+        assert_eq!(schema.metadata()[META_TREE_ENTRIES], "46");
+        assert_eq!(schema.field(0).metadata()[META_LEAF_TYPE], "i32");
+        assert_eq!(batches.len(), 8);
+        let values: Vec<i32> = batches.iter()
+            .flat_map(|b| b.column(0).as_primitive::<Int32Type>().values().to_vec())
+            .collect();
+        assert_eq!(values, (0..46).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn export_entry_range_as_stream() {
+        let file = RootFile::new(Path::new("./src/test_data/foriter.root")).unwrap();
+        let mut buf = Vec::new();
+        let export = IpcExport::new().with_tree("foriter").with_entries(3..20).with_format(IpcFormat::Stream);
+        assert_eq!(export.write(&file, &mut buf).unwrap(), 17);
+        let reader = StreamReader::try_new(Cursor::new(buf), None).unwrap();
+        let values: Vec<i32> = reader.map(Result::unwrap)
+            .flat_map(|b| b.column(0).as_primitive::<Int32Type>().values().to_vec())
+            .collect();
+        assert_eq!(values, (3..20).collect::<Vec<_>>());
+        assert!(IpcExport::new().with_tree("missing").write(&file, Vec::new()).is_err());
+    }
+
+    #[test]
+    fn unsupported_columns() {
+        // the `Type` column holds strings
+        let file = RootFile::new(Path::new("./src/test_data/Zmumu-uncompressed.root")).unwrap();
+        let mut buf = Vec::new();
+        let written = IpcExport::new().write(&file, &mut buf).unwrap();
+        let (schema, _) = read_file(buf);
+        assert_eq!(written, 2304);
+        assert!(schema.fields().iter().all(|field| field.name() != "Type"));
+        let err = IpcExport::new().with_columns(1).write(&file, Vec::new()).unwrap_err();
+        assert!(err.to_string().contains("`Type`"), "{}", err);
+    }
+
+    #[test]
+    fn compressed_export() {
+        let file = RootFile::new(Path::new("./src/test_data/simple.root")).unwrap();
+        for compression in [IpcCompression::Lz4, IpcCompression::Zstd] {
+            let mut buf = Vec::new();
+            let result = IpcExport::new().with_columns(1).with_compression(compression).write(&file, &mut buf);
+            if !cfg!(feature = "ipc_compression") {
+                assert!(result.is_err());
+                continue;
+            }
+            assert_eq!(result.unwrap(), 4);
+            let (_, batches) = read_file(buf);
+            assert_eq!(batches[0].column(0).as_primitive::<Int32Type>().values().to_vec(), vec![1, 2, 3, 4]);
+        }
+    }
+}
@@ -2,8 +2,38 @@ pub mod projection;
 pub mod rowgroup;
 pub mod arrow;
 pub mod interface;
+pub mod index;
+pub mod read_plan;
+pub mod batches;
+pub mod scan;
+pub mod ipc;
+#[cfg(all(feature = "parquet", not(target_arch = "wasm32")))]
+pub mod parquet;
+#[cfg(all(feature = "polars", not(target_arch = "wasm32")))]
+pub mod polars;
+#[cfg(all(feature = "datafusion", not(target_arch = "wasm32")))]
+pub mod datafusion;
+#[cfg(all(feature = "parallel", not(target_arch = "wasm32")))]
+pub mod parallel;
+#[cfg(all(feature = "async", not(target_arch = "wasm32")))]
+pub mod stream;
 
 pub use projection::*;
 pub use rowgroup::*;
 pub use arrow::*;
 pub use interface::*;
+pub use index::*;
+pub use read_plan::*;
+pub use batches::*;
+pub use scan::*;
+pub use ipc::*;
+#[cfg(all(feature = "parquet", not(target_arch = "wasm32")))]
+pub use self::parquet::*;
+#[cfg(all(feature = "polars", not(target_arch = "wasm32")))]
+pub use self::polars::*;
+#[cfg(all(feature = "datafusion", not(target_arch = "wasm32")))]
+pub use self::datafusion::*;
+#[cfg(all(feature = "parallel", not(target_arch = "wasm32")))]
+pub use parallel::*;
+#[cfg(all(feature = "async", not(target_arch = "wasm32")))]
+pub use stream::*;
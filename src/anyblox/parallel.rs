@@ -0,0 +1,170 @@
+//! Decompressing baskets on a thread pool. The baskets of the projected
+//! columns of a row group are decoded concurrently, each column into its
+//! own buffer sized by the baskets' `decoded_size`; several row groups can
+//! be decoded at once within a memory limit. Results are always handed
+//! out in column and row group order, independent of the thread count.
+
+use aligned_vec::AVec;
+use failure::Error;
+use rayon::prelude::*;
+
+use crate::anyblox::{BasketLocation, ColumnProjection, RowGroup, RowGroupDecodeCursor};
+
+/// Decoded bytes of one column of a row group
+pub struct DecodedColumn {
+    pub cursor: RowGroupDecodeCursor,
+    /// holds `cursor.byte_count` decoded bytes, aligned to 8 bytes
+    pub data: AVec<u8>,
+}
+
+impl DecodedColumn {
+    pub fn bytes(&self) -> &[u8] {
+        &self.data[..self.cursor.byte_count]
+    }
+}
+
+/// Thread pool decoding baskets in parallel
+pub struct ParallelDecoder {
+    pool: rayon::ThreadPool,
+    memory_limit: usize,
+}
+
+impl ParallelDecoder {
+    /// Decoder with `threads` threads; `0` picks one per CPU
+    pub fn new(threads: usize) -> Result<Self, Error> {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .thread_name(|i| format!("anyroot-decode-{}", i))
+            .build()?;
+        Ok(ParallelDecoder { pool, memory_limit: usize::MAX })
+    }
+
+    /// Upper bound for the decoded bytes of the row groups which
+    /// `decode_rowgroups` holds at once. A single row group exceeding the
+    /// limit is still decoded.
+    pub fn with_memory_limit(mut self, bytes: usize) -> Self {
+        self.memory_limit = bytes;
+        self
+    }
+
+    pub fn threads(&self) -> usize {
+        self.pool.current_num_threads()
+    }
+
+    /// Bytes needed to decode the columns `cols` of `rg`. Fails if a
+    /// basket header is damaged.
+    pub fn decoded_size<'a, B>(rg: &'a RowGroup, basket_bytes: &B, cols: u64) -> Result<usize, Error>
+        where B: Fn(&'a BasketLocation) -> Result<&'a [u8], Error>
+    {
+        let colmask = ColumnProjection::from_u64(cols);
+        (0..rg.containers.len())
+            .filter(|colid| colmask.contains(*colid as u32))
+            .map(|colid| Ok(rg.basket_headers(colid, basket_bytes)?.iter().map(|m| m.decoded_size()).sum::<usize>()))
+            .sum()
+    }
+
+    /// Decode the columns `cols` of `rg` concurrently, returned in column order
+    pub fn decode_columns<'a, B>(&self, rg: &'a RowGroup, basket_bytes: B, cols: u64) -> Result<Vec<DecodedColumn>, Error>
+        where B: Fn(&'a BasketLocation) -> Result<&'a [u8], Error> + Sync
+    {
+        self.pool.install(|| Self::decode_columns_in_pool(rg, &basket_bytes, cols))
+    }
+
+    fn decode_columns_in_pool<'a, B>(rg: &'a RowGroup, basket_bytes: &B, cols: u64) -> Result<Vec<DecodedColumn>, Error>
+        where B: Fn(&'a BasketLocation) -> Result<&'a [u8], Error> + Sync
+    {
+        let colmask = ColumnProjection::from_u64(cols);
+        let colids: Vec<usize> = (0..rg.containers.len())
+            .filter(|colid| colmask.contains(*colid as u32))
+            .collect();
+        colids.par_iter().enumerate().map(|(projected_col_idx, &colid)| {
+            let (data, written) = rg.decode_column(colid, basket_bytes)?;
+            Ok(DecodedColumn {
+                cursor: RowGroupDecodeCursor { global_col_idx: colid, projected_col_idx, byte_count: written },
+                data,
+            })
+        }).collect()
+    }
+
+    /// Decode the columns `cols` of several row groups, e.g. the current
+    /// and upcoming ones. Row groups are decoded concurrently as long as
+    /// their decoded size fits within the memory limit; `consumer` is called
+    /// with the index in `rgs` and the columns of each row group, in order,
+    /// or the error decoding it.
+    pub fn decode_rowgroups<'a, B, F>(&self, rgs: &[&'a RowGroup], basket_bytes: B, cols: u64, mut consumer: F)
+        where B: Fn(&'a BasketLocation) -> Result<&'a [u8], Error> + Sync,
+              F: FnMut(usize, Result<Vec<DecodedColumn>, Error>)
+    {
+        // damaged row groups report their error to `consumer` when decoded
+        let sizes: Vec<usize> = rgs.iter().map(|rg| Self::decoded_size(rg, &basket_bytes, cols).unwrap_or(0)).collect();
+        let mut start = 0;
+        while start < rgs.len() {
+            // as many row groups as fit into the limit, but at least one
+            let mut end = start + 1;
+            let mut used = sizes[start];
+            while end < rgs.len() && used + sizes[end] <= self.memory_limit {
+                used += sizes[end];
+                end += 1;
+            }
+            trace!("decoding row groups {}..{} ({} bytes) in parallel", start, end, used);
+            let decoded: Vec<Result<Vec<DecodedColumn>, Error>> = self.pool.install(|| {
+                rgs[start..end].par_iter()
+                    .map(|rg| Self::decode_columns_in_pool(rg, &basket_bytes, cols))
+                    .collect()
+            });
+            for (i, columns) in decoded.into_iter().enumerate() {
+                consumer(start + i, columns);
+            }
+            start = end;
+        }
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use super::*;
+    use crate::core::RootFile;
+    use std::path::Path;
+
+    fn rowgroups(path: &str) -> (Vec<u8>, Vec<RowGroup>) {
+        let file = RootFile::new(Path::new(path)).unwrap();
+        let tree = file.items()[0].as_tree().unwrap();
+        (std::fs::read(path).unwrap(), RowGroup::find_rowgroups(&tree).unwrap())
+    }
+
+    #[test]
+    fn same_as_sequential() {
+        let (data, rgs) = rowgroups("./src/test_data/Zmumu-lzma.root");
+        let cols = (1u64 << rgs[0].containers.len()) - 1;
+        for threads in [1, 4] {
+            let decoder = ParallelDecoder::new(threads).unwrap();
+            assert_eq!(decoder.threads(), threads);
+            for rg in &rgs {
+                let expected = rg.decode(&data, cols, Vec::new(), |mut v, cursor, bytes| {
+                    v.push((cursor.global_col_idx, bytes.to_vec()));
+                    v
+                }).unwrap();
+                let decoded = decoder.decode_columns(rg, |b| b.bytes(&data), cols).unwrap();
+                let decoded: Vec<_> = decoded.iter().map(|c| (c.cursor.global_col_idx, c.bytes().to_vec())).collect();
+                assert_eq!(decoded, expected);
+            }
+        }
+    }
+
+    #[test]
+    fn rowgroups_within_memory_limit() {
+        let (data, rgs) = rowgroups("./src/test_data/foriter.root");
+        let rgs: Vec<&RowGroup> = rgs.iter().collect();
+        let cols = 1;
+        let sizes: Vec<usize> = rgs.iter().map(|rg| ParallelDecoder::decoded_size(rg, &|b: &BasketLocation| b.bytes(&data), cols).unwrap()).collect();
+        let mut seen = Vec::new();
+        let decoder = ParallelDecoder::new(3).unwrap().with_memory_limit(sizes[0] + sizes[1]);
+        decoder.decode_rowgroups(&rgs, |b| b.bytes(&data), cols, |idx, columns| {
+            let columns = columns.unwrap();
+            assert_eq!(columns.len(), 1);
+            assert_eq!(columns.iter().map(|c| c.data.len()).sum::<usize>(), sizes[idx]);
+            seen.push(idx);
+        });
+        assert_eq!(seen, (0..rgs.len()).collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,153 @@
+//! Converting trees to Parquet. Every ROOT row group becomes a Parquet
+//! row group of its own, unless it holds more entries than the configured
+//! row group size, in which case it is split. The schema carries the same
+//! ROOT metadata as the Arrow IPC export, see `export_schema`.
+
+use std::io::Write;
+use std::sync::Arc;
+
+use arrow::record_batch::RecordBatch;
+use failure::Error;
+use parquet::{
+    arrow::ArrowWriter,
+    basic::Compression,
+    file::properties::WriterProperties,
+};
+
+use crate::anyblox::{
+    branches_to_arrow_schema, export_colmask, export_schema, export_tree_item, rowgroup_to_record_batch_from_source,
+    to_native_endian, RowGroupIndex, DEFAULT_MAX_GAP,
+};
+use crate::core::RootFile;
+
+/// Conversion of a tree, or a projection of it, to a Parquet file
+#[derive(Debug, Clone)]
+pub struct ParquetExport {
+    tree: Option<String>,
+    colmask: Option<u64>,
+    compression: Compression,
+    row_group_size: usize,
+}
+
+impl Default for ParquetExport {
+    fn default() -> Self {
+        ParquetExport {
+            tree: None,
+            colmask: None,
+            compression: Compression::SNAPPY,
+            row_group_size: usize::MAX,
+        }
+    }
+}
+
+impl ParquetExport {
+    /// Conversion of all columns of a supported type of the biggest tree, Snappy compressed,
+    /// with one Parquet row group per ROOT row group
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Convert the tree `spec` (`name` or `name;cycle`, see `RootFile::get`)
+    pub fn with_tree(mut self, spec: &str) -> Self {
+        self.tree = Some(spec.to_string());
+        self
+    }
+
+    /// Convert only the columns in `colmask`. Writing fails if it selects a
+    /// column of a type that cannot be decoded.
+    pub fn with_columns(mut self, colmask: u64) -> Self {
+        self.colmask = Some(colmask);
+        self
+    }
+
+    /// Codec of the column chunks, e.g. `Compression::ZSTD(ZstdLevel::try_new(3)?)`
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Upper bound for the entries of a Parquet row group; bigger ROOT row
+    /// groups are split
+    pub fn with_row_group_size(mut self, entries: usize) -> Self {
+        self.row_group_size = entries.max(1);
+        self
+    }
+
+    /// Decode the row groups of the tree in `file` and write them to `out`.
+    /// Returns the number of entries written.
+    pub fn write<W: Write + Send>(&self, file: &RootFile, out: W) -> Result<u64, Error> {
+        let item = export_tree_item(file, self.tree.as_deref())?;
+        let index = RowGroupIndex::from_tree(&item.as_tree()?)?;
+        let colmask = export_colmask(&index, self.colmask)?;
+        let schema = Arc::new(export_schema(item, &index, colmask));
+        let decode_schema = Arc::new(branches_to_arrow_schema(&index.columns, colmask));
+        let props = WriterProperties::builder()
+            .set_compression(self.compression)
+            .set_max_row_group_size(self.row_group_size)
+            .set_created_by(format!("anyroot version {}", env!("CARGO_PKG_VERSION")))
+            .build();
+        let mut writer = ArrowWriter::try_new(out, schema.clone(), Some(props))?;
+        let mut written = 0u64;
+        for rg in &index.rowgroups {
+            let batch = rowgroup_to_record_batch_from_source(file.source(), colmask, rg, decode_schema.clone(), DEFAULT_MAX_GAP)?;
+            let batch = to_native_endian(&batch)?;
+            writer.write(&RecordBatch::try_new(schema.clone(), batch.columns().to_vec())?)?;
+            // close the Parquet row group at the ROOT row group boundary
+            writer.flush()?;
+            written += rg.count as u64;
+        }
+        let meta = writer.close()?;
+        debug!("converted {} entries of {} to {} Parquet row groups", written, item.name(), meta.row_groups.len());
+        Ok(written)
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use super::*;
+    use crate::anyblox::{META_LEAF_TYPE, META_TREE_NAME};
+    use arrow::array::AsArray;
+    use arrow::datatypes::Int32Type;
+    use bytes::Bytes;
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+    use parquet::basic::ZstdLevel;
+    use std::path::Path;
+
+    fn convert(export: ParquetExport) -> ParquetRecordBatchReaderBuilder<Bytes> {
+        let file = RootFile::new(Path::new("./src/test_data/foriter.root")).unwrap();
+        let mut buf = Vec::new();
+        assert_eq!(export.write(&file, &mut buf).unwrap(), 46);
+        ParquetRecordBatchReaderBuilder::try_new(Bytes::from(buf)).unwrap()
+    }
+
+    fn values(builder: ParquetRecordBatchReaderBuilder<Bytes>) -> Vec<i32> {
+        builder.build().unwrap()
+            .flat_map(|b| b.unwrap().column(0).as_primitive::<Int32Type>().values().to_vec())
+            .collect()
+    }
+
+    #[test]
+    fn rowgroups_map_to_parquet() {
+        let builder = convert(ParquetExport::new());
+        assert_eq!(builder.metadata().num_row_groups(), 8);
+        let schema = builder.schema().clone();
+        assert_eq!(schema.metadata()[META_TREE_NAME], "foriter");
+        assert_eq!(schema.field(0).metadata()[META_LEAF_TYPE], "i32");
+        assert_eq!(values(builder), (0..46).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn split_and_compressed() {
+        let export = ParquetExport::new()
+            .with_tree("foriter")
+            .with_columns(1)
+            .with_compression(Compression::ZSTD(ZstdLevel::try_new(3).unwrap()))
+            .with_row_group_size(4);
+        let builder = convert(export);
+        let meta = builder.metadata().clone();
+        assert!(meta.row_groups().iter().all(|rg| rg.num_rows() <= 4));
+        assert!(meta.num_row_groups() > 8);
+        assert_eq!(meta.row_group(0).column(0).compression(), Compression::ZSTD(ZstdLevel::default()));
+        assert_eq!(values(builder), (0..46).collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,200 @@
+//! Reading trees into Polars. `PolarsScan` produces `DataFrame`s from
+//! the Arrow batches of `rowgroup_to_record_batch_from_source`, decoding
+//! only the selected columns and the row groups overlapping the selected
+//! rows. As a Polars `AnonymousScan` it backs `LazyFrame`s, into which
+//! Polars pushes projections and slices down.
+//!
+//! Arrays are handed to Polars through the Arrow C Data Interface, after
+//! swapping numeric columns to native byte order (see `to_native_endian`).
+
+use std::any::Any;
+use std::ops::Range;
+use std::sync::Arc;
+
+use arrow::{
+    array::ArrayRef,
+    datatypes::Field as ArrowRsField,
+    ffi::{to_ffi, FFI_ArrowArray, FFI_ArrowSchema},
+};
+use failure::Error;
+use polars::prelude::*;
+use polars_arrow::ffi::{import_array_from_c, import_field_from_c, ArrowArray, ArrowSchema};
+
+use crate::anyblox::{
+    branches_to_arrow_schema, export_tree_item, rowgroup_to_record_batch_from_source, supported_columns, to_native_endian,
+    RowGroupIndex, DEFAULT_MAX_GAP,
+};
+use crate::core::{types::Tid, RootFile, Source};
+
+fn to_polars_err<E: std::fmt::Display>(e: E) -> PolarsError {
+    PolarsError::ComputeError(e.to_string().into())
+}
+
+// `import_array` and `polars_dtype` move the C Data Interface structs
+// between arrow-rs and Polars, which define them independently
+const _: () = assert!(size_of::<FFI_ArrowArray>() == size_of::<ArrowArray>() && align_of::<FFI_ArrowArray>() == align_of::<ArrowArray>());
+const _: () = assert!(size_of::<FFI_ArrowSchema>() == size_of::<ArrowSchema>() && align_of::<FFI_ArrowSchema>() == align_of::<ArrowSchema>());
+
+/// Move an array exported by arrow-rs into a Polars array; both
+/// implement the C Data Interface structs with the same layout
+fn import_array(array: &ArrayRef) -> PolarsResult<Box<dyn polars_arrow::array::Array>> {
+    let (array, schema) = to_ffi(&array.to_data()).map_err(to_polars_err)?;
+    // Safety: the structs are `repr(C)` definitions of the same C types,
+    // the release callbacks move along with them
+    unsafe {
+        let array = std::mem::transmute::<FFI_ArrowArray, ArrowArray>(array);
+        let schema = std::mem::transmute::<FFI_ArrowSchema, ArrowSchema>(schema);
+        let field = import_field_from_c(&schema)?;
+        import_array_from_c(array, field.dtype)
+    }
+}
+
+fn polars_dtype(field: &ArrowRsField) -> PolarsResult<DataType> {
+    let schema = FFI_ArrowSchema::try_from(field).map_err(to_polars_err)?;
+    // Safety: see `import_array`
+    let field = unsafe { import_field_from_c(&std::mem::transmute::<FFI_ArrowSchema, ArrowSchema>(schema))? };
+    Ok(DataType::from_arrow_field(&field))
+}
+
+/// A tree read into Polars. Only columns of types `rowgroup_to_record_batch`
+/// can decode are part of its schema.
+#[derive(Clone)]
+pub struct PolarsScan {
+    source: Source,
+    index: Arc<RowGroupIndex>,
+    schema: SchemaRef,
+}
+
+impl PolarsScan {
+    /// Scan the tree `spec` (see `RootFile::get`), or without one the
+    /// biggest tree, of `file`
+    pub fn new(file: &RootFile, tree: Option<&str>) -> Result<Self, Error> {
+        let item = export_tree_item(file, tree)?;
+        let index = RowGroupIndex::from_tree(&item.as_tree()?)?;
+        let fields = branches_to_arrow_schema(&index.columns, supported_columns(&index.columns)).fields().iter()
+            .map(|field| Ok(Field::new(field.name().into(), polars_dtype(field)?)))
+            .collect::<PolarsResult<Vec<_>>>()?;
+        Ok(PolarsScan {
+            source: file.source().clone(),
+            index: Arc::new(index),
+            schema: Arc::new(Schema::from_iter(fields)),
+        })
+    }
+
+    pub fn schema(&self) -> SchemaRef {
+        self.schema.clone()
+    }
+
+    /// Decode the columns `columns` (all if `None`, in the given order) of
+    /// the entries `rows` (all if `None`). Rows past the last entry are
+    /// ignored, a reversed range is an error.
+    pub fn dataframe(&self, columns: Option<&[&str]>, rows: Option<Range<Tid>>) -> PolarsResult<DataFrame> {
+        let names: Vec<&str> = match columns {
+            Some(columns) => columns.to_vec(),
+            None => self.schema.iter_names().map(|name| name.as_str()).collect(),
+        };
+        let ids = self.index.column_ids(Some(&names)).map_err(|e| PolarsError::ColumnNotFound(e.to_string().into()))?;
+        let colmask = ids.iter().fold(0u64, |mask, id| mask | 1 << id);
+        let rows = rows.unwrap_or(0..self.index.tuples);
+        if rows.start > rows.end {
+            return Err(PolarsError::OutOfBounds(format!("reversed range of rows {}..{}", rows.start, rows.end).into()));
+        }
+        let arrow_schema = Arc::new(branches_to_arrow_schema(&self.index.columns, colmask));
+
+        // per projected column (in file order), the arrays of the row groups
+        let mut chunks: Vec<Vec<ArrayRef>> = vec![Vec::new(); colmask.count_ones() as usize];
+        for (rg, rows) in self.index.slices(rows) {
+            let batch = rowgroup_to_record_batch_from_source(&self.source, colmask, rg, arrow_schema.clone(), DEFAULT_MAX_GAP)
+                .map_err(to_polars_err)?;
+            let batch = to_native_endian(&batch.slice(rows.start, rows.len())).map_err(to_polars_err)?;
+            for (chunk, column) in chunks.iter_mut().zip(batch.columns()) {
+                chunk.push(column.clone());
+            }
+        }
+
+        let columns = names.iter().zip(&ids).map(|(name, id)| {
+            // position of the column among the projected ones
+            let projected = (colmask & ((1u64 << id) - 1)).count_ones() as usize;
+            let series = if chunks[projected].is_empty() {
+                Series::new_empty((*name).into(), self.schema.get(name).unwrap())
+            } else {
+                let arrays = chunks[projected].iter().map(import_array).collect::<PolarsResult<Vec<_>>>()?;
+                Series::from_arrow_chunks((*name).into(), arrays)?
+            };
+            Ok(series.into_column())
+        }).collect::<PolarsResult<Vec<_>>>()?;
+        DataFrame::new(columns)
+    }
+
+    /// Lazy scan of the tree; projections and slices of the query are only
+    /// decoded
+    pub fn lazy(self) -> PolarsResult<LazyFrame> {
+        let args = ScanArgsAnonymous {
+            schema: Some(self.schema.clone()),
+            name: "anyroot",
+            ..Default::default()
+        };
+        LazyFrame::anonymous_scan(Arc::new(self), args)
+    }
+}
+
+impl AnonymousScan for PolarsScan {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn scan(&self, scan_opts: AnonymousScanArgs) -> PolarsResult<DataFrame> {
+        let columns = scan_opts.with_columns.as_ref().map(|cols| cols.iter().map(|c| c.as_str()).collect::<Vec<_>>());
+        let rows = scan_opts.n_rows.map(|n| 0..Tid::try_from(n).unwrap_or(Tid::MAX).min(self.index.tuples));
+        self.dataframe(columns.as_deref(), rows)
+    }
+
+    fn schema(&self, _infer_schema_length: Option<usize>) -> PolarsResult<SchemaRef> {
+        Ok(self.schema.clone())
+    }
+
+    fn allows_projection_pushdown(&self) -> bool {
+        true
+    }
+
+    fn allows_slice_pushdown(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    fn scan(path: &str) -> PolarsScan {
+        PolarsScan::new(&RootFile::new(Path::new(path)).unwrap(), None).unwrap()
+    }
+
+    #[test]
+    fn dataframe_with_rows() {
+        let scan = scan("./src/test_data/foriter.root");
+        let df = scan.dataframe(None, Some(5..30)).unwrap();
+        assert_eq!(df.shape(), (25, 1));
+        let values: Vec<i32> = df.get_columns()[0].i32().unwrap().into_no_null_iter().collect();
+        assert_eq!(values, (5..30).collect::<Vec<_>>());
+        assert_eq!(scan.dataframe(None, Some(46..46)).unwrap().height(), 0);
+        assert_eq!(scan.dataframe(None, Some(40..100)).unwrap().height(), 6);
+        assert!(scan.dataframe(None, Some(Range { start: 30, end: 5 })).is_err());
+        assert!(scan.dataframe(Some(&["missing"]), None).is_err());
+    }
+
+    #[test]
+    fn lazy_projection_and_slice() {
+        let scan = scan("./src/test_data/Zmumu-lzma.root");
+        let eager = scan.dataframe(Some(&["E2", "px1"]), None).unwrap();
+        let df = scan.lazy().unwrap()
+            .select([col("px1"), col("E2")])
+            .limit(10)
+            .collect()
+            .unwrap();
+        assert_eq!(df.get_column_names(), ["px1", "E2"]);
+        assert_eq!(df.height(), 10);
+        assert_eq!(df.column("px1").unwrap(), &eager.column("px1").unwrap().slice(0, 10));
+    }
+}
@@ -0,0 +1,219 @@
+//! Planning the reads for decoding a projected row group from a `Source`
+//! which is not memory mapped. Instead of reading one basket at a time,
+//! the byte ranges of all needed baskets are sorted and ranges closer
+//! than a gap threshold are merged, so that a row group typically costs a
+//! handful of large reads (syscalls or HTTP requests).
+
+use std::ops::Range;
+
+use bytes::Bytes;
+use failure::Error;
+
+use crate::anyblox::{BasketLocation, ColumnProjection, RowGroup};
+use crate::core::Source;
+
+/// Default gap threshold: reading up to 64kB of unneeded data is
+/// cheaper than an additional request
+pub const DEFAULT_MAX_GAP: u64 = 64 * 1024;
+
+/// Merged byte ranges to read for a (row group, projection)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReadPlan {
+    pub reads: Vec<Range<u64>>,
+}
+
+impl ReadPlan {
+    /// Plan the reads of the on-disk baskets of the columns in `cols`.
+    /// Ranges separated by at most `max_gap` bytes are read together.
+    pub fn new(rg: &RowGroup, cols: u64, max_gap: u64) -> Self {
+        let colmask = ColumnProjection::from_u64(cols);
+        let mut ranges = rg.containers.iter().enumerate()
+            .filter(|(colid, _)| colmask.contains(*colid as u32))
+            .flat_map(|(_, baskets)| baskets.iter())
+            .filter_map(|basket| match basket {
+                BasketLocation::OnDisk(start, len) => Some(*start..start.saturating_add(*len as u64)),
+                BasketLocation::InMemory(_) => None,
+            })
+            .collect::<Vec<_>>();
+        ranges.sort_by_key(|r| r.start);
+        let mut reads: Vec<Range<u64>> = Vec::new();
+        for range in ranges {
+            match reads.last_mut() {
+                Some(last) if range.start <= last.end.saturating_add(max_gap) => last.end = last.end.max(range.end),
+                _ => reads.push(range),
+            }
+        }
+        ReadPlan { reads }
+    }
+
+    /// Total number of bytes read, including gaps
+    pub fn bytes(&self) -> u64 {
+        self.reads.iter().map(|r| r.end - r.start).sum()
+    }
+
+    /// Hint `source` to read ahead the planned ranges, e.g. for the row
+    /// group after the one being decoded
+    pub fn prefetch(&self, source: &Source) {
+        for r in &self.reads {
+            source.prefetch(r.start, r.end - r.start);
+        }
+    }
+
+    /// Issue the planned reads
+    pub fn execute(&self, source: &Source) -> Result<FetchedBaskets, Error> {
+        let reads = self.reads.iter()
+            .map(|r| source.fetch(r.start, r.end - r.start).map(|buf| (r.start, buf)))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(FetchedBaskets { reads })
+    }
+
+    /// Issue the planned reads concurrently with `Source::fetch_async`
+    #[cfg(all(feature = "async", not(target_arch = "wasm32")))]
+    pub async fn execute_async(&self, source: &Source) -> Result<FetchedBaskets, Error> {
+        let reads = futures::future::try_join_all(self.reads.iter().map(|r| async move {
+            source.fetch_async(r.start, r.end - r.start).await.map(|buf| (r.start, buf))
+        })).await?;
+        Ok(FetchedBaskets { reads })
+    }
+}
+
+/// Data read by a `ReadPlan`, from which the baskets are served
+pub struct FetchedBaskets {
+    /// start offset and data of each read, sorted by offset
+    reads: Vec<(u64, Bytes)>,
+}
+
+impl FetchedBaskets {
+    /// Serve the baskets from `data` holding the whole file, e.g. a mapping
+    pub fn whole_file(data: Bytes) -> Self {
+        FetchedBaskets { reads: vec![(0, data)] }
+    }
+
+    /// Bytes of `basket`. Fails for an on-disk basket which the plan did
+    /// not cover, e.g. one beyond the end of the file.
+    pub fn bytes<'a>(&'a self, basket: &'a BasketLocation) -> Result<&'a [u8], Error> {
+        match basket {
+            BasketLocation::OnDisk(start, len) => {
+                let (offset, buf) = self.read_of(*start, *len as u64)
+                    .ok_or_else(|| format_err!("basket at {} ({} bytes) was not read", start, len))?;
+                let first = (*start - offset) as usize;
+                Ok(&buf[first..first + *len as usize])
+            }
+            BasketLocation::InMemory(buf) => Ok(buf.as_slice()),
+        }
+    }
+
+    /// `subset` of the bytes of the on-disk `basket` as `Bytes` sharing
+    /// the read data, e.g. the memory mapping of a `MmapSource`
+    pub fn slice_ref(&self, basket: &BasketLocation, subset: &[u8]) -> Option<Bytes> {
+        match basket {
+            BasketLocation::OnDisk(start, len) => Some(self.read_of(*start, *len as u64)?.1.slice_ref(subset)),
+            BasketLocation::InMemory(_) => None,
+        }
+    }
+
+    /// The read covering `len` bytes at `start`, if any
+    fn read_of(&self, start: u64, len: u64) -> Option<&(u64, Bytes)> {
+        let idx = self.reads.partition_point(|(offset, _)| *offset <= start);
+        idx.checked_sub(1)
+            .map(|idx| &self.reads[idx])
+            .filter(|(offset, buf)| start.checked_add(len).is_some_and(|end| end <= offset + buf.len() as u64))
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use super::*;
+    use crate::core::{DataSource, RootFile};
+    use std::path::Path;
+    use std::sync::{Arc, Mutex};
+
+    /// Backend recording the ranges it serves
+    #[derive(Debug)]
+    struct Recording(Bytes, Arc<Mutex<Vec<Range<u64>>>>);
+
+    impl DataSource for Recording {
+        fn fetch(&self, start: u64, len: u64) -> Result<Bytes, Error> {
+            self.1.lock().unwrap().push(start..start + len);
+            Ok(self.0.slice(start as usize..(start + len) as usize))
+        }
+
+        fn len(&self) -> Result<u64, Error> {
+            Ok(self.0.len() as u64)
+        }
+    }
+
+    fn rowgroup(containers: Vec<Vec<(u64, u32)>>) -> RowGroup {
+        RowGroup {
+            start_tid: 0,
+            count: 1,
+            containers: containers.into_iter()
+                .map(|c| c.into_iter().map(|(s, l)| BasketLocation::OnDisk(s, l)).collect())
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn merge_ranges() {
+        let rg = rowgroup(vec![vec![(1000, 100), (0, 100)], vec![(110, 50)], vec![(2000, 10)]]);
+        // adjacent within the gap, unordered input, projection skipping the last column
+        assert_eq!(ReadPlan::new(&rg, 0b011, 10).reads, vec![0..160, 1000..1100]);
+        assert_eq!(ReadPlan::new(&rg, 0b111, 0).reads, vec![0..100, 110..160, 1000..1100, 2000..2010]);
+        assert_eq!(ReadPlan::new(&rg, 0b111, 1000).reads, vec![0..2010]);
+        assert_eq!(ReadPlan::new(&rg, 0b100, 1000).bytes(), 10);
+        // offsets near the end of the address space do not overflow
+        let rg = rowgroup(vec![vec![(u64::MAX - 10, 100), (0, 100)]]);
+        assert_eq!(ReadPlan::new(&rg, 1, u64::MAX).reads, vec![0..u64::MAX]);
+    }
+
+    #[test]
+    fn basket_not_read() {
+        let fetched = FetchedBaskets::whole_file(Bytes::from_static(&[0; 10]));
+        assert_eq!(fetched.bytes(&BasketLocation::OnDisk(2, 8)).unwrap().len(), 8);
+        assert!(fetched.bytes(&BasketLocation::OnDisk(5, 10)).is_err());
+        assert!(fetched.bytes(&BasketLocation::OnDisk(u64::MAX, 10)).is_err());
+        assert!(fetched.slice_ref(&BasketLocation::OnDisk(5, 10), &[]).is_none());
+    }
+
+    #[test]
+    fn decode_from_source() {
+        let path = "./src/test_data/foriter.root";
+        let data = Bytes::from(std::fs::read(path).unwrap());
+        let file = RootFile::new(Path::new(path)).unwrap();
+        let tree = file.items()[0].as_tree().unwrap();
+        let rgs = RowGroup::find_rowgroups(&tree).unwrap();
+        let source = Source::from(Path::new(path));
+        let schema = std::sync::Arc::new(crate::anyblox::tree_to_arrow_schema(&tree, 1));
+        for rg in &rgs {
+            let plan = ReadPlan::new(rg, 1, DEFAULT_MAX_GAP);
+            assert_eq!(plan.reads.len(), 1);
+            let expected = crate::anyblox::rowgroup_to_record_batch(&data, 1, rg, schema.clone()).unwrap();
+            let batch = crate::anyblox::rowgroup_to_record_batch_from_source(&source, 1, rg, schema.clone(), DEFAULT_MAX_GAP).unwrap();
+            assert_eq!(batch, expected);
+        }
+    }
+
+    #[test]
+    fn coalesced_reads() {
+        let data = Bytes::from(std::fs::read("./src/test_data/Zmumu-uncompressed.root").unwrap());
+        let reads = Arc::new(Mutex::new(Vec::new()));
+        let source = Source::with_backend(Recording(data.clone(), reads.clone()));
+        let index = crate::anyblox::RowGroupIndex::from_file(&RootFile::new(source.clone()).unwrap()).unwrap();
+        let rg = &index.rowgroups[0];
+        // `Run` and `E1`, whose baskets are separated by the one of `Event`
+        let cols = 0b1010;
+        let schema = Arc::new(crate::anyblox::branches_to_arrow_schema(&index.columns, cols));
+        let expected = crate::anyblox::rowgroup_to_record_batch(&data, cols, rg, schema.clone()).unwrap();
+        let run = match rg.containers[1][..] { [BasketLocation::OnDisk(start, len)] => start..start + len as u64, _ => unreachable!() };
+        let e1 = match rg.containers[3][..] { [BasketLocation::OnDisk(start, len)] => start..start + len as u64, _ => unreachable!() };
+        let gap = e1.start - run.end;
+        let merged = Range { start: run.start, end: e1.end };
+        for (max_gap, planned) in [(gap - 1, vec![run.clone(), e1.clone()]), (gap, vec![merged])] {
+            reads.lock().unwrap().clear();
+            let batch = crate::anyblox::rowgroup_to_record_batch_from_source(&source, cols, rg, schema.clone(), max_gap).unwrap();
+            // one read per planned range, nothing else
+            assert_eq!(*reads.lock().unwrap(), planned);
+            assert_eq!(batch, expected);
+        }
+    }
+}
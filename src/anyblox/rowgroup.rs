@@ -1,14 +1,46 @@
 use std::fmt::{Formatter, Debug};
-use crate::core::
+use std::sync::Arc;
+use crate::core::{
     types::{Tid}
-;
-use crate::tree_reader::{Tree, Container, basket_header};
+};
+use crate::tree_reader::{Tree, TBranch, Container, BasketHeader, basket_header};
 use crate::anyblox::ColumnProjection;
 
 use aligned_vec::AVec;
 use failure::Error;
 use nom::IResult;
 
+/// Where the bytes of a basket (including its key header) live
+#[derive(Clone, PartialEq)]
+pub enum BasketLocation {
+    /// start and length of the basket in the file
+    OnDisk(u64, u32),
+    /// basket embedded in its `TBranch`, as in files that were not closed properly
+    InMemory(Arc<Vec<u8>>),
+}
+
+impl BasketLocation {
+    /// The basket bytes, reading on-disk baskets from the file data `mmap`.
+    /// Fails if the basket is not within `mmap`.
+    pub fn bytes<'a>(&'a self, mmap: &'a [u8]) -> Result<&'a [u8], Error> {
+        match self {
+            BasketLocation::OnDisk(start, len) => usize::try_from(*start).ok()
+                .and_then(|first| mmap.get(first..first.checked_add(*len as usize)?))
+                .ok_or_else(|| format_err!("basket at {} ({} bytes) is beyond the {} bytes of the file", start, len, mmap.len())),
+            BasketLocation::InMemory(buf) => Ok(buf.as_slice()),
+        }
+    }
+}
+
+impl Debug for BasketLocation {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BasketLocation::OnDisk(start, len) => write!(f, "OnDisk({}, {})", start, len),
+            BasketLocation::InMemory(buf) => write!(f, "InMemory({} bytes)", buf.len()),
+        }
+    }
+}
+
 /// row groups are implicit in file format, we need to 'find' them
 /// by finding alignment points between containers (= column chunks)
 /// of the tree branches.
@@ -16,7 +48,7 @@ use nom::IResult;
 pub struct RowGroup {
     pub start_tid: Tid,
     pub count: Tid,
-    pub containers: Vec<Vec<(u32, u32)>>
+    pub containers: Vec<Vec<BasketLocation>>
 }
 
 impl Debug for RowGroup {
@@ -38,10 +70,10 @@ pub struct RowGroupDecodeCursor {
 
 impl RowGroup {
 
-    pub fn container_to_offsets(c: &Container) -> (u32, u32) {
+    pub fn container_location(c: &Container) -> BasketLocation {
        match c {
-           Container::InMemory(_) => panic!("not implemented"),
-           Container::OnDisk(_src, start, len) => (*start as u32, *len as u32)
+           Container::InMemory(buf) => BasketLocation::InMemory(Arc::new(buf.clone())),
+           Container::OnDisk(_src, start, len) => BasketLocation::OnDisk(*start, *len as u32),
        }
     }
 
@@ -49,75 +81,112 @@ impl RowGroup {
         self.start_tid + self.count
     }
 
-    pub fn find_rowgroups(t: &Tree) -> Vec<RowGroup> {
-        let branches = &t.fbranches;
-        let bcnt = t.branch_count();
-        // result
+    /// Check that the basket layout of `branch` is consistent with a tree of `entries` entries
+    fn check_branch(branch: &TBranch, entries: Tid) -> Result<(), Error> {
+        let starts = branch.container_start_indices();
+        let name = &branch.name;
+        if branch.entries() != entries as i64 {
+            return Err(format_err!("branch `{}` has {} entries, but the tree has {}", name, branch.entries(), entries));
+        }
+        if branch.containers().len() != starts.len() {
+            return Err(format_err!("branch `{}` has {} baskets, but {} basket entry offsets", name, branch.containers().len(), starts.len()));
+        }
+        match starts.first() {
+            None if entries > 0 => return Err(format_err!("branch `{}` has {} entries, but no baskets", name, entries)),
+            Some(first) if *first != 0 => return Err(format_err!("first basket of branch `{}` starts at entry {}", name, first)),
+            _ => {}
+        }
+        if let Some(idx) = starts.windows(2).position(|w| w[0] >= w[1]) {
+            return Err(format_err!("basket entry offsets of branch `{}` are not increasing at basket {}", name, idx + 1));
+        }
+        if starts.last().is_some_and(|last| *last >= entries) {
+            return Err(format_err!("last basket of branch `{}` starts past the tree's {} entries", name, entries));
+        }
+        Ok(())
+    }
+
+    /// Row group boundaries are the entries at which a basket starts in every branch.
+    /// Each row group holds, per branch, the baskets starting within its entry range.
+    pub fn find_rowgroups(t: &Tree) -> Result<Vec<RowGroup>, Error> {
+        let branches = t.main_branches();
+        let max_tid = Tid::try_from(t.entries()).map_err(|_| format_err!("tree has too many entries ({})", t.entries()))?;
+        if max_tid == 0 || branches.is_empty() {
+            debug!("tree has {} entries and {} branches, no row groups", max_tid, branches.len());
+            return Ok(Vec::new());
+        }
+        for branch in branches {
+            Self::check_branch(branch, max_tid)?;
+        }
+        let boundaries = branches[0].container_start_indices().iter().copied().skip(1)
+            .filter(|tid| branches[1..].iter().all(|b| b.container_start_indices().binary_search(tid).is_ok()))
+            .chain(std::iter::once(max_tid));
         let mut rowgroups: Vec<RowGroup> = Vec::new();
-        let mut container_ids = vec![0usize; bcnt];
-        let bundle_cur_containers = |ids: &mut Vec<usize>| {
-            (0..bcnt).map(|idx| {
-                let off = ids[idx]; ids[idx] += 1;
-                vec![Self::container_to_offsets(&branches[idx].containers()[off]); 1]
-            }).collect::<Vec<Vec<(u32, u32)>>>()
-        };
-        let max_tid = t.entries() as Tid;
-        let mut bundle_rowgroup = |tid_end: Tid, current: RowGroup, ids: &mut Vec<usize>| {
-            let mut res = RowGroup{start_tid: current.start_tid, count: tid_end - current.start_tid, containers: current.containers };
-            if tid_end ==  max_tid { // collect rest of containers
-              (0..bcnt).for_each(|idx| {
-                  let missing_range = ids[idx]..(&branches[idx]).containers().len();
-                  missing_range.for_each(|off| {
-                      res.containers[idx].push(Self::container_to_offsets(&branches[idx].containers()[off]));
-                  });
-              });
-            }
-            rowgroups.push(res);
-            println!("found rowgroup: {:?}", rowgroups.last().unwrap());
-            RowGroup{start_tid: tid_end, count: 0, containers: if tid_end == max_tid { Vec::new() } else { bundle_cur_containers(ids) } }
-        };
-        let mut cur = RowGroup{start_tid: 0, count: 0, containers: bundle_cur_containers(&mut container_ids)};
-        while cur.start_tid != max_tid {
-            // assert we're not OOB for any branch
-            assert!(container_ids.iter().enumerate().all(|(idx, id)| *id < (&branches[idx]).containers().len()));
-            let first = branches[0].container_start_indices()[container_ids[0]];
-            // check whether all branches are in alignment
-            let (largest_tid, is_same) = (0..bcnt).fold((first, true), |(tid, same), idx| {
-                let branch = &branches[idx];
-                let branch_tid = branch.container_start_indices()[container_ids[idx]];
-                (branch_tid.max(tid), same && branch_tid == tid)
-            });
-            if is_same { // all tids were the same -> row group boundary
-                cur = bundle_rowgroup(largest_tid, cur, &mut container_ids);
-                // this might also be the end iff the very last containers all have the same size
-            } else { // advance the smallest tid(s) if not at row group boundary
-                for idx in 0..bcnt {
-                    let id = &mut container_ids[idx];
-                    let indices = (&branches[idx]).container_start_indices();
-                    let branch_tid = indices[*id];
-                    if branch_tid < largest_tid {
-                        cur.containers[idx].push(Self::container_to_offsets(&branches[idx].containers()[*id]));
-                        *id += 1;
-                    }
+        let mut container_ids = vec![0usize; branches.len()];
+        let mut start_tid = 0;
+        for end_tid in boundaries {
+            let containers = branches.iter().zip(container_ids.iter_mut()).map(|(branch, id)| {
+                let starts = branch.container_start_indices();
+                let first = *id;
+                while *id < starts.len() && starts[*id] < end_tid {
+                    *id += 1;
                 }
-            }
-            if container_ids.iter().enumerate().any(|(idx, id)| *id == (&branches[idx]).containers().len()) {
-                cur = bundle_rowgroup(max_tid, cur, &mut container_ids);
-                assert!(cur.start_tid == max_tid);
-                break;
-            }
+                branch.containers()[first..*id].iter().map(Self::container_location).collect()
+            }).collect();
+            let rg = RowGroup{start_tid, count: end_tid - start_tid, containers};
+            trace!("found rowgroup: {:?}", rg);
+            rowgroups.push(rg);
+            start_tid = end_tid;
         }
-        // sum(rowgroup.#entries) == #file.entries
-        assert!(rowgroups.iter().fold(0usize, |acc, rg| acc + rg.count as usize) == t.entries() as usize);
-        // sum(rowgroup.#containers) == #branch[0].#containers
-        assert!(rowgroups.iter().fold(0usize, |acc, rg| acc + rg.containers[0].len()) == branches[0].containers().len());
-        assert!(rowgroups.iter().fold(0usize, |acc, rg| acc + rg.containers[0].len()) == branches[0].containers().len());
-        // return result
-        rowgroups
+        debug!("found {} row groups for {} entries in {} branches", rowgroups.len(), max_tid, branches.len());
+        Ok(rowgroups)
     }
 
-    pub fn decode<F, T>(&self, mmap: &[u8], cols: u64, mut init: T, consumer: F) -> T
+    pub fn decode<F, T>(&self, mmap: &[u8], cols: u64, init: T, consumer: F) -> Result<T, Error>
         where F: Fn(T, RowGroupDecodeCursor, &[u8]) -> T
+    {
+        self.decode_with(|basket| basket.bytes(mmap), cols, init, consumer)
+    }
+
+    /// Headers of the baskets of column `colid`
+    pub(crate) fn basket_headers<'a, B>(&'a self, colid: usize, basket_bytes: &B) -> Result<Vec<BasketHeader<'a>>, Error>
+        where B: Fn(&'a BasketLocation) -> Result<&'a [u8], Error>
+    {
+        self.containers[colid].iter().map(|basket| {
+            let (_, header) = basket_header(basket_bytes(basket)?)
+                .map_err(|_| format_err!("cannot parse the header of basket {:?} of column {}", basket, colid))?;
+            Ok(header)
+        }).collect()
+    }
+
+    /// Decode the baskets in `meta` one after another into `output`, which
+    /// must hold the sum of their `decoded_size`s. Returns the bytes written.
+    pub(crate) fn decode_baskets(meta: &[BasketHeader], output: &mut [u8]) -> Result<usize, Error> {
+        let totsize = meta.iter().fold(0usize, |acc, m| acc + m.decoded_size());
+        let written = meta.iter().try_fold(0usize, |offset, m| {
+            let nbyte = m.decode_into(&mut output[offset..(offset+m.decoded_size())])?;
+            Ok::<_, Error>(offset + nbyte)
+        })?;
+        assert!(written <= totsize);
+        Ok(written)
+    }
+
+    /// Decode column `colid` into a buffer of its own, aligned to 8 bytes so
+    /// that it can be handed to Arrow as is. Returns the buffer and the
+    /// number of bytes written to it.
+    pub(crate) fn decode_column<'a, B>(&'a self, colid: usize, basket_bytes: &B) -> Result<(AVec<u8>, usize), Error>
+        where B: Fn(&'a BasketLocation) -> Result<&'a [u8], Error>
+    {
+        let meta = self.basket_headers(colid, basket_bytes)?;
+        let totsize = meta.iter().map(|m| m.decoded_size()).sum();
+        let mut data = AVec::new(8);
+        data.resize(totsize, 0u8);
+        let written = Self::decode_baskets(&meta, &mut data)?;
+        Ok((data, written))
+    }
+
+    fn decode_with<'a, B, F, T>(&'a self, basket_bytes: B, cols: u64, mut init: T, consumer: F) -> Result<T, Error>
+        where B: Fn(&'a BasketLocation) -> Result<&'a [u8], Error>,
+              F: Fn(T, RowGroupDecodeCursor, &[u8]) -> T
     {
         let colmask = ColumnProjection::from_u64(cols);
         let allcols = self.containers.len();
@@ -128,19 +197,12 @@ impl RowGroup {
             if !colmask.contains(colid as u32) {
                 continue;
             }
-            let meta = &self.containers[colid].iter().map(|(start, len)| {
-                let buf = &mmap[*start as usize..(*start + *len) as usize];
-                basket_header(buf).unwrap().1
-            }).collect::<Vec<_>>();
-            let totsize = meta.iter().fold(0usize, |acc, m| acc + m.header.uncomp_len as usize);
+            let meta = self.basket_headers(colid, &basket_bytes)?;
+            let totsize = meta.iter().fold(0usize, |acc, m| acc + m.decoded_size());
             if totsize > output.len() {
                 output.resize(totsize, 0);
             }
-            let written = meta.iter().fold(0usize, |offset, m| {
-                let nbyte = m.decode_into(&mut output[offset..(offset+(m.header.uncomp_len as usize))]);
-                offset + nbyte
-            });
-            assert!(written <= totsize);
+            let written = Self::decode_baskets(&meta, &mut output)?;
             init = consumer(
                 init,
                 RowGroupDecodeCursor{global_col_idx: colid, projected_col_idx: colidx, byte_count: written},
@@ -148,36 +210,40 @@ impl RowGroup {
             );
             colidx += 1;
         };
-        init
+        Ok(init)
     }
 }
 
 pub struct DecompressedRowGroup {
     pub start_tid: Tid,
     pub count: Tid,
-    pub data: Vec<Vec<u8>>
+    pub data: Vec<AVec<u8>>
 }
 
 impl DecompressedRowGroup {
-    pub fn new(mmap: &[u8], cols: u64, offsets: &RowGroup) -> Self {
-        let mut coldata = vec![Vec::new(); cols.count_ones() as usize];
-        coldata = offsets.decode(mmap, cols, coldata, |mut cols, cursor, bytes| {
-            cols[cursor.projected_col_idx] = bytes.to_vec();
-            cols
-        });
-        DecompressedRowGroup{
+    pub fn new(mmap: &[u8], cols: u64, offsets: &RowGroup) -> Result<Self, Error> {
+        let colmask = ColumnProjection::from_u64(cols);
+        let coldata = (0..offsets.containers.len())
+            .filter(|colid| colmask.contains(*colid as u32))
+            .map(|colid| {
+                let (mut data, written) = offsets.decode_column(colid, &|basket| basket.bytes(mmap))?;
+                data.truncate(written);
+                Ok(data)
+            })
+            .collect::<Result<_, Error>>()?;
+        Ok(DecompressedRowGroup{
             start_tid: offsets.start_tid,
             count: offsets.count,
             data: coldata
-        }
+        })
     }
 
     pub fn parse_col<P, G, T>(&self, col: usize, parser: P, mut consumer: G) -> Result<(), Error>
     where
         P: Fn(&[u8]) -> IResult<&[u8], T>,
-        G: FnMut(usize, T) -> (),
+        G: FnMut(usize, T),
     {
-        let mut input: &[u8] = &self.data[col].as_slice();
+        let mut input: &[u8] = self.data[col].as_slice();
         for idx in 0..self.count {
             let input_: &[u8] = input;
             match parser(input_) { // use nom parsers
@@ -194,3 +260,101 @@ impl DecompressedRowGroup {
         Ok(())
     }
 }
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use super::{BasketLocation, RowGroup};
+    use crate::core::RootFile;
+    use std::sync::Arc;
+    use std::path::Path;
+
+    fn find(path: &str) -> Result<Vec<RowGroup>, failure::Error> {
+        let f = RootFile::new(Path::new(path)).expect("Failed to open file");
+        let tree = f.items()[0].as_tree().unwrap();
+        RowGroup::find_rowgroups(&tree)
+    }
+
+    #[test]
+    fn single_basket_per_branch() {
+        let rgs = find("./src/test_data/simple.root").unwrap();
+        assert_eq!(rgs.len(), 1);
+        assert_eq!((rgs[0].start_tid, rgs[0].count), (0, 4));
+        assert!(rgs[0].containers.iter().all(|c| c.len() == 1));
+    }
+
+    #[test]
+    fn contiguous_rowgroups() {
+        let rgs = find("./src/test_data/foriter.root").unwrap();
+        assert_eq!(rgs.len(), 8);
+        assert_eq!(rgs[0].start_tid, 0);
+        assert!(rgs.windows(2).all(|w| w[0].end_tid() == w[1].start_tid));
+        assert_eq!(rgs.last().unwrap().end_tid(), 46);
+    }
+
+    #[test]
+    fn in_memory_baskets_decode_like_on_disk() {
+        let data = bytes::Bytes::from(std::fs::read("./src/test_data/foriter.root").unwrap());
+        let rgs = find("./src/test_data/foriter.root").unwrap();
+        let schema = std::sync::Arc::new(crate::anyblox::branches_to_arrow_schema(&[("i".into(), "i32".into())], 1));
+        for rg in &rgs {
+            let embedded = RowGroup {
+                start_tid: rg.start_tid,
+                count: rg.count,
+                containers: rg.containers.iter().map(|c| c.iter().map(|b| {
+                    BasketLocation::InMemory(Arc::new(b.bytes(&data).unwrap().to_vec()))
+                }).collect()).collect(),
+            };
+            let expected = crate::anyblox::rowgroup_to_record_batch(&data, 1, rg, schema.clone()).unwrap();
+            // in-memory baskets must not touch the file data
+            let batch = crate::anyblox::rowgroup_to_record_batch(&bytes::Bytes::new(), 1, &embedded, schema.clone()).unwrap();
+            assert_eq!(batch, expected);
+        }
+    }
+
+    #[test]
+    fn basket_embedded_in_its_branch() {
+        // the last basket of foriter.root was never written to disk
+        let file = RootFile::new(Path::new("./src/test_data/foriter-embedded.root")).unwrap();
+        let tree = file.items()[0].as_tree().unwrap();
+        let branch = tree.branch_by_name("data").unwrap();
+        assert_eq!(branch.container_start_indices(), &[0, 6, 12, 18, 24, 30, 36, 42]);
+        let basket = match branch.containers().last().unwrap() {
+            crate::tree_reader::Container::InMemory(buf) => buf.clone(),
+            c => panic!("expected an embedded basket, got {:?}", c),
+        };
+        let (_, header) = crate::tree_reader::basket_header(&basket).unwrap();
+        assert!(!header.is_compressed());
+        assert_eq!((header.n_entry_buf, header.useful_bytes(), header.decoded_size()), (4, 16, 16));
+        assert!(header.decode_into(&mut [0; 15]).is_err());
+        let values = (42..46).flat_map(|i: i32| i.to_be_bytes()).collect::<Vec<_>>();
+        assert_eq!(branch.containers().last().unwrap().clone().raw_data().unwrap(), (4, values));
+
+        let data = bytes::Bytes::from(std::fs::read("./src/test_data/foriter-embedded.root").unwrap());
+        let expected = bytes::Bytes::from(std::fs::read("./src/test_data/foriter.root").unwrap());
+        let rgs = find("./src/test_data/foriter-embedded.root").unwrap();
+        let expected_rgs = find("./src/test_data/foriter.root").unwrap();
+        assert!(matches!(rgs.last().unwrap().containers[0][..], [BasketLocation::InMemory(_)]));
+        let schema = std::sync::Arc::new(crate::anyblox::branches_to_arrow_schema(&[("data".into(), "i32".into())], 1));
+        assert_eq!(rgs.len(), expected_rgs.len());
+        for (rg, expected_rg) in rgs.iter().zip(&expected_rgs) {
+            assert_eq!(
+                crate::anyblox::rowgroup_to_record_batch(&data, 1, rg, schema.clone()).unwrap(),
+                crate::anyblox::rowgroup_to_record_batch(&expected, 1, expected_rg, schema.clone()).unwrap(),
+            );
+        }
+        file.verify().unwrap();
+    }
+
+    #[test]
+    fn branch_without_baskets_is_an_error() {
+        let err = find("./src/test_data/small-evnt-tree-fullsplit.root").unwrap_err();
+        assert!(err.to_string().contains("no baskets"), "{}", err);
+    }
+
+    #[test]
+    fn damaged_basket_header_is_an_error() {
+        let rg = RowGroup { start_tid: 0, count: 1, containers: vec![vec![BasketLocation::InMemory(Arc::new(vec![0; 10]))]] };
+        let err = rg.decode_column(0, &|b| b.bytes(&[])).unwrap_err();
+        assert!(err.to_string().contains("cannot parse the header"), "{}", err);
+    }
+}
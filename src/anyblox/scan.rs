@@ -0,0 +1,185 @@
+//! Planning table scans of a tree for query engines. A scan pushes the
+//! projection down to the column mask, splits the row groups into
+//! partitions which can be read in parallel and, for a `LIMIT`, only
+//! reads the row groups covering the first entries. This is the
+//! engine-independent part of a table provider; the DataFusion one
+//! (`RootTableProvider`, feature `datafusion`) maps its partitions onto
+//! `ScanPlan::partitions`.
+
+use std::ops::Range;
+use std::sync::Arc;
+
+use arrow::{datatypes::Schema, record_batch::{RecordBatch, RecordBatchOptions}};
+use failure::Error;
+
+use crate::anyblox::{branches_to_arrow_schema, rowgroup_to_record_batch_from_source, RowGroupIndex, DEFAULT_MAX_GAP};
+use crate::core::{types::Tid, Source};
+
+/// Consecutive row groups read by one partition of a scan
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScanPartition {
+    /// indices into `RowGroupIndex::rowgroups`
+    pub rowgroups: Range<usize>,
+    /// entries produced by this partition, within its row groups
+    pub entries: Range<Tid>,
+}
+
+/// Projection, partitioning and limit of a scan over a tree
+#[derive(Debug, Clone)]
+pub struct ScanPlan {
+    pub colmask: u64,
+    /// column ids in the order of the produced batches
+    pub projection: Vec<usize>,
+    pub partitions: Vec<ScanPartition>,
+}
+
+impl ScanPlan {
+    /// Plan a scan of `projection` (column ids, all columns if `None`)
+    /// returning at most `limit` entries, in up to `target_partitions`
+    /// partitions of similar entry counts. Columns are produced in the
+    /// order of `projection`.
+    pub fn new(index: &RowGroupIndex, projection: Option<&[usize]>, limit: Option<usize>, target_partitions: usize) -> Result<Self, Error> {
+        let ncols = index.columns.len();
+        let projection = match projection {
+            None if ncols > 64 => return Err(format_err!("tree has {} columns, at most 64 can be scanned", ncols)),
+            None => (0..ncols).collect(),
+            Some(cols) => cols.to_vec(),
+        };
+        let colmask = projection.iter().try_fold(0u64, |mask, &col| {
+            if col >= ncols.min(64) {
+                return Err(format_err!("column {} is out of range, tree has {} columns", col, ncols));
+            }
+            Ok(mask | 1 << col)
+        })?;
+        let end = limit.map_or(index.tuples, |limit| index.tuples.min(Tid::try_from(limit).unwrap_or(Tid::MAX)));
+        // row groups overlapping 0..end
+        let nrgs = index.rowgroups.partition_point(|rg| rg.start_tid < end);
+        let per_partition = (end as usize).div_ceil(target_partitions.max(1)).max(1);
+        let mut partitions: Vec<ScanPartition> = Vec::new();
+        for (idx, rg) in index.rowgroups[..nrgs].iter().enumerate() {
+            let rg_end = rg.end_tid().min(end);
+            match partitions.last_mut() {
+                Some(p) if ((p.entries.end - p.entries.start) as usize) < per_partition => {
+                    p.rowgroups.end = idx + 1;
+                    p.entries.end = rg_end;
+                }
+                _ => partitions.push(ScanPartition { rowgroups: idx..idx + 1, entries: rg.start_tid..rg_end }),
+            }
+        }
+        Ok(ScanPlan { colmask, projection, partitions })
+    }
+
+    /// Schema of the produced batches, with the columns in projection order
+    pub fn schema(&self, index: &RowGroupIndex) -> Schema {
+        let decoded = branches_to_arrow_schema(&index.columns, self.colmask);
+        Schema::new(self.order().into_iter().map(|idx| decoded.field(idx).clone()).collect::<Vec<_>>())
+    }
+
+    /// Position of every projected column among the decoded columns,
+    /// which are in file order
+    fn order(&self) -> Vec<usize> {
+        self.projection.iter()
+            .map(|col| (self.colmask & ((1 << col) - 1)).count_ones() as usize)
+            .collect()
+    }
+
+    /// Entries produced by the whole scan
+    pub fn entries(&self) -> u64 {
+        self.partitions.iter().map(|p| (p.entries.end - p.entries.start) as u64).sum()
+    }
+
+    /// Batches of partition `partition`, one per row group
+    pub fn execute(&self, partition: usize, source: Source, index: Arc<RowGroupIndex>) -> impl Iterator<Item = Result<RecordBatch, Error>> {
+        let part = self.partitions[partition].clone();
+        let colmask = self.colmask;
+        let order = self.order();
+        let decoded = Arc::new(branches_to_arrow_schema(&index.columns, colmask));
+        let schema = Arc::new(self.schema(&index));
+        part.rowgroups.clone().map(move |idx| {
+            let rg = &index.rowgroups[idx];
+            let start = part.entries.start.max(rg.start_tid) - rg.start_tid;
+            let end = part.entries.end.min(rg.end_tid()) - rg.start_tid;
+            if colmask == 0 {
+                // e.g. `COUNT(*)`: only the number of rows is needed
+                let options = RecordBatchOptions::new().with_row_count(Some((end - start) as usize));
+                return Ok(RecordBatch::try_new_with_options(schema.clone(), vec![], &options)?);
+            }
+            let batch = rowgroup_to_record_batch_from_source(&source, colmask, rg, decoded.clone(), DEFAULT_MAX_GAP)?;
+            Ok(batch.project(&order)?.slice(start as usize, (end - start) as usize))
+        })
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use super::*;
+    use crate::core::RootFile;
+    use std::path::Path;
+
+    fn foriter() -> (Source, Arc<RowGroupIndex>) {
+        let file = RootFile::new(Path::new("./src/test_data/foriter.root")).unwrap();
+        (file.source().clone(), Arc::new(RowGroupIndex::from_file(&file).unwrap()))
+    }
+
+    #[test]
+    fn partitions_cover_all_rowgroups() {
+        let (source, index) = foriter();
+        let plan = ScanPlan::new(&index, None, None, 3).unwrap();
+        assert_eq!(plan.colmask, 1);
+        assert!(plan.partitions.len() <= 3);
+        assert_eq!(plan.partitions.first().unwrap().rowgroups.start, 0);
+        assert_eq!(plan.partitions.last().unwrap().rowgroups.end, 8);
+        assert!(plan.partitions.windows(2).all(|w| w[0].rowgroups.end == w[1].rowgroups.start));
+        let rows: usize = (0..plan.partitions.len())
+            .flat_map(|p| plan.execute(p, source.clone(), index.clone()))
+            .map(|b| b.unwrap().num_rows())
+            .sum();
+        assert_eq!(rows, 46);
+        assert!(ScanPlan::new(&index, Some(&[1]), None, 1).is_err());
+    }
+
+    #[test]
+    fn columns_in_projection_order() {
+        let file = RootFile::new(Path::new("./src/test_data/small-flat-tree.root")).unwrap();
+        let index = Arc::new(RowGroupIndex::from_file(&file).unwrap());
+        let plan = ScanPlan::new(&index, Some(&[5, 0, 2]), None, 1).unwrap();
+        assert_eq!(plan.colmask, 0b100101);
+        let names = |schema: &Schema| schema.fields().iter().map(|f| f.name().clone()).collect::<Vec<_>>();
+        assert_eq!(names(&plan.schema(&index)), ["Float64", "Int32", "UInt32"]);
+        let batch = plan.execute(0, file.source().clone(), index.clone()).next().unwrap().unwrap();
+        assert_eq!(names(&batch.schema()), ["Float64", "Int32", "UInt32"]);
+        let decoded = crate::anyblox::rowgroup_to_record_batch_from_source(
+            file.source(), 0b100101, &index.rowgroups[0], Arc::new(branches_to_arrow_schema(&index.columns, 0b100101)), DEFAULT_MAX_GAP).unwrap();
+        assert_eq!(batch.column(0), decoded.column(2));
+        assert_eq!(batch.column(1), decoded.column(0));
+
+        // no columns at all, e.g. for counting
+        let plan = ScanPlan::new(&index, Some(&[]), None, 1).unwrap();
+        assert_eq!(plan.colmask, 0);
+        let batch = plan.execute(0, file.source().clone(), index.clone()).next().unwrap().unwrap();
+        assert_eq!((batch.num_columns(), batch.num_rows()), (0, index.rowgroups[0].count as usize));
+    }
+
+    #[test]
+    fn tree_without_columns() {
+        let index = RowGroupIndex { tuples: 0, columns: vec![], rowgroups: vec![], basket_entries: vec![] };
+        let plan = ScanPlan::new(&index, None, None, 4).unwrap();
+        assert_eq!((plan.colmask, plan.partitions.len()), (0, 0));
+    }
+
+    #[test]
+    fn limit_reads_first_rowgroups() {
+        let (source, index) = foriter();
+        let plan = ScanPlan::new(&index, Some(&[0]), Some(8), 4).unwrap();
+        assert_eq!(plan.entries(), 8);
+        let last = plan.partitions.last().unwrap();
+        assert!(last.rowgroups.end < 8);
+        assert_eq!(last.entries.end, 8);
+        let rows: usize = (0..plan.partitions.len())
+            .flat_map(|p| plan.execute(p, source.clone(), index.clone()))
+            .map(|b| b.unwrap().num_rows())
+            .sum();
+        assert_eq!(rows, 8);
+        assert!(ScanPlan::new(&index, None, Some(0), 4).unwrap().partitions.is_empty());
+    }
+}
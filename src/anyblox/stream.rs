@@ -0,0 +1,104 @@
+//! Asynchronous reading of a tree as a stream of `RecordBatch`es, one per
+//! row group, for use on tokio. Basket reads are awaited on tokio's
+//! blocking pool and decompression runs there as well; a bounded number
+//! of upcoming row groups is read and decoded ahead of the consumer.
+
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use arrow::{datatypes::Schema, record_batch::RecordBatch};
+use failure::Error;
+use futures::stream::{self, Stream, StreamExt};
+
+use crate::anyblox::{branches_to_arrow_schema, rowgroup_to_record_batch_from_fetched, ReadPlan, RowGroupIndex, DEFAULT_MAX_GAP};
+use crate::core::{RootFile, Source};
+
+/// Stream of the row groups of a tree as `RecordBatch`es, in order
+pub struct RecordBatchStream {
+    schema: Arc<Schema>,
+    inner: Pin<Box<dyn Stream<Item = Result<RecordBatch, Error>> + Send>>,
+}
+
+impl RecordBatchStream {
+    /// Stream the row groups in `index`, keeping up to `prefetch` of them
+    /// (at least one) in flight
+    pub fn new(source: Source, index: Arc<RowGroupIndex>, colmask: u64, prefetch: usize) -> Self {
+        let schema = Arc::new(branches_to_arrow_schema(&index.columns, colmask));
+        let sc = schema.clone();
+        let inner = stream::iter(0..index.rowgroups.len())
+            .map(move |rg| {
+                let (source, index, sc) = (source.clone(), index.clone(), sc.clone());
+                async move {
+                    let plan = ReadPlan::new(&index.rowgroups[rg], colmask, DEFAULT_MAX_GAP);
+                    let fetched = plan.execute_async(&source).await?;
+                    tokio::task::spawn_blocking(move || {
+                        rowgroup_to_record_batch_from_fetched(&fetched, colmask, &index.rowgroups[rg], sc)
+                    })
+                    .await
+                    .map_err(|e| format_err!("decoding row group {} failed: {}", rg, e))?
+                }
+            })
+            .buffered(prefetch.max(1));
+        RecordBatchStream { schema, inner: Box::pin(inner) }
+    }
+
+    /// Open the file in `source` and stream its biggest tree. Parsing the
+    /// file's metadata runs on the blocking pool.
+    pub async fn open(source: Source, colmask: u64, prefetch: usize) -> Result<Self, Error> {
+        let meta = source.clone();
+        let index = tokio::task::spawn_blocking(move || RowGroupIndex::from_file(&RootFile::new(meta)?))
+            .await
+            .map_err(|e| format_err!("reading file metadata failed: {}", e))??;
+        Ok(Self::new(source, Arc::new(index), colmask, prefetch))
+    }
+
+    pub fn schema(&self) -> Arc<Schema> {
+        self.schema.clone()
+    }
+}
+
+impl Stream for RecordBatchStream {
+    type Item = Result<RecordBatch, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.inner.as_mut().poll_next(cx)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn stream_matches_sync_decoding() {
+        let path = "./src/test_data/foriter.root";
+        let data = bytes::Bytes::from(std::fs::read(path).unwrap());
+        let stream = RecordBatchStream::open(Source::from(Path::new(path)), 1, 3).await.unwrap();
+        let schema = stream.schema();
+        let batches: Vec<_> = stream.collect().await;
+
+        let index = RowGroupIndex::from_file(&RootFile::new(Path::new(path)).unwrap()).unwrap();
+        assert_eq!(batches.len(), index.rowgroups.len());
+        for (batch, rg) in batches.into_iter().zip(&index.rowgroups) {
+            let expected = crate::anyblox::rowgroup_to_record_batch(&data, 1, rg, schema.clone()).unwrap();
+            assert_eq!(batch.unwrap(), expected);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn errors_are_yielded() {
+        let data: &'static [u8] = std::fs::read("./src/test_data/foriter.root").unwrap().leak();
+        let index = RowGroupIndex::from_file(&RootFile::new(data).unwrap()).unwrap();
+        // the baskets are past the end of this source
+        let truncated = Source::from(&data[..200]);
+        let mut stream = RecordBatchStream::new(truncated, Arc::new(index), 1, 2);
+        assert!(stream.next().await.unwrap().is_err());
+        assert!(RecordBatchStream::open(Source::from(&data[..50]), 1, 2).await.is_err());
+    }
+}
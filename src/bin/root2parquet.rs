@@ -0,0 +1,84 @@
+//! Convert a tree of a ROOT file to Parquet.
+//!
+//! ```text
+//! root2parquet [options] <input.root> <output.parquet>
+//!   --tree <name[;cycle]>    tree to convert (default: the biggest)
+//!   --columns <i,j,..>       column ids to convert (default: all)
+//!   --compression <codec>    e.g. snappy, zstd(3), lz4_raw, gzip(6) (default: snappy)
+//!   --row-group-size <n>     max entries per Parquet row group (default: one per ROOT row group)
+//! ```
+
+#[cfg(not(target_arch = "wasm32"))]
+use std::{path::Path, process::ExitCode, str::FromStr};
+
+#[cfg(not(target_arch = "wasm32"))]
+use anyroot::{anyblox::ParquetExport, RootFile};
+#[cfg(not(target_arch = "wasm32"))]
+use parquet::basic::Compression;
+
+#[cfg(not(target_arch = "wasm32"))]
+const USAGE: &str = "usage: root2parquet [--tree <name>] [--columns <i,j,..>] [--compression <codec>] [--row-group-size <n>] <input.root> <output.parquet>";
+
+#[cfg(not(target_arch = "wasm32"))]
+fn parse_args(args: &[String]) -> Result<(ParquetExport, String, String), String> {
+    let mut export = ParquetExport::new();
+    let mut paths = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| format!("missing value for {}", arg));
+        match arg.as_str() {
+            "--tree" => export = export.with_tree(value()?),
+            "--columns" => {
+                let mask = value()?.split(',')
+                    .map(|id| id.trim().parse::<u32>().ok().filter(|id| *id < 64).ok_or_else(|| format!("invalid column id `{}`", id)))
+                    .try_fold(0u64, |mask, id| id.map(|id| mask | 1 << id))?;
+                export = export.with_columns(mask);
+            }
+            "--compression" => {
+                let codec = value()?;
+                export = export.with_compression(Compression::from_str(codec).map_err(|e| format!("invalid codec `{}`: {}", codec, e))?);
+            }
+            "--row-group-size" => {
+                let size = value()?;
+                export = export.with_row_group_size(size.parse().map_err(|_| format!("invalid row group size `{}`", size))?);
+            }
+            opt if opt.starts_with("--") => return Err(format!("unknown option {}", opt)),
+            path => paths.push(path.to_string()),
+        }
+    }
+    match <[String; 2]>::try_from(paths) {
+        Ok([input, output]) => Ok((export, input, output)),
+        Err(_) => Err("expected an input and an output file".to_string()),
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let (export, input, output) = match parse_args(&args) {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            eprintln!("{}\n{}", e, USAGE);
+            return ExitCode::from(2);
+        }
+    };
+    let result = RootFile::open(Path::new(&input))
+        .and_then(|file| {
+            let out = std::fs::File::create(&output)?;
+            export.write(&file, std::io::BufWriter::new(out))
+        });
+    match result {
+        Ok(entries) => {
+            println!("wrote {} entries to {}", entries, output);
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            eprintln!("converting {} failed: {}", input, e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+// dummy main for wasm
+#[cfg(target_arch = "wasm32")]
+fn main() {}
@@ -1,8 +1,39 @@
-use std::{path::Path};
-use anyroot::anyblox::*;
-use std::env;
- // number parsing
-use memmap::Mmap;
+//! Inspect ROOT files from the command line.
+//!
+//! ```text
+//! root_inspect <command> [options] <file.root>
+//!   ls          keys of the file, including the contents of directories
+//!   tree        branches of a tree with their types and entry counts
+//!   schema      Arrow schema of a tree
+//!   dump        decoded entries of a tree
+//!   rowgroups   row group layout of a tree
+//!   map         what every byte range of the file is used for
+//!   verify      read and check every key and basket of the file
+//! options:
+//!   --tree <path[;cycle]>       tree to inspect, e.g. `dir/events;2` (default: the biggest)
+//!   --columns <name,..>         columns to dump, in file order (default: all of a decodable type)
+//!   --entries <start..end>      entries to dump, either bound may be omitted (default: all)
+//!   --format <table|csv|json>   output format of dump (default: table)
+//! ```
+//!
+//! Exits with 0 on success, 1 if the file cannot be read, is damaged (for
+//! verify) or the tree or a column does not exist, and 2 for invalid
+//! arguments.
+
+#[cfg(not(target_arch = "wasm32"))]
+use std::{io::Write, ops::Range, path::Path, process::ExitCode, sync::Arc};
+
+#[cfg(not(target_arch = "wasm32"))]
+use anyroot::anyblox::{
+    branches_to_arrow_schema, export_schema, main_tree_item, rowgroup_to_record_batch_from_source, supported_columns,
+    to_native_endian, BasketLocation, RowGroupIndex, DEFAULT_MAX_GAP,
+};
+#[cfg(not(target_arch = "wasm32"))]
+use anyroot::{core::Region, FileItem, RootFile, Tid};
+#[cfg(not(target_arch = "wasm32"))]
+use arrow::record_batch::RecordBatch;
+#[cfg(not(target_arch = "wasm32"))]
+use failure::{format_err, Error};
 
 // ROOT file format
 // from https://github.com/root-project/root/blob/master/io/io/src/TFile.cxx
@@ -45,53 +76,323 @@ use memmap::Mmap;
 // ----->          | DATA      | Data bytes associated to the object
 
 #[cfg(not(target_arch = "wasm32"))]
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let args: Vec<String> = env::args().collect();
-    // RootFile::new("../b2hhh.zstd.root");
-    // if arg given, read file from given  name, otherwise default
-    let default_file = String::from("../cern.ch:84000.root");
-    let filename: &String = if args.len() <= 1 {&default_file} else {&args[1]};
-    let path = Path::new(filename);
-    let file = std::fs::File::open(path).unwrap();
-    let mmap = unsafe { Mmap::map(&file).unwrap() };
-
-    // print branch data itself
-    let mut state: Option<DecoderState> = None;
-    loop {
-        println!("pick some space-separated column ids to print or 'exit' to exit");
-        println!("> ");
-        // read name from stdin
-        let mut input = String::new();
-        std::io::stdin().read_line(&mut input)?;
-        if input.trim() == "exit" {
-            break;
+const USAGE: &str = "usage: root_inspect <ls|tree|schema|dump|rowgroups|map|verify> [--tree <path>] [--columns <name,..>] [--entries <start..end>] [--format <table|csv|json>] <file.root>";
+
+#[cfg(not(target_arch = "wasm32"))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Command {
+    Ls,
+    Tree,
+    Schema,
+    Dump,
+    RowGroups,
+    Map,
+    Verify,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Table,
+    Csv,
+    Json,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+#[derive(Debug)]
+struct Options {
+    command: Command,
+    path: String,
+    tree: Option<String>,
+    columns: Option<Vec<String>>,
+    entries: Option<Range<Tid>>,
+    format: Format,
+}
+
+/// Parse `start..end`, where either bound may be omitted
+#[cfg(not(target_arch = "wasm32"))]
+fn parse_entries(range: &str) -> Result<Range<Tid>, String> {
+    let invalid = || format!("invalid entry range `{}`, expected <start>..<end>", range);
+    let (start, end) = range.split_once("..").ok_or_else(invalid)?;
+    let start = if start.is_empty() { 0 } else { start.parse().map_err(|_| invalid())? };
+    let end = if end.is_empty() { Tid::MAX } else { end.parse().map_err(|_| invalid())? };
+    if start > end {
+        return Err(invalid());
+    }
+    Ok(start..end)
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn parse_args(args: &[String]) -> Result<Options, String> {
+    let mut args = args.iter();
+    let command = match args.next().map(String::as_str) {
+        Some("ls") => Command::Ls,
+        Some("tree") => Command::Tree,
+        Some("schema") => Command::Schema,
+        Some("dump") => Command::Dump,
+        Some("rowgroups") => Command::RowGroups,
+        Some("map") => Command::Map,
+        Some("verify") => Command::Verify,
+        Some(cmd) => return Err(format!("unknown command `{}`", cmd)),
+        None => return Err("missing command".to_string()),
+    };
+    let (mut tree, mut columns, mut entries, mut format) = (None, None, None, Format::Table);
+    let mut paths = Vec::new();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| format!("missing value for {}", arg));
+        match arg.as_str() {
+            "--tree" => tree = Some(value()?.clone()),
+            "--columns" => columns = Some(value()?.split(',').map(|name| name.trim().to_string()).collect()),
+            "--entries" => entries = Some(parse_entries(value()?)?),
+            "--format" => {
+                format = match value()?.as_str() {
+                    "table" => Format::Table,
+                    "csv" => Format::Csv,
+                    "json" => Format::Json,
+                    other => return Err(format!("unknown format `{}`", other)),
+                }
+            }
+            opt if opt.starts_with("--") => return Err(format!("unknown option {}", opt)),
+            path => paths.push(path.to_string()),
         }
+    }
+    let path = match <[String; 1]>::try_from(paths) {
+        Ok([path]) => path,
+        Err(_) => return Err("expected a single ROOT file".to_string()),
+    };
+    Ok(Options { command, path, tree, columns, entries, format })
+}
 
-        // split input into column  indices
-        let column_mask = input.trim()
-                               .split_whitespace()
-                               .map(|s| s.parse::<usize>().unwrap())
-                               .fold(0u64, |acc, x| acc | 1 << x);
-        println!("parsed column mask: {:b}", column_mask);
+/// The tree `spec` (a path, see `RootFile::get_path`) or the biggest tree of `file`
+#[cfg(not(target_arch = "wasm32"))]
+fn tree_item(file: &RootFile, spec: Option<&str>) -> Result<FileItem, Error> {
+    let item = match spec {
+        Some(spec) => file.get_path(spec)?,
+        None => main_tree_item(file).cloned().ok_or_else(|| format_err!("no TTree found in file"))?,
+    };
+    if item.root_class() != "TTree" {
+        return Err(format_err!("{} is not a TTree", item.name()));
+    }
+    Ok(item)
+}
 
-        println!("enter start tuple: " );
-        input.clear();
-        std::io::stdin().read_line(&mut input)?;
-        let start = input.trim().parse::<i32>().unwrap();
-        println!("parsed start: {}", start);
+/// `root_inspect ls`: one line per key, directories followed by their contents
+#[cfg(not(target_arch = "wasm32"))]
+fn print_keys(file: &RootFile, items: &[FileItem], prefix: &str, out: &mut dyn Write) -> Result<(), Error> {
+    for item in items {
+        let path = format!("{}{}", prefix, item.obj_name());
+        writeln!(out, "{:<16} {:<40} {:>12} {}", item.root_class(), format!("{};{}", path, item.cycle()), item.disk_size(), item.title())?;
+        if item.is_directory() {
+            print_keys(file, &file.directory_items(item)?, &format!("{}/", path), out)?;
+        }
+    }
+    Ok(())
+}
 
-        println!("enter count: " );
-        input.clear();
-        std::io::stdin().read_line(&mut input)?;
-        let count = input.trim().parse::<i32>().unwrap();
+/// `root_inspect tree`: entries and branches of the tree
+#[cfg(not(target_arch = "wasm32"))]
+fn print_tree(item: &FileItem, out: &mut dyn Write) -> Result<(), Error> {
+    let tree = item.as_tree()?;
+    let index = RowGroupIndex::from_tree(&tree)?;
+    let supported = supported_columns(&index.columns);
+    writeln!(out, "{};{} \"{}\": {} entries, {} branches, {} row groups",
+             item.obj_name(), item.cycle(), item.title(), tree.entries(), tree.main_branches().len(), index.rowgroups.len())?;
+    for (idx, (branch, (_, ty))) in tree.main_branches().iter().zip(&index.columns).enumerate() {
+        let note = if supported & 1u64.checked_shl(idx as u32).unwrap_or(0) == 0 { " (not decodable)" } else { "" };
+        writeln!(out, "{:>4} {:<24} {:<24} {:>10} entries {:>6} baskets{}",
+                 idx, branch.name(), ty, branch.entries(), branch.containers().len(), note)?;
+    }
+    Ok(())
+}
 
-        let batch = decode_batch_internal(&mmap, start, count, &mut state, column_mask);
-        println!("decoded batch: {:?}", batch);
-        println!("{} rows / {} cols: ", batch.num_rows(), batch.num_columns());
+/// `root_inspect schema`: Arrow fields and metadata of the decodable columns
+#[cfg(not(target_arch = "wasm32"))]
+fn print_schema(item: &FileItem, out: &mut dyn Write) -> Result<(), Error> {
+    let index = RowGroupIndex::from_tree(&item.as_tree()?)?;
+    let schema = export_schema(item, &index, supported_columns(&index.columns));
+    for field in schema.fields() {
+        writeln!(out, "{}: {}{}", field.name(), field.data_type(), if field.is_nullable() { "" } else { " not null" })?;
+    }
+    let mut meta = schema.metadata().iter().collect::<Vec<_>>();
+    meta.sort();
+    for (key, value) in meta {
+        writeln!(out, "-- {}: {}", key, value)?;
+    }
+    Ok(())
+}
+
+/// `root_inspect dump`: decode `columns` of the `entries` of the tree
+#[cfg(not(target_arch = "wasm32"))]
+fn dump(file: &RootFile, item: &FileItem, opts: &Options, out: &mut dyn Write) -> Result<(), Error> {
+    let index = RowGroupIndex::from_tree(&item.as_tree()?)?;
+    let colmask = index.colmask(opts.columns.as_deref())?;
+    let schema = Arc::new(branches_to_arrow_schema(&index.columns, colmask));
+    let mut batches = Vec::new();
+    for (rg, rows) in index.slices(opts.entries.clone().unwrap_or(0..Tid::MAX)) {
+        let batch = rowgroup_to_record_batch_from_source(file.source(), colmask, rg, schema.clone(), DEFAULT_MAX_GAP)?;
+        batches.push(to_native_endian(&batch.slice(rows.start, rows.len()))?);
+    }
+    match opts.format {
+        Format::Table => writeln!(out, "{}", arrow::util::pretty::pretty_format_batches(&batches)?)?,
+        Format::Csv => {
+            let mut writer = arrow::csv::Writer::new(out);
+            if batches.is_empty() {
+                // still print the header
+                writer.write(&RecordBatch::new_empty(schema))?;
+            }
+            for batch in &batches {
+                writer.write(batch)?;
+            }
+        }
+        Format::Json => {
+            let mut writer = arrow::json::ArrayWriter::new(out);
+            writer.write_batches(&batches.iter().collect::<Vec<_>>())?;
+            writer.finish()?;
+            writeln!(writer.into_inner())?;
+        }
+    }
+    Ok(())
+}
+
+/// `root_inspect rowgroups`: entries, baskets and bytes of every row group
+#[cfg(not(target_arch = "wasm32"))]
+fn print_rowgroups(item: &FileItem, out: &mut dyn Write) -> Result<(), Error> {
+    let index = RowGroupIndex::from_tree(&item.as_tree()?)?;
+    let mut total = 0u64;
+    for (idx, rg) in index.rowgroups.iter().enumerate() {
+        let baskets = rg.containers.iter().map(Vec::len).sum::<usize>();
+        let bytes = rg.containers.iter().flatten().map(|basket| match basket {
+            BasketLocation::OnDisk(_, len) => *len as u64,
+            BasketLocation::InMemory(buf) => buf.len() as u64,
+        }).sum::<u64>();
+        total += bytes;
+        writeln!(out, "{:>6} entries {:>10}..{:<10} {:>8} baskets {:>12} bytes", idx, rg.start_tid, rg.end_tid(), baskets, bytes)?;
+    }
+    writeln!(out, "{} row groups, {} entries, {} bytes", index.rowgroups.len(), index.tuples, total)?;
+    Ok(())
+}
+
+/// `root_inspect map`: print what every byte range of the file is
+/// used for, followed by a summary per kind of record
+#[cfg(not(target_arch = "wasm32"))]
+fn print_map(file: &RootFile, out: &mut dyn Write) -> Result<(), Error> {
+    let map = file.byte_map()?;
+    let (mut header, mut keys, mut baskets, mut free, mut unknown) = (0, 0, 0, 0, 0);
+    for entry in &map {
+        let what = match &entry.region {
+            Region::Header => { header += entry.len; "header".to_string() }
+            Region::Key { class, name, cycle } => { keys += entry.len; format!("{} {};{}", class, name, cycle) }
+            Region::Basket { branch } => { baskets += entry.len; format!("TBasket {}", branch) }
+            Region::Free => { free += entry.len; "free".to_string() }
+            Region::Unknown => { unknown += entry.len; "unknown".to_string() }
+        };
+        writeln!(out, "At:{:<12} N={:<10} {}", entry.start, entry.len, what)?;
+    }
+    let end = file.header().end();
+    writeln!(out, "total {} bytes: header {}, keys {}, baskets {}, free {} ({:.1}%), unknown {}",
+             end, header, keys, baskets, free, 100.0 * free as f64 / end.max(1) as f64, unknown)?;
+    for seg in file.free_segments()? {
+        writeln!(out, "free segment {}..={}", seg.first, seg.last)?;
+    }
+    Ok(())
+}
+
+/// `root_inspect verify`: check the whole file, see `RootFile::verify`
+#[cfg(not(target_arch = "wasm32"))]
+fn print_verify(file: &RootFile, out: &mut dyn Write) -> Result<(), Error> {
+    let report = file.verify()?;
+    writeln!(out, "ok: {} keys, {} trees, {} baskets, {} bytes ({} uncompressed)",
+             report.keys, report.trees, report.baskets, report.disk_bytes, report.uncompressed_bytes)?;
+    Ok(())
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn run(opts: &Options, out: &mut dyn Write) -> Result<(), Error> {
+    let file = RootFile::open(Path::new(&opts.path))?;
+    match opts.command {
+        Command::Ls => print_keys(&file, file.items(), "", out),
+        Command::Map => print_map(&file, out),
+        Command::Verify => print_verify(&file, out),
+        Command::Tree => print_tree(&tree_item(&file, opts.tree.as_deref())?, out),
+        Command::Schema => print_schema(&tree_item(&file, opts.tree.as_deref())?, out),
+        Command::Dump => dump(&file, &tree_item(&file, opts.tree.as_deref())?, opts, out),
+        Command::RowGroups => print_rowgroups(&tree_item(&file, opts.tree.as_deref())?, out),
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let opts = match parse_args(&args) {
+        Ok(opts) => opts,
+        Err(e) => {
+            eprintln!("{}\n{}", e, USAGE);
+            return ExitCode::from(2);
+        }
+    };
+    let stdout = std::io::stdout();
+    let mut out = std::io::BufWriter::new(stdout.lock());
+    match run(&opts, &mut out).and_then(|()| Ok(out.flush()?)) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{}: {}", opts.path, e);
+            ExitCode::FAILURE
+        }
     }
-    return Ok(());
 }
 
 // dummy main for wasm
 #[cfg(target_arch = "wasm32")]
 fn main() {}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use super::*;
+
+    fn inspect(args: &[&str]) -> Result<String, Error> {
+        let args = args.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        let opts = parse_args(&args).map_err(|e| format_err!("{}", e))?;
+        let mut out = Vec::new();
+        run(&opts, &mut out)?;
+        Ok(String::from_utf8(out).unwrap())
+    }
+
+    #[test]
+    fn arguments() {
+        let args = |args: &[&str]| parse_args(&args.iter().map(|s| s.to_string()).collect::<Vec<_>>());
+        let opts = args(&["dump", "--entries", "5..", "--format", "csv", "f.root"]).unwrap();
+        assert_eq!((opts.command, opts.entries, opts.format), (Command::Dump, Some(5..Tid::MAX), Format::Csv));
+        assert!(args(&[]).is_err());
+        assert!(args(&["cat", "f.root"]).is_err());
+        assert!(args(&["ls"]).is_err());
+        assert!(args(&["dump", "--entries", "9..3", "f.root"]).is_err());
+        assert!(args(&["dump", "--format", "xml", "f.root"]).is_err());
+    }
+
+    #[test]
+    fn keys_and_tree() {
+        let ls = inspect(&["ls", "./src/test_data/nesteddirs.root"]).unwrap();
+        assert!(ls.lines().any(|line| line.starts_with("TTree") && line.contains("one/two/tree;1")));
+        let tree = inspect(&["tree", "./src/test_data/Zmumu-lzma.root"]).unwrap();
+        assert!(tree.starts_with("events;1 \"Z -> mumu events\": 2304 entries"));
+        assert!(tree.lines().any(|line| line.contains("Type") && line.ends_with("(not decodable)")));
+        assert!(inspect(&["schema", "--tree", "nope", "./src/test_data/foriter.root"]).is_err());
+        assert!(inspect(&["ls", "./src/test_data/missing.root"]).is_err());
+        let verify = inspect(&["verify", "./src/test_data/nesteddirs.root"]).unwrap();
+        assert!(verify.starts_with("ok: 6 keys, 3 trees"), "{}", verify);
+    }
+
+    #[test]
+    fn dump_formats() {
+        let csv = inspect(&["dump", "--entries", "3..6", "--format", "csv", "./src/test_data/foriter.root"]).unwrap();
+        assert_eq!(csv, "data\n3\n4\n5\n");
+        let json = inspect(&["dump", "--entries", "44..", "--format", "json", "./src/test_data/foriter.root"]).unwrap();
+        assert_eq!(json, "[{\"data\":44},{\"data\":45}]\n");
+        let table = inspect(&["dump", "--columns", "px1,E2", "--entries", "..2", "./src/test_data/Zmumu-lzma.root"]).unwrap();
+        assert_eq!(table.lines().count(), 6);
+        assert!(inspect(&["dump", "--columns", "Type", "./src/test_data/Zmumu-lzma.root"]).is_err());
+        let rowgroups = inspect(&["rowgroups", "./src/test_data/foriter.root"]).unwrap();
+        assert_eq!(rowgroups.lines().count(), 9);
+        assert!(rowgroups.lines().last().unwrap().starts_with("8 row groups, 46 entries, "));
+    }
+}
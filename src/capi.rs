@@ -0,0 +1,388 @@
+//! `extern "C"` interface for embedding anyroot in C/C++ hosts.
+//!
+//! Files are opened from a path (memory mapped) or from a caller-owned
+//! buffer, trees are looked up by name, and decoded row groups are
+//! exported through the [Arrow C Data Interface](https://arrow.apache.org/docs/format/CDataInterface.html)
+//! as `ArrowArray`/`ArrowSchema` pairs. The matching header lives in
+//! `cpp/include/anyroot.h` and is generated with `cbindgen` (see `cbindgen.toml`).
+//! The C libraries are only built on request:
+//! `cargo rustc --release --lib --features capi --crate-type cdylib,staticlib`.
+//!
+//! Conventions:
+//! - functions returning a pointer return `NULL` on failure,
+//! - functions returning `int` return `0` on success and `-1` on failure,
+//! - after a failure, `anyroot_last_error` describes what went wrong.
+//!
+//! Unless the `slow_endian_parsing` feature is enabled, exported numeric
+//! buffers hold the big-endian values exactly as stored in the ROOT file;
+//! see `anyroot_native_endian`.
+
+use std::cell::RefCell;
+use std::ffi::{c_char, c_int, CStr, CString};
+use std::fs::File;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::ptr;
+use std::sync::Arc;
+
+use arrow::array::{Array, StructArray};
+use arrow::ffi::{to_ffi, FFI_ArrowArray, FFI_ArrowSchema};
+use bytes::Bytes;
+use failure::Error;
+use memmap2::Mmap;
+
+use crate::anyblox::{branches_to_arrow_schema, rowgroup_to_record_batch_shared, RowGroup};
+use crate::core::RootFile;
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn set_last_error(msg: String) {
+    let msg = CString::new(msg.replace('\0', " ")).unwrap_or_default();
+    LAST_ERROR.with(|e| *e.borrow_mut() = Some(msg));
+}
+
+/// Run `f`, turning both errors and panics into a recorded error message
+fn guarded<T, F>(f: F) -> Option<T>
+where
+    F: FnOnce() -> Result<T, Error>,
+{
+    match catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(v)) => Some(v),
+        Ok(Err(e)) => {
+            set_last_error(e.to_string());
+            None
+        }
+        Err(panic) => {
+            let msg = panic
+                .downcast_ref::<&str>()
+                .map(|s| s.to_string())
+                .or_else(|| panic.downcast_ref::<String>().cloned())
+                .unwrap_or_else(|| "unknown panic".to_string());
+            set_last_error(format!("anyroot panicked: {}", msg));
+            None
+        }
+    }
+}
+
+fn status(res: Option<()>) -> c_int {
+    match res {
+        Some(()) => 0,
+        None => -1,
+    }
+}
+
+/// Opaque handle to an open ROOT file
+pub struct AnyrootFile {
+    /// the file's bytes, shared with the trees and exported batches
+    data: Bytes,
+    file: RootFile,
+    tree_names: Vec<CString>,
+}
+
+/// Opaque handle to a `TTree` of an open file, including its row group layout
+pub struct AnyrootTree {
+    data: Bytes,
+    entries: i64,
+    rowgroups: Vec<RowGroup>,
+    branch_names: Vec<CString>,
+    branch_types: Vec<CString>,
+    columns: Vec<(String, String)>,
+}
+
+fn open(data: Bytes) -> Result<AnyrootFile, Error> {
+    let file = RootFile::new(data.clone())?;
+    let tree_names = file
+        .latest_items()
+        .into_iter()
+        .filter(|item| item.root_class() == "TTree")
+        .map(|item| CString::new(item.obj_name()))
+        .collect::<Result<_, _>>()?;
+    Ok(AnyrootFile { data, file, tree_names })
+}
+
+unsafe fn str_arg<'a>(s: *const c_char, what: &str) -> Result<&'a str, Error> {
+    if s.is_null() {
+        return Err(format_err!("{} must not be NULL", what));
+    }
+    Ok(CStr::from_ptr(s).to_str()?)
+}
+
+/// Message describing the last error on this thread, or `NULL` if there was none.
+/// The string stays valid until the next failing call on the same thread.
+#[no_mangle]
+pub extern "C" fn anyroot_last_error() -> *const c_char {
+    LAST_ERROR.with(|e| e.borrow().as_ref().map_or(ptr::null(), |s| s.as_ptr()))
+}
+
+/// Whether exported numeric buffers are in native byte order (see module docs)
+#[no_mangle]
+pub extern "C" fn anyroot_native_endian() -> bool {
+    cfg!(feature = "slow_endian_parsing")
+}
+
+/// Open and memory-map the ROOT file at `path`
+///
+/// # Safety
+/// `path` must be a valid, NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn anyroot_open_file(path: *const c_char) -> *mut AnyrootFile {
+    guarded(|| {
+        let path = str_arg(path, "path")?;
+        let file = File::open(path)?;
+        let mmap = Mmap::map(&file)?;
+        open(Bytes::from_owner(mmap))
+    })
+    .map_or(ptr::null_mut(), |f| Box::into_raw(Box::new(f)))
+}
+
+/// Open a ROOT file from an in-memory buffer without copying it
+///
+/// # Safety
+/// `data` must point to `len` readable bytes that stay valid and unchanged
+/// until the file, every tree opened from it and every array exported from
+/// these trees are released: uncompressed baskets are exported without
+/// copying them.
+#[no_mangle]
+pub unsafe extern "C" fn anyroot_open_buffer(data: *const u8, len: usize) -> *mut AnyrootFile {
+    guarded(|| {
+        if data.is_null() {
+            return Err(format_err!("data must not be NULL"));
+        }
+        let buf: &'static [u8] = std::slice::from_raw_parts(data, len);
+        open(Bytes::from_static(buf))
+    })
+    .map_or(ptr::null_mut(), |f| Box::into_raw(Box::new(f)))
+}
+
+/// Close a file opened with `anyroot_open_file` or `anyroot_open_buffer`
+///
+/// # Safety
+/// `file` must be `NULL` or a handle that was not closed yet.
+#[no_mangle]
+pub unsafe extern "C" fn anyroot_file_close(file: *mut AnyrootFile) {
+    if !file.is_null() {
+        drop(Box::from_raw(file));
+    }
+}
+
+/// Number of distinct `TTree`s in the file
+///
+/// # Safety
+/// `file` must be a valid file handle.
+#[no_mangle]
+pub unsafe extern "C" fn anyroot_file_tree_count(file: *const AnyrootFile) -> usize {
+    (&*file).tree_names.len()
+}
+
+/// Name of the `idx`-th tree, or `NULL` if out of range. Owned by the file handle.
+///
+/// # Safety
+/// `file` must be a valid file handle.
+#[no_mangle]
+pub unsafe extern "C" fn anyroot_file_tree_name(file: *const AnyrootFile, idx: usize) -> *const c_char {
+    (&*file).tree_names.get(idx).map_or(ptr::null(), |s| s.as_ptr())
+}
+
+/// Parse the tree called `name` and discover its row groups. `name` refers
+/// to the highest cycle of the tree, `name;N` to cycle `N`.
+///
+/// # Safety
+/// `file` must be a valid file handle and `name` a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn anyroot_tree_open(file: *const AnyrootFile, name: *const c_char) -> *mut AnyrootTree {
+    guarded(|| {
+        let file = &*file;
+        let name = str_arg(name, "name")?;
+        let item = file.file.get(name)?;
+        if item.root_class() != "TTree" {
+            return Err(format_err!("`{}` is a `{}`, not a TTree", name, item.root_class()));
+        }
+        let tree = item.as_tree()?;
+        let columns = tree.main_branch_names_and_types();
+        let cstrings = |f: fn(&(String, String)) -> &str| {
+            columns.iter().map(|c| CString::new(f(c))).collect::<Result<Vec<_>, _>>()
+        };
+        Ok(AnyrootTree {
+            data: file.data.clone(),
+            entries: tree.entries(),
+            rowgroups: RowGroup::find_rowgroups(&tree)?,
+            branch_names: cstrings(|c| c.0.as_str())?,
+            branch_types: cstrings(|c| c.1.as_str())?,
+            columns,
+        })
+    })
+    .map_or(ptr::null_mut(), |t| Box::into_raw(Box::new(t)))
+}
+
+/// Free a tree opened with `anyroot_tree_open`
+///
+/// # Safety
+/// `tree` must be `NULL` or a handle that was not freed yet.
+#[no_mangle]
+pub unsafe extern "C" fn anyroot_tree_free(tree: *mut AnyrootTree) {
+    if !tree.is_null() {
+        drop(Box::from_raw(tree));
+    }
+}
+
+/// Number of entries (rows) in the tree
+///
+/// # Safety
+/// `tree` must be a valid tree handle.
+#[no_mangle]
+pub unsafe extern "C" fn anyroot_tree_entries(tree: *const AnyrootTree) -> i64 {
+    (*tree).entries
+}
+
+/// Number of top-level branches (columns) in the tree
+///
+/// # Safety
+/// `tree` must be a valid tree handle.
+#[no_mangle]
+pub unsafe extern "C" fn anyroot_tree_branch_count(tree: *const AnyrootTree) -> usize {
+    (&*tree).branch_names.len()
+}
+
+/// Name of the `idx`-th branch, or `NULL` if out of range. Owned by the tree handle.
+///
+/// # Safety
+/// `tree` must be a valid tree handle.
+#[no_mangle]
+pub unsafe extern "C" fn anyroot_tree_branch_name(tree: *const AnyrootTree, idx: usize) -> *const c_char {
+    (&*tree).branch_names.get(idx).map_or(ptr::null(), |s| s.as_ptr())
+}
+
+/// Element type (e.g. `f64`) of the `idx`-th branch, or `NULL` if out of range
+///
+/// # Safety
+/// `tree` must be a valid tree handle.
+#[no_mangle]
+pub unsafe extern "C" fn anyroot_tree_branch_type(tree: *const AnyrootTree, idx: usize) -> *const c_char {
+    (&*tree).branch_types.get(idx).map_or(ptr::null(), |s| s.as_ptr())
+}
+
+/// Number of row groups (aligned basket clusters) in the tree
+///
+/// # Safety
+/// `tree` must be a valid tree handle.
+#[no_mangle]
+pub unsafe extern "C" fn anyroot_tree_rowgroup_count(tree: *const AnyrootTree) -> usize {
+    (&*tree).rowgroups.len()
+}
+
+/// First entry and entry count of the `idx`-th row group
+///
+/// # Safety
+/// `tree` must be a valid tree handle; `start` and `count` must be writable.
+#[no_mangle]
+pub unsafe extern "C" fn anyroot_tree_rowgroup_range(
+    tree: *const AnyrootTree,
+    idx: usize,
+    start: *mut i64,
+    count: *mut i64,
+) -> c_int {
+    status(guarded(|| {
+        let rg = (&*tree)
+            .rowgroups
+            .get(idx)
+            .ok_or_else(|| format_err!("row group {} out of range", idx))?;
+        *start = rg.start_tid as i64;
+        *count = rg.count as i64;
+        Ok(())
+    }))
+}
+
+/// Export the Arrow schema of the columns selected by `colmask` (bit `i` selects branch `i`)
+///
+/// # Safety
+/// `tree` must be a valid tree handle and `out` point to writable, uninitialized
+/// `ArrowSchema` storage. The caller must release the schema.
+#[no_mangle]
+pub unsafe extern "C" fn anyroot_tree_schema(
+    tree: *const AnyrootTree,
+    colmask: u64,
+    out: *mut FFI_ArrowSchema,
+) -> c_int {
+    status(guarded(|| {
+        let schema = branches_to_arrow_schema(&(*tree).columns, colmask);
+        ptr::write(out, FFI_ArrowSchema::try_from(&schema)?);
+        Ok(())
+    }))
+}
+
+/// Decode the `idx`-th row group into a struct array with one child per selected column
+///
+/// # Safety
+/// `tree` must be a valid tree handle, `out_array` and `out_schema` must point
+/// to writable, uninitialized `ArrowArray`/`ArrowSchema` storage. The caller
+/// must release both.
+#[no_mangle]
+pub unsafe extern "C" fn anyroot_tree_read_rowgroup(
+    tree: *const AnyrootTree,
+    idx: usize,
+    colmask: u64,
+    out_array: *mut FFI_ArrowArray,
+    out_schema: *mut FFI_ArrowSchema,
+) -> c_int {
+    status(guarded(|| {
+        let tree = &*tree;
+        let rg = tree
+            .rowgroups
+            .get(idx)
+            .ok_or_else(|| format_err!("row group {} out of range", idx))?;
+        let schema = Arc::new(branches_to_arrow_schema(&tree.columns, colmask));
+        let batch = rowgroup_to_record_batch_shared(&tree.data, colmask, rg, schema)?;
+        let (array, schema) = to_ffi(&StructArray::from(batch).to_data())?;
+        ptr::write(out_array, array);
+        ptr::write(out_schema, schema);
+        Ok(())
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow::array::{make_array, Array};
+    use arrow::ffi::from_ffi;
+    use std::mem::MaybeUninit;
+
+    #[test]
+    fn export_rowgroups() {
+        let path = CString::new("./src/test_data/foriter.root").unwrap();
+        unsafe {
+            let file = anyroot_open_file(path.as_ptr());
+            assert!(!file.is_null());
+            assert_eq!(anyroot_file_tree_count(file), 1);
+            let name = anyroot_file_tree_name(file, 0);
+            let tree = anyroot_tree_open(file, name);
+            assert!(!tree.is_null());
+            // trees keep the file data alive on their own
+            anyroot_file_close(file);
+
+            let branches = anyroot_tree_branch_count(tree);
+            let mut rows = 0;
+            for rg in 0..anyroot_tree_rowgroup_count(tree) {
+                let mut array = MaybeUninit::<FFI_ArrowArray>::uninit();
+                let mut schema = MaybeUninit::<FFI_ArrowSchema>::uninit();
+                let colmask = (1u64 << branches) - 1;
+                assert_eq!(anyroot_tree_read_rowgroup(tree, rg, colmask, array.as_mut_ptr(), schema.as_mut_ptr()), 0);
+                let data = from_ffi(array.assume_init(), &schema.assume_init()).unwrap();
+                let array = make_array(data);
+                assert_eq!(array.as_any().downcast_ref::<StructArray>().unwrap().num_columns(), branches);
+                rows += array.len() as i64;
+            }
+            assert_eq!(rows, anyroot_tree_entries(tree));
+            anyroot_tree_free(tree);
+        }
+    }
+
+    #[test]
+    fn reports_errors() {
+        let path = CString::new("./src/test_data/does-not-exist.root").unwrap();
+        unsafe {
+            assert!(anyroot_open_file(path.as_ptr()).is_null());
+            assert!(!anyroot_last_error().is_null());
+        }
+    }
+}
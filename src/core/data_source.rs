@@ -1,47 +1,174 @@
+use std::fmt;
 use std::fs::File;
-use std::io::{Read, Seek, SeekFrom};
 #[cfg(not(target_arch = "wasm32"))]
 use std::path::Path;
 use std::path::PathBuf;
+use std::sync::{Arc, OnceLock};
 
+use bytes::Bytes;
 use failure::Error;
 
-/// The source from where the Root file is read. Construct it using
-/// `.into()` on a `Url` or `Path`. The latter is not availible for
-/// the `wasm32` target.
-#[derive(Debug, Clone)]
-pub struct Source(SourceInner);
+/// Storage backend a ROOT file can be read from. Implement this to read
+/// files from places other than the local file system or memory.
+pub trait DataSource: fmt::Debug + Send + Sync {
+    /// Read `len` bytes starting at `start`. Reads past the end of the
+    /// data are an error.
+    fn fetch(&self, start: u64, len: u64) -> Result<Bytes, Error>;
 
-#[derive(Debug, Clone)]
-enum SourceInner {
-    /// A local source, i.e. a file on disc.
-    Local(PathBuf),
-    /// An in-memory (e.g., mmaped) region of data
-    InMem(&'static [u8]),
+    /// Total size of the data in bytes
+    fn len(&self) -> Result<u64, Error>;
+
+    fn is_empty(&self) -> Result<bool, Error> {
+        Ok(self.len()? == 0)
+    }
+
+    /// Hint that the given range will be read soon. Backends which can
+    /// read ahead (e.g. memory mappings) may start doing so.
+    fn prefetch(&self, _start: u64, _len: u64) {}
 }
 
+/// The source from where the Root file is read. Construct it using
+/// `.into()` on a `Path`, a buffer, or from any `DataSource` with
+/// `Source::with_backend`. Local files are not availible for the
+/// `wasm32` target. Cloning a `Source` is cheap and shares the backend.
+#[derive(Debug, Clone)]
+pub struct Source(Arc<dyn DataSource>);
+
 impl Source {
     pub fn new<T: Into<Self>>(thing: T) -> Self {
         thing.into()
     }
 
-    pub fn fetch(&self, start: u64, len: u64) -> Result<Vec<u8>, Error> {
-        match &self.0 {
-            SourceInner::Local(path) => {
-                let mut f = File::open(path)?;
-                f.seek(SeekFrom::Start(start))?;
-                let mut buf = vec![0; len as usize];
-                f.read_exact(&mut buf)?;
-                Ok(buf)
-            }
-            SourceInner::InMem(ref data) =>  {
-                // TODO copies stuff
-                Ok(data[(start as usize)..((start+len) as usize)].to_vec())
+    /// Read from a custom storage backend
+    pub fn with_backend<D: DataSource + 'static>(backend: D) -> Self {
+        Self(Arc::new(backend))
+    }
+
+    pub fn fetch(&self, start: u64, len: u64) -> Result<Bytes, Error> {
+        self.0.fetch(start, len)
+    }
+
+    /// Total size of the underlying data in bytes
+    pub fn len(&self) -> Result<u64, Error> {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> Result<bool, Error> {
+        self.0.is_empty()
+    }
+
+    /// Hint that the given range will be read soon
+    pub fn prefetch(&self, start: u64, len: u64) {
+        self.0.prefetch(start, len)
+    }
+
+    /// `fetch` on tokio's blocking thread pool
+    #[cfg(all(feature = "async", not(target_arch = "wasm32")))]
+    pub async fn fetch_async(&self, start: u64, len: u64) -> Result<Bytes, Error> {
+        let source = self.clone();
+        tokio::task::spawn_blocking(move || source.fetch(start, len))
+            .await
+            .map_err(|e| format_err!("fetch of {} bytes at {} failed: {}", len, start, e))?
+    }
+}
+
+/// A file on disc. The file is opened on the first read and the handle
+/// is kept for positional reads, so clones of a `Source` can read
+/// concurrently without seeking.
+pub struct LocalFile {
+    path: PathBuf,
+    file: OnceLock<File>,
+}
+
+impl LocalFile {
+    /// Open `path` right away rather than on the first read
+    pub fn open<P: Into<PathBuf>>(path: P) -> Result<Self, Error> {
+        let path = path.into();
+        let file = File::open(&path)?;
+        Ok(LocalFile { path, file: OnceLock::from(file) })
+    }
+
+    fn file(&self) -> Result<&File, Error> {
+        if let Some(file) = self.file.get() {
+            return Ok(file);
+        }
+        let file = File::open(&self.path)?;
+        Ok(self.file.get_or_init(|| file))
+    }
+}
+
+impl fmt::Debug for LocalFile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("Local").field(&self.path).finish()
+    }
+}
+
+#[cfg(unix)]
+fn read_exact_at(file: &File, buf: &mut [u8], offset: u64) -> std::io::Result<()> {
+    std::os::unix::fs::FileExt::read_exact_at(file, buf, offset)
+}
+
+#[cfg(windows)]
+fn read_exact_at(file: &File, mut buf: &mut [u8], mut offset: u64) -> std::io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !buf.is_empty() {
+        match file.seek_read(buf, offset)? {
+            0 => return Err(std::io::ErrorKind::UnexpectedEof.into()),
+            n => {
+                buf = &mut buf[n..];
+                offset += n as u64;
             }
         }
     }
+    Ok(())
 }
 
+#[cfg(not(any(unix, windows)))]
+fn read_exact_at(_file: &File, _buf: &mut [u8], _offset: u64) -> std::io::Result<()> {
+    Err(std::io::ErrorKind::Unsupported.into())
+}
+
+impl DataSource for LocalFile {
+    fn fetch(&self, start: u64, len: u64) -> Result<Bytes, Error> {
+        // check the range before allocating what a damaged header claims
+        let file_len = self.len()?;
+        if start.checked_add(len).is_none_or(|end| end > file_len) {
+            return Err(format_err!("Read of {} bytes at {} is out of bounds ({} bytes)", len, start, file_len));
+        }
+        let mut buf = vec![0; len as usize];
+        read_exact_at(self.file()?, &mut buf, start)?;
+        Ok(buf.into())
+    }
+
+    fn len(&self) -> Result<u64, Error> {
+        Ok(self.file()?.metadata()?.len())
+    }
+}
+
+/// An in-memory (e.g., mmaped) region of data. Reads are slices of the
+/// same buffer and do not copy.
+struct InMem(Bytes);
+
+impl fmt::Debug for InMem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "InMem({} bytes)", self.0.len())
+    }
+}
+
+impl DataSource for InMem {
+    fn fetch(&self, start: u64, len: u64) -> Result<Bytes, Error> {
+        let data = &self.0;
+        start
+            .checked_add(len)
+            .filter(|end| *end <= data.len() as u64)
+            .map(|end| data.slice(start as usize..end as usize))
+            .ok_or_else(|| format_err!("Read of {} bytes at {} is out of bounds ({} bytes)", len, start, data.len()))
+    }
+
+    fn len(&self) -> Result<u64, Error> {
+        Ok(self.0.len() as u64)
+    }
+}
 
 // Disallow the construction of a local source object on wasm since
 // wasm does not have a (proper) file system.
@@ -55,13 +182,80 @@ impl From<&Path> for Source {
 #[cfg(not(target_arch = "wasm32"))]
 impl From<PathBuf> for Source {
     fn from(path_buf: PathBuf) -> Self {
-        Self(SourceInner::Local(path_buf))
+        Self::with_backend(LocalFile { path: path_buf, file: OnceLock::new() })
     }
 }
 
 // allow construction from slices
 impl From<&'static [u8]> for Source {
     fn from(buf: &'static [u8]) -> Self {
-        Self(SourceInner::InMem(buf))
+        Bytes::from_static(buf).into()
+    }
+}
+
+impl From<Bytes> for Source {
+    fn from(buf: Bytes) -> Self {
+        Self::with_backend(InMem(buf))
+    }
+}
+
+impl From<Vec<u8>> for Source {
+    fn from(buf: Vec<u8>) -> Self {
+        Bytes::from(buf).into()
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use super::*;
+    use crate::core::RootFile;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Backend counting the reads it serves
+    #[derive(Debug)]
+    struct Counting(Bytes, Arc<AtomicUsize>);
+
+    impl DataSource for Counting {
+        fn fetch(&self, start: u64, len: u64) -> Result<Bytes, Error> {
+            self.1.fetch_add(1, Ordering::Relaxed);
+            Ok(self.0.slice(start as usize..(start + len) as usize))
+        }
+
+        fn len(&self) -> Result<u64, Error> {
+            Ok(self.0.len() as u64)
+        }
+    }
+
+    #[test]
+    fn custom_backend() {
+        let data = Bytes::from(std::fs::read("./src/test_data/foriter.root").unwrap());
+        let reads = Arc::new(AtomicUsize::new(0));
+        let file = RootFile::new(Source::with_backend(Counting(data, reads.clone()))).unwrap();
+        assert!(reads.load(Ordering::Relaxed) > 0);
+        assert_eq!(file.items()[0].as_tree().unwrap().entries(), 46);
+    }
+
+    #[test]
+    fn in_memory_reads_do_not_copy() {
+        let data: &'static [u8] = std::fs::read("./src/test_data/foriter.root").unwrap().leak();
+        let source = Source::from(data);
+        let buf = source.fetch(100, 20).unwrap();
+        assert_eq!(buf.as_ptr(), data[100..].as_ptr());
+        assert!(source.fetch(data.len() as u64 - 10, 20).is_err());
+        assert!(source.fetch(u64::MAX, 2).is_err());
+    }
+
+    #[test]
+    fn local_file() {
+        let path = "./src/test_data/foriter.root";
+        let data = std::fs::read(path).unwrap();
+        for source in [Source::from(Path::new(path)), Source::with_backend(LocalFile::open(path).unwrap())] {
+            assert_eq!(source.len().unwrap(), data.len() as u64);
+            assert_eq!(&source.fetch(996, 435).unwrap()[..], &data[996..996 + 435]);
+            assert!(source.fetch(data.len() as u64 - 1, 2).is_err());
+            assert!(source.fetch(0, u64::MAX).is_err());
+            assert!(source.fetch(u64::MAX, 2).is_err());
+        }
+        assert!(LocalFile::open("./src/test_data/missing.root").is_err());
     }
 }
@@ -14,7 +14,9 @@ use uuid::Uuid;
 use crate::{
     code_gen::rust::{ToNamedRustParser, ToRustStruct},
     core::tstreamer::streamers,
+    core::verify,
     core::*,
+    tree_reader::Container,
     MAP_OFFSET,
 };
 
@@ -30,11 +32,14 @@ const TDIRECTORY_MAX_SIZE: u64 = 42;
 pub struct RootFile {
     source: Source,
     hdr: FileHeader,
+    /// `None` for a file recovered without a readable top level directory
+    dir: Option<Directory>,
     items: Vec<FileItem>,
 }
 
+/// Header at the very beginning of a ROOT file (the `TFile` record)
 #[derive(Debug, PartialEq)]
-struct FileHeader {
+pub struct FileHeader {
     version: i32,
     begin: i32,
     end: u64,
@@ -50,6 +55,120 @@ struct FileHeader {
     seek_dir: SeekPointer,
 }
 
+/// Version of ROOT which wrote a file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct RootVersion {
+    pub major: u32,
+    pub minor: u32,
+    pub patch: u32,
+}
+
+impl fmt::Display for RootVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{:02}/{:02}", self.major, self.minor, self.patch)
+    }
+}
+
+/// Compression algorithm of a file, from `fCompress / 100`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompressionAlgorithm {
+    /// `kUseGlobal`; files written this way use ZLIB
+    Global,
+    Zlib,
+    Lzma,
+    /// ROOT's legacy algorithm ("CS" blocks)
+    Old,
+    Lz4,
+    Zstd,
+    Unknown(u32),
+}
+
+/// Compression setting of a file, decoded from `fCompress`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Compression {
+    pub algorithm: CompressionAlgorithm,
+    /// 0 means uncompressed, 1 to 9 are increasingly aggressive
+    pub level: u32,
+}
+
+impl Compression {
+    fn from_setting(setting: i32) -> Self {
+        let setting = setting.max(0) as u32;
+        let algorithm = match setting / 100 {
+            0 => CompressionAlgorithm::Global,
+            1 => CompressionAlgorithm::Zlib,
+            2 => CompressionAlgorithm::Lzma,
+            3 => CompressionAlgorithm::Old,
+            4 => CompressionAlgorithm::Lz4,
+            5 => CompressionAlgorithm::Zstd,
+            n => CompressionAlgorithm::Unknown(n),
+        };
+        Compression { algorithm, level: setting % 100 }
+    }
+}
+
+impl FileHeader {
+    /// Raw `fVersion`; 1000000 is added for files using 64 bit pointers
+    pub fn raw_version(&self) -> i32 {
+        self.version
+    }
+
+    /// Version of ROOT which wrote this file
+    pub fn root_version(&self) -> RootVersion {
+        let v = (self.version % 1000000) as u32;
+        RootVersion { major: v / 10000, minor: v / 100 % 100, patch: v % 100 }
+    }
+
+    /// Whether the file uses 64 bit pointers (i.e. is larger than 2GB)
+    pub fn is_large_file(&self) -> bool {
+        self.version > 1000000
+    }
+
+    /// Offset of the first record (`fBEGIN`)
+    pub fn begin(&self) -> u64 {
+        self.begin as u64
+    }
+
+    /// Offset of the first free byte at the end of the file (`fEND`)
+    pub fn end(&self) -> u64 {
+        self.end
+    }
+
+    /// Offset and size of the record listing free segments
+    pub fn seek_free(&self) -> (u64, i32) {
+        (self.seek_free, self.nbytes_free)
+    }
+
+    /// Number of free segments
+    pub fn n_entries_free(&self) -> i32 {
+        self.n_entries_free
+    }
+
+    /// Raw `fCompress` setting
+    pub fn raw_compression(&self) -> i32 {
+        self.compression
+    }
+
+    pub fn compression(&self) -> Compression {
+        Compression::from_setting(self.compression)
+    }
+
+    /// Offset and size of the `TStreamerInfo` record
+    pub fn seek_info(&self) -> (u64, i32) {
+        (self.seek_info, self.nbytes_info)
+    }
+
+    pub fn uuid(&self) -> Uuid {
+        self.uuid
+    }
+
+    /// Offset of the top level directory data, following the file name and title
+    pub fn seek_dir(&self) -> u64 {
+        self.seek_dir
+    }
+}
+
+/// A `TDirectory` record
 #[derive(Debug, PartialEq)]
 pub struct Directory {
     version: i16,
@@ -62,6 +181,37 @@ pub struct Directory {
     seek_keys: SeekPointer,
 }
 
+impl Directory {
+    pub fn version(&self) -> i16 {
+        self.version
+    }
+
+    /// Time the directory was created
+    pub fn created(&self) -> Datime {
+        Datime::from_raw(self.c_time)
+    }
+
+    /// Time the directory was last modified
+    pub fn modified(&self) -> Datime {
+        Datime::from_raw(self.m_time)
+    }
+
+    /// Offset of the key of this directory
+    pub fn seek_dir(&self) -> u64 {
+        self.seek_dir
+    }
+
+    /// Offset of the parent directory; 0 for the top level directory
+    pub fn seek_parent(&self) -> u64 {
+        self.seek_parent
+    }
+
+    /// Offset and size of the list of keys in this directory
+    pub fn seek_keys(&self) -> (u64, i32) {
+        (self.seek_keys, self.n_bytes_keys)
+    }
+}
+
 /// Parse opening part of a root file
 fn file_header(i: &[u8]) -> IResult<&[u8], FileHeader> {
     fn version_dep_int(i: &[u8], is_64_bit: bool) -> IResult<&[u8], u64> {
@@ -144,40 +294,207 @@ fn directory(input: &[u8]) -> nom::IResult<&[u8], Directory> {
     ))
 }
 
+/// The item `name` (highest cycle) or `name;N` among `items`
+fn find_item<'a>(items: &'a [FileItem], spec: &str) -> Result<&'a FileItem, Error> {
+    let (name, cycle) = match spec.rsplit_once(';') {
+        Some((name, cycle)) => {
+            let cycle = cycle
+                .parse::<i16>()
+                .map_err(|_| format_err!("Invalid cycle in `{}`", spec))?;
+            (name, Some(cycle))
+        }
+        None => (spec, None),
+    };
+    let candidates = items.iter().filter(|item| item.obj_name() == name);
+    match cycle {
+        Some(cycle) => candidates.into_iter().find(|item| item.cycle() == cycle),
+        None => candidates.max_by_key(|item| item.cycle()),
+    }
+    .ok_or_else(|| format_err!("No object `{}` in file", spec))
+}
+
+/// Outcome of `RootFile::recover`
+#[derive(Debug, Default)]
+pub struct RecoveryReport {
+    /// Number of top-level keys (other than baskets) found by scanning the file
+    pub recovered_keys: usize,
+    /// Latest cycle of each recovered tree as (name, cycle, entries)
+    pub trees: Vec<(String, i16, i64)>,
+    /// Trees whose key was found, but whose object could not be parsed
+    pub unreadable_trees: Vec<(String, i16)>,
+    /// Number of baskets found in the file
+    pub baskets: usize,
+    /// Offsets of baskets not referenced by any recovered tree. They
+    /// were written after the last autosave of their tree and are lost.
+    pub orphaned_baskets: Vec<SeekPointer>,
+    /// Bytes in gaps (deleted records) between keys
+    pub free_bytes: u64,
+    /// Offset at which scanning stopped
+    pub end_of_data: u64,
+    /// Bytes after `end_of_data` which do not belong to a complete key
+    pub lost_bytes: u64,
+}
+
+/// Parse the key header of the record at `pos`, checking it is consistent with its position
+pub(crate) fn scan_key(source: &Source, pos: u64, nbytes: u64) -> Option<TKeyHeader> {
+    // Nbytes, Version, ObjLen and Datime are followed by KeyLen
+    let buf = source.fetch(pos, 16.min(nbytes)).ok()?;
+    let (_, key_len) = be_i16::<_, nom::error::Error<_>>(buf.get(14..)?).ok()?;
+    if key_len <= 0 || key_len as u64 > nbytes {
+        return None;
+    }
+    let buf = source.fetch(pos, key_len as u64).ok()?;
+    let (_, hdr) = tkey_header(&buf).ok()?;
+    if hdr.seek_key != pos || hdr.total_size as u64 != nbytes {
+        return None;
+    }
+    Some(hdr)
+}
+
 impl RootFile {
-    /// Open a new ROOT file either from a `Url`
-    /// (not available on `wasm32`).
-    pub fn new<S: Into<Source>>(source: S) -> Result<Self, Error> {
-        let source = source.into();
-        let hdr = source.fetch(0, FILE_HEADER_SIZE).and_then(|buf| {
+    fn read_header(source: &Source) -> Result<FileHeader, Error> {
+        source.fetch(0, FILE_HEADER_SIZE).and_then(|buf| {
             file_header(&buf)
                 .map_err(|_| format_err!("Failed to parse file header"))
-                .map(|(_i, o)| o)})?;
-        // Jump to the TDirectory and parse it
-        let dir = source
+                .map(|(_i, o)| o)})
+    }
+
+    fn read_directory(source: &Source, hdr: &FileHeader) -> Result<Directory, Error> {
+        source
             .fetch(hdr.seek_dir, TDIRECTORY_MAX_SIZE)
             .and_then(|buf| {
                 directory(&buf)
                     .map_err(|_| format_err!("Failed to parse TDirectory"))
                     .map(|(_i, o)| o)
-            })?;
+            })
+    }
+
+    /// Open a new ROOT file either from a `Url`
+    /// (not available on `wasm32`).
+    pub fn new<S: Into<Source>>(source: S) -> Result<Self, Error> {
+        let source = source.into();
+        let hdr = Self::read_header(&source)?;
+        // Jump to the TDirectory and parse it
+        let dir = Self::read_directory(&source, &hdr)?;
+        let items = Self::read_keys(&source, &dir)?;
+        Ok(RootFile { source, hdr, dir: Some(dir), items })
+    }
+
+    /// Items of the key list of `dir`
+    fn read_keys(source: &Source, dir: &Directory) -> Result<Vec<FileItem>, Error> {
         let tkey_of_keys = source
             .fetch(dir.seek_keys, dir.n_bytes_keys as u64)
-            .and_then(|buf| {
-                tkey(&buf)
-                    .map_err(|_| format_err!("Failed to parse TKeys"))
-                    .map(|(_i, o)| o)
-            })?;
+            .and_then(|buf| tkey(&buf).map_err(|e| format_err!("Failed to parse TKeys: {}", e)))?;
         let keys = match tkey_headers(&tkey_of_keys.obj) {
             Ok((_, hdrs)) => Ok(hdrs),
             _ => Err(format_err!("Expected TKeyHeaders")),
         }?;
-        let items = keys
+        Ok(keys
             .iter()
             .map(|k_hdr| FileItem::new(k_hdr, source.clone()))
-            .collect();
+            .collect())
+    }
 
-        Ok(RootFile { source, hdr, items })
+    /// Open the file at `path`. With the `mmap` feature on Linux, the file
+    /// is memory mapped, otherwise it is read with positional reads.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn open<P: AsRef<std::path::Path>>(path: P) -> Result<Self, Error> {
+        #[cfg(all(feature = "mmap", target_os = "linux"))]
+        let source = Source::with_backend(MmapSource::open(path)?);
+        #[cfg(not(all(feature = "mmap", target_os = "linux")))]
+        let source = Source::with_backend(LocalFile::open(path.as_ref())?);
+        Self::new(source)
+    }
+
+    /// Open a file whose key list is missing or unreadable, e.g. because
+    /// the job writing it crashed. Instead of trusting the directory, the
+    /// file is scanned linearly from `fBEGIN` for valid keys, the same way
+    /// `TFile::Recover` does. Scanning stops at the first record that is not
+    /// a consistent key; everything after it is reported as lost. The top
+    /// level directory is not needed either.
+    pub fn recover<S: Into<Source>>(source: S) -> Result<(Self, RecoveryReport), Error> {
+        let source = source.into();
+        let hdr = Self::read_header(&source)?;
+        let dir = match Self::read_directory(&source, &hdr) {
+            Ok(dir) => Some(dir),
+            Err(e) => {
+                warn!("unreadable top level directory ({}), recovering without it", e);
+                None
+            }
+        };
+        let size = source.len()?;
+        let mut report = RecoveryReport::default();
+        let mut keys = Vec::new();
+        let mut baskets = Vec::new();
+        let mut pos = hdr.begin as u64;
+        while pos + 4 <= size {
+            let buf = source.fetch(pos, 4)?;
+            let nbytes = i32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]);
+            if nbytes < 0 {
+                // gap left by a deleted object
+                report.free_bytes += nbytes.unsigned_abs() as u64;
+                pos += nbytes.unsigned_abs() as u64;
+                continue;
+            }
+            if nbytes == 0 || pos + nbytes as u64 > size {
+                break;
+            }
+            let key = match scan_key(&source, pos, nbytes as u64) {
+                Some(key) => key,
+                None => {
+                    warn!("no valid key at offset {}, stopping recovery", pos);
+                    break;
+                }
+            };
+            match (key.class_name.as_str(), key.obj_name.as_str()) {
+                ("TBasket", _) => baskets.push(pos),
+                // the file's own record, its key list and free segments
+                ("TFile", _) | ("TList", "StreamerInfo") => {}
+                _ if key.seek_pdir == hdr.begin as u64 => keys.push(key),
+                // object in a sub directory
+                _ => {}
+            }
+            pos += nbytes as u64;
+        }
+        report.end_of_data = pos.min(size);
+        report.lost_bytes = size - report.end_of_data;
+        report.recovered_keys = keys.len();
+        report.baskets = baskets.len();
+
+        let items: Vec<FileItem> = keys
+            .iter()
+            .map(|k_hdr| FileItem::new(k_hdr, source.clone()))
+            .collect();
+        // reconstruct the latest cycle of every tree and collect the baskets it references
+        let mut referenced = std::collections::HashSet::new();
+        for item in items.iter().filter(|item| item.root_class() == "TTree") {
+            let hdr = item.tkey_hdr();
+            let is_latest = !keys.iter().any(|k| {
+                k.class_name == "TTree" && k.obj_name == hdr.obj_name && k.cycle > hdr.cycle
+            });
+            if !is_latest {
+                continue;
+            }
+            match item.as_tree() {
+                Ok(tree) => {
+                    for branch in tree.branches() {
+                        for c in branch.containers() {
+                            if let Container::OnDisk(_, seek, _) = c {
+                                referenced.insert(*seek);
+                            }
+                        }
+                    }
+                    report.trees.push((hdr.obj_name.clone(), hdr.cycle, tree.entries()));
+                }
+                Err(e) => {
+                    warn!("failed to recover tree {};{}: {}", hdr.obj_name, hdr.cycle, e);
+                    report.unreadable_trees.push((hdr.obj_name.clone(), hdr.cycle));
+                }
+            }
+        }
+        report.orphaned_baskets = baskets.into_iter().filter(|seek| !referenced.contains(seek)).collect();
+        debug!("recovery: {:?}", report);
+        Ok((RootFile { source, hdr, dir, items }, report))
     }
 
     pub fn get_streamer_context(&self) -> Result<Context, Error> {
@@ -185,7 +502,7 @@ impl RootFile {
         let info_key = self
             .source
             .fetch(self.hdr.seek_info, seek_info_len)
-            .map(|buf| tkey(&buf).unwrap().1)?;
+            .and_then(|buf| tkey(&buf))?;
 
         let key_len = info_key.hdr.key_len;
         Ok(Context {
@@ -195,11 +512,94 @@ impl RootFile {
         })
     }
 
-    /// Slice of the items contained in this file
+    /// Where the file is read from
+    pub fn source(&self) -> &Source {
+        &self.source
+    }
+
+    /// The file header
+    pub fn header(&self) -> &FileHeader {
+        &self.hdr
+    }
+
+    /// The top level directory; `None` if the file was recovered without
+    /// a readable directory
+    pub fn root_directory(&self) -> Option<&Directory> {
+        self.dir.as_ref()
+    }
+
+    /// Free segments of this file, as recorded in its `TFree` list
+    pub fn free_segments(&self) -> Result<Vec<FreeSegment>, Error> {
+        layout::free_segments(&self.source, &self.hdr)
+    }
+
+    /// Map of the file from its start to `fEND`, attributing every byte
+    /// range to the header, a key, a basket or a free gap
+    pub fn byte_map(&self) -> Result<Vec<MapEntry>, Error> {
+        layout::byte_map(&self.source, &self.hdr)
+    }
+
+    /// Slice of the items contained in this file, including all cycles of each object
     pub fn items(&self) -> &[FileItem] {
         &self.items
     }
 
+    /// The highest cycle of every object in this file
+    pub fn latest_items(&self) -> Vec<&FileItem> {
+        self.items
+            .iter()
+            .filter(|item| {
+                !self.items.iter().any(|other| {
+                    other.obj_name() == item.obj_name() && other.cycle() > item.cycle()
+                })
+            })
+            .collect()
+    }
+
+    /// Read and decompress every key of the file and its directories and
+    /// every basket of every tree, checking sizes, entry counts and LZ4
+    /// checksums. Returns the first damaged object.
+    pub fn verify(&self) -> Result<VerifyReport, Corruption> {
+        self.verify_with(&DecompressOptions::default())
+    }
+
+    /// Like `verify`, decompressing with the given `options`, e.g. to skip
+    /// the LZ4 checksums for a faster check of the file structure
+    pub fn verify_with(&self, options: &DecompressOptions) -> Result<VerifyReport, Corruption> {
+        verify::verify(self, options)
+    }
+
+    /// Items of the subdirectory `item`, a key of class `TDirectory` (or
+    /// `TDirectoryFile`) of this file or of one of its subdirectories
+    pub fn directory_items(&self, item: &FileItem) -> Result<Vec<FileItem>, Error> {
+        if !item.is_directory() {
+            return Err(format_err!("{} is not a directory", item.name()));
+        }
+        let buf = item.get_buffer()?;
+        let (_, dir) = directory(&buf).map_err(|_| format_err!("Failed to parse TDirectory of {}", item.name()))?;
+        Self::read_keys(&self.source, &dir)
+    }
+
+    /// Look up an object by name. `name` resolves to the highest cycle of
+    /// that name, `name;N` to cycle `N`.
+    pub fn get(&self, spec: &str) -> Result<&FileItem, Error> {
+        find_item(&self.items, spec)
+    }
+
+    /// Look up an object in a subdirectory by its path, e.g. `dir/sub/name`
+    /// or `dir/sub/name;N`. Directories resolve to their highest cycle, the
+    /// object itself as in `get`.
+    pub fn get_path(&self, path: &str) -> Result<FileItem, Error> {
+        let mut parts = path.split('/').filter(|part| !part.is_empty()).collect::<Vec<_>>();
+        let spec = parts.pop().ok_or_else(|| format_err!("Empty path `{}`", path))?;
+        let mut items = None;
+        for dir in parts {
+            let dir = find_item(items.as_deref().unwrap_or(&self.items), dir)?;
+            items = Some(self.directory_items(dir)?);
+        }
+        find_item(items.as_deref().unwrap_or(&self.items), spec).cloned()
+    }
+
     /// Translate the streamer info of this file to a YAML file
     pub fn streamer_infos(&self) -> Result<Vec<TStreamerInfo>, Error> {
         let ctx = self.get_streamer_context()?;
@@ -254,3 +654,161 @@ impl RootFile {
         Ok(())
     }
 }
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use super::*;
+
+    fn foriter() -> (&'static [u8], Directory) {
+        let data: &'static [u8] = std::fs::read("./src/test_data/foriter.root").unwrap().leak();
+        let (_, hdr) = file_header(data).unwrap();
+        let (_, dir) = directory(&data[hdr.seek_dir as usize..]).unwrap();
+        (data, dir)
+    }
+
+    #[test]
+    fn recover_without_key_list() {
+        let (data, dir) = foriter();
+        // the job "crashed" before writing the key list
+        let truncated = &data[..dir.seek_keys as usize];
+        assert!(RootFile::new(truncated).is_err());
+        let (file, report) = RootFile::recover(truncated).unwrap();
+        assert_eq!(report.trees, vec![("foriter".to_string(), 1, 46)]);
+        assert!(report.orphaned_baskets.is_empty());
+        assert!(report.baskets > 0);
+        assert_eq!(report.lost_bytes, 0);
+        let tree = file.items().iter().find(|i| i.root_class() == "TTree").unwrap();
+        assert_eq!(tree.as_tree().unwrap().entries(), 46);
+    }
+
+    #[test]
+    fn recover_without_directory() {
+        let (data, _) = foriter();
+        let mut damaged = data.to_vec();
+        // fNbytesName points the directory beyond the end of the file
+        damaged[28..32].copy_from_slice(&0x7fff_0000i32.to_be_bytes());
+        let damaged: &'static [u8] = damaged.leak();
+        assert!(RootFile::new(damaged).is_err());
+        let (file, report) = RootFile::recover(damaged).unwrap();
+        assert!(file.root_directory().is_none());
+        assert_eq!(report.trees, vec![("foriter".to_string(), 1, 46)]);
+        assert!(report.orphaned_baskets.is_empty());
+    }
+
+    #[test]
+    fn file_metadata() {
+        let file = RootFile::new(std::path::Path::new("./src/test_data/HZZ-lz4.root")).unwrap();
+        let hdr = file.header();
+        assert_eq!(hdr.root_version(), RootVersion { major: 6, minor: 10, patch: 5 });
+        assert_eq!(hdr.root_version().to_string(), "6.10/05");
+        assert!(!hdr.is_large_file());
+        assert_eq!(
+            hdr.compression(),
+            Compression { algorithm: CompressionAlgorithm::Lz4, level: 4 }
+        );
+        assert_eq!(hdr.uuid().to_string(), "af1dace0-9a86-11e7-a72f-c116400abeef");
+        assert_eq!(hdr.end(), 286260);
+        let dir = file.root_directory().unwrap();
+        assert_eq!(dir.seek_dir(), hdr.begin());
+        assert_eq!(dir.seek_parent(), 0);
+        assert!(dir.created() <= dir.modified());
+
+        let file = RootFile::new(std::path::Path::new("./src/test_data/sample-5.23.02-zlib.root")).unwrap();
+        assert_eq!(file.header().root_version(), RootVersion { major: 5, minor: 23, patch: 2 });
+        assert_eq!(file.header().compression().algorithm, CompressionAlgorithm::Global);
+    }
+
+    #[test]
+    fn legacy_compression() {
+        let open = |name: &str| RootFile::new(std::path::Path::new(&format!("./src/test_data/{}", name))).unwrap();
+        let (legacy, zlib) = (open("sample-5.23.02-cs.root"), open("sample-5.23.02-zlib.root"));
+        assert_eq!(legacy.header().compression().algorithm, CompressionAlgorithm::Old);
+        let report = legacy.verify().unwrap();
+        assert_eq!(report.uncompressed_bytes, zlib.verify().unwrap().uncompressed_bytes);
+
+        assert_eq!(legacy.items().len(), zlib.items().len());
+        for (old, new) in legacy.items().iter().zip(zlib.items()) {
+            assert_eq!(old.get_buffer().unwrap(), new.get_buffer().unwrap(), "{}", old.name());
+        }
+        let tree = legacy.items().iter().find(|i| i.root_class() == "TTree").unwrap().as_tree().unwrap();
+        let expected = zlib.items().iter().find(|i| i.root_class() == "TTree").unwrap().as_tree().unwrap();
+        assert!(tree.branch_count() > 0);
+        for (branch, expected) in tree.branches().iter().zip(expected.branches()) {
+            assert_eq!(branch.containers().len(), expected.containers().len());
+            for (old, new) in branch.containers().iter().zip(expected.containers()) {
+                assert_eq!(old.clone().raw_data().unwrap(), new.clone().raw_data().unwrap(), "{}", branch.name());
+            }
+        }
+    }
+
+    #[test]
+    fn lookup_by_cycle() {
+        let (data, _) = foriter();
+        let mut file = RootFile::new(data).unwrap();
+        // pretend the tree was autosaved: a stale, bigger cycle 1 and the final cycle 2
+        let mut stale = file.items[0].tkey_hdr().clone();
+        stale.uncomp_len *= 2;
+        let mut last = file.items[0].tkey_hdr().clone();
+        last.cycle = 2;
+        file.items = vec![
+            FileItem::new(&stale, file.source.clone()),
+            FileItem::new(&last, file.source.clone()),
+        ];
+
+        assert_eq!(file.get("foriter").unwrap().cycle(), 2);
+        assert_eq!(file.get("foriter;1").unwrap().cycle(), 1);
+        assert!(file.get("foriter;3").is_err());
+        assert!(file.get("foriter;x").is_err());
+        assert!(file.get("nope").is_err());
+        let latest = file.latest_items();
+        assert_eq!(latest.len(), 1);
+        assert_eq!(latest[0].cycle(), 2);
+        assert_eq!(crate::anyblox::main_tree_item(&file).unwrap().cycle(), 2);
+    }
+
+    #[test]
+    fn recover_truncated_basket() {
+        let (data, _) = foriter();
+        let (intact, _) = RootFile::recover(data).unwrap();
+        assert_eq!(intact.items().len(), 1);
+        // cut the file in the middle of the first record after the file's own key
+        let (_, hdr) = file_header(data).unwrap();
+        let first = hdr.begin as usize;
+        let nbytes = u32::from_be_bytes(data[first..first + 4].try_into().unwrap()) as usize;
+        let cut = first + nbytes + 10;
+        let (file, report) = RootFile::recover(&data[..cut]).unwrap();
+        assert!(file.items().is_empty());
+        assert!(report.trees.is_empty());
+        assert_eq!(report.end_of_data, (first + nbytes) as u64);
+        assert_eq!(report.lost_bytes, 10);
+    }
+
+    #[test]
+    fn subdirectories() {
+        fn walk(file: &RootFile, prefix: &str, items: &[FileItem], out: &mut Vec<String>) {
+            for item in items {
+                let path = format!("{}{}", prefix, item.obj_name());
+                out.push(format!("{} {}", path, item.root_class()));
+                if item.is_directory() {
+                    walk(file, &format!("{}/", path), &file.directory_items(item).unwrap(), out);
+                }
+            }
+        }
+        let file = RootFile::new(std::path::Path::new("./src/test_data/nesteddirs.root")).unwrap();
+        let mut paths = Vec::new();
+        walk(&file, "", file.items(), &mut paths);
+        paths.sort();
+        assert_eq!(paths, [
+            "one TDirectory", "one/tree TTree", "one/two TDirectory", "one/two/tree TTree",
+            "three TDirectory", "three/tree TTree",
+        ]);
+        assert_eq!(file.get_path("one/two/tree").unwrap().root_class(), "TTree");
+        assert_eq!(file.get_path("/three/tree;1").unwrap().obj_name(), "tree");
+        assert!(file.get_path("one/three/tree").is_err());
+        assert!(file.get_path("one/tree/x").is_err());
+        let (data, _) = foriter();
+        let file = RootFile::new(data).unwrap();
+        assert!(file.directory_items(&file.items()[0]).is_err());
+        assert_eq!(file.get_path("foriter").unwrap().cycle(), 1);
+    }
+}
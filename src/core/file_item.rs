@@ -1,11 +1,11 @@
 use failure::Error;
 use nom::multi::length_value;
 
-use crate::core::{checked_byte_count, decompress, Context, Source, TKeyHeader};
+use crate::core::{checked_byte_count, decompress, Context, Datime, Source, TKeyHeader};
 use crate::tree_reader::{ttree, Tree};
 
 /// Describes a single item within this file (e.g. a `Tree`)
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct FileItem {
     source: Source,
     tkey_hdr: TKeyHeader,
@@ -25,6 +25,7 @@ impl FileItem {
         format!("{:#?}", self.tkey_hdr)
     }
 
+    /// Name and class formatted for display; see `obj_name` and `root_class`
     pub fn name(&self) -> String {
         format!(
             "`{}` of type `{}`",
@@ -36,27 +37,82 @@ impl FileItem {
         self.tkey_hdr.class_name.clone()
     }
 
+    /// Whether this is a subdirectory, see `RootFile::directory_items`
+    pub fn is_directory(&self) -> bool {
+        matches!(self.tkey_hdr.class_name.as_str(), "TDirectory" | "TDirectoryFile")
+    }
+
     pub fn uncompressed_size(&self) -> u32 {
         self.tkey_hdr.uncomp_len
     }
 
-    fn get_buffer(&self) -> Result<Vec<u8>, Error> {
+    pub(crate) fn tkey_hdr(&self) -> &TKeyHeader {
+        &self.tkey_hdr
+    }
+
+    /// Name of the object, without class information
+    pub fn obj_name(&self) -> &str {
+        &self.tkey_hdr.obj_name
+    }
+
+    /// Cycle number of this key. Objects written several times under
+    /// the same name (e.g. autosaved trees) have one key per cycle.
+    pub fn cycle(&self) -> i16 {
+        self.tkey_hdr.cycle
+    }
+
+    /// Title of the object
+    pub fn title(&self) -> &str {
+        &self.tkey_hdr.obj_title
+    }
+
+    /// Time at which the object was written
+    pub fn datime(&self) -> Datime {
+        Datime::from_raw(self.tkey_hdr.datime)
+    }
+
+    /// Absolute position of the key in the file
+    pub fn seek_key(&self) -> u64 {
+        self.tkey_hdr.seek_key
+    }
+
+    /// Bytes occupied in the file by the key header and the object
+    pub fn disk_size(&self) -> u32 {
+        self.tkey_hdr.total_size
+    }
+
+    /// Bytes occupied in the file by the (possibly compressed) object
+    /// alone; `None` for a damaged key whose header is longer than the key
+    pub fn compressed_size(&self) -> Option<u32> {
+        u32::try_from(self.tkey_hdr.key_len).ok()
+            .and_then(|key_len| self.tkey_hdr.total_size.checked_sub(key_len))
+    }
+
+    /// Ratio of uncompressed to compressed object size; 1 for objects
+    /// stored without compression, `None` if the key holds no object data
+    pub fn compression_ratio(&self) -> Option<f64> {
+        self.compressed_size()
+            .filter(|size| *size > 0)
+            .map(|size| f64::from(self.uncompressed_size()) / f64::from(size))
+    }
+
+    pub(crate) fn get_buffer(&self) -> Result<Vec<u8>, Error> {
+        let len = self.compressed_size()
+            .ok_or_else(|| format_err!("Key of {} is shorter than its header", self.name()))?;
         let start = self.tkey_hdr.seek_key + self.tkey_hdr.key_len as u64;
-        let len = self.tkey_hdr.total_size - self.tkey_hdr.key_len as u32;
         let comp_buf = self.source.fetch(start, len as u64)?;
 
         let buf = if self.tkey_hdr.total_size < self.tkey_hdr.uncomp_len {
             // Decompress the read buffer; buf is Vec<u8>
             debug_print!("decompressing fileitem buffer of length {}MB", len/ 1024/1024);
-            let (_, buf) = decompress(comp_buf.as_slice()).unwrap();
-            buf
+            decompress(&comp_buf)?
         } else {
-            comp_buf
+            comp_buf.to_vec()
         };
         Ok(buf)
     }
 
-    pub(crate) fn get_context<'s>(&self) -> Result<Context, Error> {
+    pub(crate) fn get_context(&self) -> Result<Context, Error> {
         let buffer = self.get_buffer()?;
         let k_map_offset = 2;
         Ok(Context {
@@ -77,7 +133,48 @@ impl FileItem {
             Err(nom::Err::Error(e)) | Err(nom::Err::Failure(e)) => {
                 Err(format_err!("Supplied parser failed! {:?}", e))
             }
-            _ => panic!(),
+            Err(nom::Err::Incomplete(needed)) => Err(format_err!("Tree is incomplete, needs {:?}", needed)),
         }
     }
 }
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use crate::core::{Datime, FileItem, RootFile};
+    use std::path::Path;
+
+    #[test]
+    fn key_metadata() {
+        let file = RootFile::new(Path::new("./src/test_data/foriter.root")).unwrap();
+        let item = &file.items()[0];
+        assert_eq!(item.obj_name(), "foriter");
+        assert_eq!(item.root_class(), "TTree");
+        assert_eq!(item.title(), "small baskets to test arrayiter");
+        assert_eq!(item.cycle(), 1);
+        assert_eq!(
+            item.datime(),
+            Datime { year: 2017, month: 9, day: 15, hour: 8, minute: 6, second: 49 }
+        );
+        assert_eq!(item.datime().to_string(), "2017-09-15 08:06:49");
+        assert_eq!(item.seek_key(), 996);
+        assert_eq!((item.disk_size(), item.compressed_size(), item.uncompressed_size()), (435, Some(363), 820));
+        assert!((item.compression_ratio().unwrap() - 820.0 / 363.0).abs() < 1e-12);
+
+        let file = RootFile::new(Path::new("./src/test_data/HZZ-uncompressed.root")).unwrap();
+        assert_eq!(file.items()[0].compression_ratio(), Some(1.0));
+    }
+
+    #[test]
+    fn key_shorter_than_its_header() {
+        let file = RootFile::new(Path::new("./src/test_data/foriter.root")).unwrap();
+        let item = &file.items()[0];
+        let mut hdr = item.tkey_hdr().clone();
+        hdr.total_size = hdr.key_len as u32;
+        let empty = FileItem::new(&hdr, file.source().clone());
+        assert_eq!((empty.compressed_size(), empty.compression_ratio()), (Some(0), None));
+        hdr.total_size -= 1;
+        let damaged = FileItem::new(&hdr, file.source().clone());
+        assert_eq!((damaged.compressed_size(), damaged.compression_ratio()), (None, None));
+        assert!(damaged.as_tree().is_err());
+    }
+}
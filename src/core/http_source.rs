@@ -0,0 +1,324 @@
+//! Reading remote files with HTTP(S) range requests, e.g. directly from
+//! CERN Open Data. Reads are served from a cache of fixed size blocks;
+//! missing blocks which are adjacent are fetched with a single request.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use bytes::Bytes;
+use failure::Error;
+
+use crate::core::DataSource;
+
+const DEFAULT_BLOCK_SIZE: u64 = 256 * 1024;
+const DEFAULT_CACHE_BLOCKS: usize = 64;
+const DEFAULT_RETRIES: u32 = 3;
+const RETRY_BACKOFF: Duration = Duration::from_millis(100);
+
+/// A remote file read with HTTP range requests. The server has to
+/// support `Range` headers; this is checked when the source is created.
+pub struct HttpSource {
+    url: String,
+    agent: ureq::Agent,
+    len: u64,
+    block_size: u64,
+    cache_blocks: usize,
+    retries: u32,
+    cache: Mutex<BlockCache>,
+}
+
+/// Least recently used blocks, indexed by block number
+#[derive(Default)]
+struct BlockCache {
+    /// data and time of last use of every block
+    blocks: HashMap<u64, (u64, Bytes)>,
+    /// blocks by time of last use
+    lru: BTreeMap<u64, u64>,
+    clock: u64,
+}
+
+impl BlockCache {
+    fn get(&mut self, block: u64) -> Option<Bytes> {
+        let (used, data) = self.blocks.get_mut(&block)?;
+        self.lru.remove(used);
+        self.clock += 1;
+        *used = self.clock;
+        self.lru.insert(self.clock, block);
+        Some(data.clone())
+    }
+
+    fn insert(&mut self, block: u64, data: Bytes, capacity: usize) {
+        self.clock += 1;
+        if let Some((used, _)) = self.blocks.insert(block, (self.clock, data)) {
+            self.lru.remove(&used);
+        }
+        self.lru.insert(self.clock, block);
+        while self.blocks.len() > capacity {
+            if let Some((_, old)) = self.lru.pop_first() {
+                self.blocks.remove(&old);
+            }
+        }
+    }
+}
+
+/// Whether a failed request is worth repeating
+fn is_transient(e: &ureq::Error) -> bool {
+    match e {
+        ureq::Error::StatusCode(status) => *status >= 500 || *status == 429,
+        ureq::Error::Io(_) | ureq::Error::Timeout(_) | ureq::Error::ConnectionFailed => true,
+        _ => false,
+    }
+}
+
+impl HttpSource {
+    /// Connect to `url` and determine the size of the file
+    pub fn new<S: Into<String>>(url: S) -> Result<Self, Error> {
+        let mut source = HttpSource {
+            url: url.into(),
+            agent: ureq::Agent::new_with_defaults(),
+            len: 0,
+            block_size: DEFAULT_BLOCK_SIZE,
+            cache_blocks: DEFAULT_CACHE_BLOCKS,
+            retries: DEFAULT_RETRIES,
+            cache: Mutex::new(BlockCache::default()),
+        };
+        let (_, total) = source.get_range(0, 1)?;
+        source.len = total.ok_or_else(|| {
+            format_err!("{} does not support range requests", source.url)
+        })?;
+        Ok(source)
+    }
+
+    /// Size of the blocks which are requested and cached; defaults to 256kB
+    pub fn with_block_size(mut self, block_size: u64) -> Self {
+        self.block_size = block_size.max(1);
+        self
+    }
+
+    /// Number of blocks kept in the cache; defaults to 64
+    pub fn with_cache_blocks(mut self, cache_blocks: usize) -> Self {
+        self.cache_blocks = cache_blocks;
+        self
+    }
+
+    /// How often a request failing with a transient error (connection
+    /// problems, 5xx) is repeated; defaults to 3
+    pub fn with_retries(mut self, retries: u32) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    /// Request `len` bytes at `start`. Returns the data and the total file
+    /// size, if the server sent it.
+    fn get_range(&self, start: u64, len: u64) -> Result<(Bytes, Option<u64>), Error> {
+        let range = format!("bytes={}-{}", start, start + len - 1);
+        let mut attempt = 0;
+        let mut response = loop {
+            match self.agent.get(&self.url).header("Range", &range).call() {
+                Ok(response) => break response,
+                Err(e) if attempt < self.retries && is_transient(&e) => {
+                    warn!("request for {} of {} failed ({}), retrying", range, self.url, e);
+                    std::thread::sleep(RETRY_BACKOFF * 2u32.pow(attempt));
+                    attempt += 1;
+                }
+                Err(e) => return Err(format_err!("request for {} of {} failed: {}", range, self.url, e)),
+            }
+        };
+        // check the response before reading the body: a server ignoring
+        // the range answers `200` and sends the whole file
+        let status = response.status().as_u16();
+        if status != 206 {
+            return Err(format_err!("unexpected status {} for {} of {}", status, range, self.url));
+        }
+        // `bytes <first>-<last>/<total>`, where the total may be `*`
+        let content_range = response
+            .headers()
+            .get("content-range")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("bytes "))
+            .and_then(|v| v.split_once('/'))
+            .and_then(|(bytes, total)| Some((bytes.split_once('-')?, total.parse::<u64>().ok())));
+        let total = match content_range {
+            Some(((first, last), total)) if first.parse() == Ok(start) && last.parse() == Ok(start + len - 1) => total,
+            _ => return Err(format_err!("response for {} of {} does not have a matching Content-Range", range, self.url)),
+        };
+        // ureq rejects a body that reaches the limit, hence the extra byte
+        let body = response.body_mut().with_config().limit(len + 1).read_to_vec()
+            .map_err(|e| format_err!("failed to read {} of {}: {}", range, self.url, e))?;
+        if body.len() as u64 != len {
+            return Err(format_err!("expected {} bytes for {} of {}, got {}", len, range, self.url, body.len()));
+        }
+        Ok((Bytes::from(body), total))
+    }
+}
+
+impl std::fmt::Debug for HttpSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_tuple("Http").field(&self.url).finish()
+    }
+}
+
+impl DataSource for HttpSource {
+    fn fetch(&self, start: u64, len: u64) -> Result<Bytes, Error> {
+        if start.checked_add(len).is_none_or(|end| end > self.len) {
+            return Err(format_err!("Read of {} bytes at {} is out of bounds ({} bytes)", len, start, self.len));
+        }
+        if len == 0 {
+            return Ok(Bytes::new());
+        }
+        let bs = self.block_size;
+        let (first, last) = (start / bs, (start + len - 1) / bs);
+        let mut blocks: Vec<Option<Bytes>> = {
+            let mut cache = self.cache.lock().unwrap();
+            (first..=last).map(|b| cache.get(b)).collect()
+        };
+        // fetch each run of consecutive missing blocks with one request
+        let mut i = 0;
+        while i < blocks.len() {
+            if blocks[i].is_some() {
+                i += 1;
+                continue;
+            }
+            let run_end = (i..blocks.len()).find(|j| blocks[*j].is_some()).unwrap_or(blocks.len());
+            let run_start = (first + i as u64) * bs;
+            let run_len = ((first + run_end as u64) * bs).min(self.len) - run_start;
+            let (data, _) = self.get_range(run_start, run_len)?;
+            let mut cache = self.cache.lock().unwrap();
+            for (j, block) in blocks.iter_mut().enumerate().take(run_end).skip(i) {
+                let offset = (j - i) as u64 * bs;
+                let data = data.slice(offset as usize..(offset + bs).min(run_len) as usize);
+                cache.insert(first + j as u64, data.clone(), self.cache_blocks);
+                *block = Some(data);
+            }
+            i = run_end;
+        }
+        let offset = (start - first * bs) as usize;
+        if let [block] = blocks.as_slice() {
+            return Ok(block.as_ref().unwrap().slice(offset..offset + len as usize));
+        }
+        let mut buf = Vec::with_capacity(len as usize);
+        for block in blocks.iter().flatten() {
+            buf.extend_from_slice(block);
+        }
+        Ok(Bytes::from(buf).slice(offset..offset + len as usize))
+    }
+
+    fn len(&self) -> Result<u64, Error> {
+        Ok(self.len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{RootFile, Source};
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    /// Serve `data` on localhost, answering range requests. The first
+    /// `failures` requests are answered with `503`. Without `ranges`, the
+    /// server ignores the `Range` header and sends the whole file.
+    fn serve(data: Vec<u8>, failures: usize, ranges: bool) -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/file.root", listener.local_addr().unwrap());
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut range = None;
+                let mut reader = BufReader::new(&stream);
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line.trim().is_empty() {
+                        break;
+                    }
+                    if let Some(v) = line.to_ascii_lowercase().strip_prefix("range: bytes=") {
+                        let (a, b) = v.trim().split_once('-').unwrap();
+                        range = Some((a.parse::<usize>().unwrap(), b.parse::<usize>().unwrap()));
+                    }
+                }
+                let n = counter.fetch_add(1, Ordering::SeqCst);
+                if n < failures {
+                    stream.write_all(b"HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\nConnection: close\r\n\r\n").unwrap();
+                    continue;
+                }
+                if !ranges {
+                    let head = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", data.len());
+                    stream.write_all(head.as_bytes()).unwrap();
+                    // the client must not wait for the body
+                    let _ = stream.write_all(&data);
+                    continue;
+                }
+                let (a, b) = range.unwrap();
+                let body = &data[a..=b];
+                let head = format!(
+                    "HTTP/1.1 206 Partial Content\r\nContent-Length: {}\r\nContent-Range: bytes {}-{}/{}\r\nConnection: close\r\n\r\n",
+                    body.len(), a, b, data.len()
+                );
+                stream.write_all(head.as_bytes()).unwrap();
+                stream.write_all(body).unwrap();
+            }
+        });
+        (url, requests)
+    }
+
+    #[test]
+    fn read_remote_file() {
+        let data = std::fs::read("./src/test_data/foriter.root").unwrap();
+        let (url, requests) = serve(data.clone(), 0, true);
+        let http = HttpSource::new(url).unwrap().with_block_size(1024);
+        assert_eq!(http.len().unwrap(), data.len() as u64);
+        let source = Source::with_backend(http);
+
+        // a read spanning three blocks is a single request
+        let before = requests.load(Ordering::SeqCst);
+        assert_eq!(&source.fetch(1000, 2100).unwrap()[..], &data[1000..3100]);
+        assert_eq!(requests.load(Ordering::SeqCst), before + 1);
+        // and is cached afterwards
+        assert_eq!(&source.fetch(1500, 100).unwrap()[..], &data[1500..1600]);
+        assert_eq!(requests.load(Ordering::SeqCst), before + 1);
+        assert!(source.fetch(data.len() as u64 - 1, 2).is_err());
+
+        let file = RootFile::new(source).unwrap();
+        assert_eq!(file.items()[0].as_tree().unwrap().entries(), 46);
+    }
+
+    #[test]
+    fn retry_transient_errors() {
+        let data = std::fs::read("./src/test_data/foriter.root").unwrap();
+        let (url, _) = serve(data.clone(), 2, true);
+        let http = HttpSource::new(url.clone()).unwrap();
+        assert_eq!(&http.fetch(100, 20).unwrap()[..], &data[100..120]);
+
+        let (url, _) = serve(vec![0; 10], 10, true);
+        assert!(HttpSource::new(url).is_err());
+    }
+
+    #[test]
+    fn server_ignoring_ranges() {
+        let (url, _) = serve(vec![0; 1 << 20], 0, false);
+        let err = HttpSource::new(url).unwrap_err();
+        assert!(err.to_string().contains("unexpected status 200"), "{}", err);
+    }
+
+    #[test]
+    fn cache_evicts_least_recently_used() {
+        let mut cache = BlockCache::default();
+        for block in 0..3 {
+            cache.insert(block, Bytes::from(vec![block as u8]), 3);
+        }
+        assert!(cache.get(0).is_some());
+        cache.insert(3, Bytes::new(), 3);
+        // block 1 was used least recently
+        assert!(cache.get(1).is_none());
+        assert!(cache.get(0).is_some() && cache.get(2).is_some() && cache.get(3).is_some());
+        // reinserting a block does not grow the cache
+        cache.insert(3, Bytes::new(), 3);
+        assert_eq!((cache.blocks.len(), cache.lru.len()), (3, 3));
+    }
+}
@@ -0,0 +1,182 @@
+//! Physical layout of a file: the list of free segments (`TFree`
+//! records) and a map attributing every byte to the record it belongs
+//! to, similar to `TFile::Map()`.
+
+use failure::Error;
+use nom::{
+    combinator::map,
+    multi::count,
+    number::complete::{be_i16, be_u32, be_u64},
+    sequence::tuple,
+    IResult,
+};
+
+use crate::core::file::{scan_key, FileHeader};
+use crate::core::*;
+
+/// A range of unused bytes, `first..=last`, as listed in the file's
+/// free segment record. The last segment usually starts at `fEND` and
+/// extends to the maximum file size ROOT allows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FreeSegment {
+    pub first: u64,
+    pub last: u64,
+}
+
+/// What a range of bytes in the file is used for
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Region {
+    /// The file header, up to `fBEGIN`
+    Header,
+    /// A key and the object it holds, e.g. a `TTree`, the key list or the
+    /// `StreamerInfo`
+    Key { class: String, name: String, cycle: i16 },
+    /// A `TBasket` holding entries of `branch`
+    Basket { branch: String },
+    /// A gap left by a deleted or rewritten record
+    Free,
+    /// Bytes which could not be attributed, e.g. after a corrupt record
+    Unknown,
+}
+
+/// A contiguous byte range of the file and what it is used for
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MapEntry {
+    pub start: u64,
+    pub len: u64,
+    pub region: Region,
+}
+
+/// Single `TFree` record
+fn tfree(input: &[u8]) -> IResult<&[u8], FreeSegment> {
+    let (input, version) = be_i16(input)?;
+    let (input, (first, last)) = if version > 1000 {
+        tuple((be_u64, be_u64))(input)?
+    } else {
+        map(tuple((be_u32, be_u32)), |(f, l)| {
+            (u64::from(f), u64::from(l))
+        })(input)?
+    };
+    Ok((input, FreeSegment { first, last }))
+}
+
+/// Read the free segment list referenced by the file header
+pub(crate) fn free_segments(source: &Source, hdr: &FileHeader) -> Result<Vec<FreeSegment>, Error> {
+    let (seek, nbytes) = hdr.seek_free();
+    if seek == 0 || nbytes <= 0 {
+        return Ok(vec![]);
+    }
+    let buf = source.fetch(seek, nbytes as u64)?;
+    let key = tkey(&buf)
+        .map_err(|e| format_err!("Failed to parse key of free segments: {}", e))?;
+    let segments = count(tfree, hdr.n_entries_free().max(0) as usize)(&key.obj)
+        .map(|(_, segments)| segments)
+        .map_err(|_| format_err!("Failed to parse free segments"))?;
+    Ok(segments)
+}
+
+/// Walk all records from `fBEGIN` to `fEND`
+pub(crate) fn byte_map(source: &Source, hdr: &FileHeader) -> Result<Vec<MapEntry>, Error> {
+    let free = free_segments(source, hdr)?;
+    let end = hdr.end().min(source.len()?);
+    let mut entries = vec![MapEntry { start: 0, len: hdr.begin(), region: Region::Header }];
+    let mut pos = hdr.begin();
+    while pos < end {
+        let nbytes = source
+            .fetch(pos, 4)
+            .map(|buf| i32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]))?;
+        if nbytes < 0 {
+            let len = u64::from(nbytes.unsigned_abs());
+            entries.push(MapEntry { start: pos, len, region: Region::Free });
+            pos += len;
+            continue;
+        }
+        let key = match scan_key(source, pos, nbytes as u64) {
+            Some(key) => key,
+            None => {
+                // ROOT marks gaps with a negative size, but fall back to
+                // the free list in case only that was updated
+                if let Some(seg) = free.iter().find(|seg| seg.first == pos && seg.last < end) {
+                    let len = seg.last - seg.first + 1;
+                    entries.push(MapEntry { start: pos, len, region: Region::Free });
+                    pos += len;
+                    continue;
+                }
+                warn!("no valid record at offset {}, rest of the file is unaccounted for", pos);
+                entries.push(MapEntry { start: pos, len: end - pos, region: Region::Unknown });
+                break;
+            }
+        };
+        let region = if key.class_name == "TBasket" {
+            Region::Basket { branch: key.obj_name }
+        } else {
+            Region::Key { class: key.class_name, name: key.obj_name, cycle: key.cycle }
+        };
+        entries.push(MapEntry { start: pos, len: nbytes as u64, region });
+        pos += nbytes as u64;
+    }
+    Ok(entries)
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    #[test]
+    fn map_covers_file() {
+        let file = RootFile::new(Path::new("./src/test_data/foriter.root")).unwrap();
+        let free = file.free_segments().unwrap();
+        // only the space after the end of the file
+        assert_eq!(free.len(), 1);
+        assert_eq!(free[0].first, file.header().end());
+
+        let map = file.byte_map().unwrap();
+        assert_eq!(map[0], MapEntry { start: 0, len: 100, region: Region::Header });
+        // contiguous and complete
+        let mut pos = 0;
+        for entry in &map {
+            assert_eq!(entry.start, pos);
+            pos += entry.len;
+        }
+        assert_eq!(pos, file.header().end());
+        assert!(!map.iter().any(|e| e.region == Region::Unknown));
+
+        let tree = map.iter().find(|e| matches!(&e.region, Region::Key { class, .. } if class == "TTree"));
+        assert_eq!(tree.unwrap().start, file.items()[0].seek_key());
+        let baskets: std::collections::HashSet<_> = map.iter()
+            .filter(|e| matches!(e.region, Region::Basket { .. }))
+            .map(|e| e.start)
+            .collect();
+        let tree = file.items()[0].as_tree().unwrap();
+        let expected = tree.branches().iter()
+            .flat_map(|b| b.containers())
+            .filter_map(|c| match c {
+                crate::tree_reader::Container::OnDisk(_, seek, _) => Some(*seek),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(baskets, expected);
+    }
+
+    #[test]
+    fn deleted_record_is_free() {
+        let mut data = std::fs::read("./src/test_data/foriter.root").unwrap();
+        let file = RootFile::new(Path::new("./src/test_data/foriter.root")).unwrap();
+        let (seek, len) = file.items()[0].as_tree().unwrap().branches()[0].containers().iter()
+            .filter_map(|c| match c {
+                crate::tree_reader::Container::OnDisk(_, seek, len) => Some((*seek, *len)),
+                _ => None,
+            })
+            .min()
+            .unwrap();
+        // mark the first basket as deleted, the way ROOT does
+        let start = seek as usize;
+        data[start..start + 4].copy_from_slice(&(-(len as i32)).to_be_bytes());
+        let file = RootFile::new(&*data.leak()).unwrap();
+        let map = file.byte_map().unwrap();
+        let idx = map.iter().position(|e| e.start == seek).unwrap();
+        assert_eq!(map[idx], MapEntry { start: seek, len, region: Region::Free });
+        assert!(matches!(map[idx + 1].region, Region::Basket { .. }));
+    }
+}
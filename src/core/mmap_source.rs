@@ -0,0 +1,108 @@
+//! Memory mapped local files. The mapping is owned by the source and
+//! shared through an `Arc`; the `Bytes` returned by `fetch` are slices of
+//! it, so they stay valid even after the `RootFile` is dropped.
+
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use bytes::Bytes;
+use failure::Error;
+use memmap2::Mmap;
+
+use crate::core::DataSource;
+
+/// Keeps the mapping alive for as long as any `Bytes` point into it
+struct MapOwner(Arc<Mmap>);
+
+impl AsRef<[u8]> for MapOwner {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+/// A local file read through a memory mapping
+pub struct MmapSource {
+    path: PathBuf,
+    map: Arc<Mmap>,
+    data: Bytes,
+}
+
+impl MmapSource {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let path = path.as_ref().to_path_buf();
+        let file = File::open(&path)?;
+        // Safety: as with any mapping, the file must not be truncated
+        // while it is mapped
+        let map = Arc::new(unsafe { Mmap::map(&file)? });
+        let data = Bytes::from_owner(MapOwner(map.clone()));
+        Ok(MmapSource { path, map, data })
+    }
+
+    /// The whole file, e.g. for `decode_batch_internal`
+    pub fn data(&self) -> &Bytes {
+        &self.data
+    }
+}
+
+impl std::fmt::Debug for MmapSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_tuple("Mmap").field(&self.path).finish()
+    }
+}
+
+impl DataSource for MmapSource {
+    fn fetch(&self, start: u64, len: u64) -> Result<Bytes, Error> {
+        start
+            .checked_add(len)
+            .filter(|end| *end <= self.data.len() as u64)
+            .map(|end| self.data.slice(start as usize..end as usize))
+            .ok_or_else(|| format_err!("Read of {} bytes at {} is out of bounds ({} bytes)", len, start, self.data.len()))
+    }
+
+    fn len(&self) -> Result<u64, Error> {
+        Ok(self.map.len() as u64)
+    }
+
+    /// `madvise(MADV_WILLNEED)`, so the kernel starts reading the range in
+    fn prefetch(&self, start: u64, len: u64) {
+        #[cfg(unix)]
+        {
+            let end = start.saturating_add(len).min(self.map.len() as u64);
+            if start < end {
+                if let Err(e) = self.map.advise_range(memmap2::Advice::WillNeed, start as usize, (end - start) as usize) {
+                    debug!("madvise of {} bytes at {} failed: {}", end - start, start, e);
+                }
+            }
+        }
+        #[cfg(not(unix))]
+        let _ = (start, len);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{RootFile, Source};
+
+    #[test]
+    fn mapping_outlives_source() {
+        let path = "./src/test_data/foriter.root";
+        let data = std::fs::read(path).unwrap();
+        let source = Source::with_backend(MmapSource::open(path).unwrap());
+        assert_eq!(source.len().unwrap(), data.len() as u64);
+        let buf = source.fetch(996, 435).unwrap();
+        assert!(source.fetch(data.len() as u64 - 1, 2).is_err());
+        source.prefetch(0, data.len() as u64 * 2);
+        drop(source);
+        assert_eq!(&buf[..], &data[996..996 + 435]);
+
+        let file = RootFile::open(path).unwrap();
+        assert_eq!(file.items()[0].as_tree().unwrap().entries(), 46);
+        // `open` maps the file with the default features
+        if cfg!(target_os = "linux") {
+            assert!(format!("{:?}", file.source()).contains("Mmap"));
+        }
+        assert!(MmapSource::open("./src/test_data/missing.root").is_err());
+    }
+}
@@ -5,12 +5,18 @@
 mod data_source;
 pub(crate) mod file;
 mod file_item;
+#[cfg(all(feature = "http", not(target_arch = "wasm32")))]
+mod http_source;
+mod layout;
+#[cfg(all(feature = "mmap", not(target_arch = "wasm32")))]
+mod mmap_source;
 pub mod parsers;
 mod tkey;
 mod tstreamer;
 mod tstreamerinfo;
 mod typeid;
 pub mod types;
+mod verify;
 
 pub(crate) use self::parsers::*;
 pub(crate) use self::tkey::*;
@@ -19,8 +25,16 @@ pub(crate) use self::tstreamerinfo::{tstreamerinfo, TStreamerInfo};
 pub(crate) use self::typeid::*;
 pub(crate) use self::types::*;
 
-pub use self::data_source::Source;
+pub use self::data_source::{DataSource, LocalFile, Source};
 pub use self::file::RootFile;
-pub use self::file::Directory;
+pub use self::file::{
+    Compression, CompressionAlgorithm, Directory, FileHeader, RecoveryReport, RootVersion,
+};
 pub use self::file_item::FileItem;
-pub use self::types::Tid;
+#[cfg(all(feature = "http", not(target_arch = "wasm32")))]
+pub use self::http_source::HttpSource;
+#[cfg(all(feature = "mmap", not(target_arch = "wasm32")))]
+pub use self::mmap_source::MmapSource;
+pub use self::layout::{FreeSegment, MapEntry, Region};
+pub use self::types::{Datime, Tid};
+pub use self::verify::{Corruption, VerifyReport};
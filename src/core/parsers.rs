@@ -1,5 +1,5 @@
 use std::convert::TryFrom;
-use std::fmt::Debug;
+use std::fmt::{self, Debug};
 use std::io::Read;
 /// Parsers of the ROOT core types. Note that objects in ROOT files
 /// are often, but not always, preceeded by their size. The parsers in
@@ -9,8 +9,8 @@ use std::io::Read;
 /// themselves.
 use std::str;
 
-use failure::Error;
-use flate2::bufread::ZlibDecoder;
+use failure::{Error, Fail};
+use flate2::bufread::{DeflateDecoder, ZlibDecoder};
 use lz4_compress::decompress as lz4_decompress;
 use lzma_rs::xz_decompress;
 use nom::{
@@ -33,7 +33,7 @@ fn is_byte_count(v: &u32) -> bool {
 
 /// Return the size in bytes of the following object in the input. The
 /// count is the remainder of this object minus the size of the count.
-pub fn checked_byte_count<'s, E>(input: &'s [u8]) -> nom::IResult<&[u8], u32, E>
+pub fn checked_byte_count<'s, E>(input: &'s [u8]) -> nom::IResult<&'s [u8], u32, E>
 where
     E: ParseError<&'s [u8]> + Debug,
 {
@@ -110,13 +110,7 @@ where
     let (i, objs) = count(
         map_res(
             |i| raw(i, context),
-            |r| {
-                let res = parser(&r, context).map(|(_i, res)| res);
-                if res.is_err() {
-                    res.as_ref().unwrap();
-                }
-                res
-            },
+            |r| parser(&r, context).map(|(_i, res)| res),
         ),
         size as usize,
     )(i)?;
@@ -124,14 +118,13 @@ where
 }
 
 /// Parse a `TObjArray` which does not have references pointing outside of the input buffer
-pub fn tobjarray_no_context(input: &[u8]) -> nom::IResult<&[u8], Vec<(ClassInfo, &[u8])>> {
+pub fn tobjarray_no_context(input: &[u8]) -> nom::IResult<&[u8], Vec<(ClassInfo<'_>, &[u8])>> {
     let (input, _ver) = be_u16(input)?;
     let (input, _tobj) = tobject(input)?;
     let (input, _name) = c_string(input)?;
     let (input, size) = be_i32(input)?;
     let (input, _low) = be_i32(input)?;
     let (input, objs) = count(raw_no_context, size as usize)(input)?;
-    let objs = objs.into_iter().map(|(ci, s)| (ci, s)).collect();
     Ok((input, objs))
 }
 
@@ -155,69 +148,221 @@ where
     count(parser, counts as usize)(i)
 }
 
-fn decode_reader<'s>(bytes: &'s [u8], magic: &[u8]) -> nom::IResult<&'s [u8], Vec<u8>> {
-    // print magic as chars
+/// Checksum ROOT stores (big-endian) in front of every LZ4 block: the
+/// xxhash64 of the compressed block, with seed 0
+pub fn lz4_checksum(block: &[u8]) -> u64 {
+    xxhash_rust::xxh64::xxh64(block, 0)
+}
+
+/// Error of `decompress` and `decompress_into` for an LZ4 block whose
+/// contents do not match its checksum, i.e. corrupted data
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChecksumMismatch {
+    pub stored: u64,
+    pub computed: u64,
+}
+
+impl fmt::Display for ChecksumMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "LZ4 checksum mismatch: stored {:016x}, computed {:016x}", self.stored, self.computed)
+    }
+}
+
+impl Fail for ChecksumMismatch {}
+
+/// Settings of `decompress_with` and `decompress_into_with`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DecompressOptions {
+    lz4_checksums: bool,
+}
+
+impl Default for DecompressOptions {
+    fn default() -> Self {
+        DecompressOptions { lz4_checksums: true }
+    }
+}
+
+impl DecompressOptions {
+    /// Verify the checksums of LZ4 blocks (the default). Skipping them
+    /// trades the detection of corrupted data for speed.
+    pub fn with_lz4_checksums(mut self, verify: bool) -> Self {
+        self.lz4_checksums = verify;
+        self
+    }
+
+    pub fn lz4_checksums(&self) -> bool {
+        self.lz4_checksums
+    }
+}
+
+/// The LZ4 block following the checksum in `bytes`, verifying the checksum
+/// if `options` ask for it
+fn lz4_block<'s>(bytes: &'s [u8], options: &DecompressOptions) -> Result<&'s [u8], Error> {
+    let (block, stored) = be_u64::<_, ()>(bytes).map_err(|_| format_err!("LZ4 block is too short for its checksum"))?;
+    if options.lz4_checksums {
+        let computed = lz4_checksum(block);
+        if stored != computed {
+            return Err(ChecksumMismatch { stored, computed }.into());
+        }
+    }
+    Ok(block)
+}
+
+/// Size of the header in front of every compressed block: the algorithm,
+/// method and the 3-byte little-endian compressed and uncompressed sizes
+const BLOCK_HEADER_SIZE: usize = 9;
+
+/// One compressed block of a buffer
+struct Block<'s> {
+    /// "Magic" bytes naming the algorithm
+    magic: &'s [u8],
+    /// Size of the block after decompression, according to its header
+    uncomp_len: usize,
+    /// The compressed data
+    data: &'s [u8],
+}
+
+fn le_u24(bytes: &[u8]) -> usize {
+    u32::from_le_bytes([bytes[0], bytes[1], bytes[2], 0]) as usize
+}
+
+/// Split the first block off `input`, which starts at byte `pos` of the
+/// whole buffer
+fn compression_block(input: &[u8], pos: usize) -> Result<(Block<'_>, &[u8]), Error> {
+    if input.len() < BLOCK_HEADER_SIZE {
+        return Err(format_err!("Truncated compression header at byte {}", pos));
+    }
+    let (hdr, rest) = input.split_at(BLOCK_HEADER_SIZE);
+    let comp_len = le_u24(&hdr[3..6]);
+    if comp_len > rest.len() {
+        return Err(format_err!("Compressed block of {} bytes at byte {} exceeds the buffer", comp_len, pos));
+    }
+    let (data, rest) = rest.split_at(comp_len);
+    Ok((Block { magic: &hdr[..2], uncomp_len: le_u24(&hdr[6..9]), data }, rest))
+}
+
+/// The blocks of a compressed buffer and their positions in it. A buffer
+/// holds at least one block; iteration stops at the first damaged header.
+fn blocks(input: &[u8]) -> impl Iterator<Item = Result<(usize, Block<'_>), Error>> {
+    let mut rest = Some(input);
+    std::iter::from_fn(move || {
+        let current = rest.take()?;
+        let pos = input.len() - current.len();
+        Some(compression_block(current, pos).map(|(block, next)| {
+            if !next.is_empty() {
+                rest = Some(next);
+            }
+            debug_print!("decompress scheme: {:?}", block.magic.iter().map(|&b| b as char).collect::<String>());
+            (pos, block)
+        }))
+    })
+}
+
+fn decode_reader(bytes: &[u8], magic: &[u8], options: &DecompressOptions) -> Result<Vec<u8>, Error> {
+    let mut ret = vec![];
     match magic {
-        b"ZL" => map_res(rest, |bytes| {
-            let mut ret = vec![];
+        b"ZL" => {
             let mut decoder = ZlibDecoder::new(bytes);
             decoder.read_to_end(&mut ret)?;
-            Ok::<_, Error>(ret)
-        })(bytes),
-        b"XZ" => map_res(rest, |bytes| {
-            let mut ret = vec![];
+        }
+        b"CS" => {
+            // ROOT's original algorithm, derived from gzip: a raw deflate
+            // stream without the zlib header and checksum
+            let mut decoder = DeflateDecoder::new(bytes);
+            decoder.read_to_end(&mut ret)?;
+        }
+        b"XZ" => {
             let mut reader = std::io::BufReader::new(bytes);
-            xz_decompress(&mut reader, &mut ret).unwrap();
-            Ok::<_, Error>(ret)
-        })(bytes),
-        b"L4" => {
-            let (bytes, _checksum) = be_u64(bytes)?;
-            map_res(rest, lz4_decompress)(bytes)
+            xz_decompress(&mut reader, &mut ret).map_err(|e| format_err!("XZ decompression failed: {:?}", e))?;
         }
-        _ => panic!(), // m => return Err(format_err!("Unsupported compression format `{}`", m)),
+        b"L4" => ret = lz4_decompress(lz4_block(bytes, options)?)?,
+        m => return Err(format_err!("Unsupported compression format `{}`", String::from_utf8_lossy(m))),
     }
+    Ok(ret)
 }
 
-/// Decompress the given buffer. Figures out the compression algorithm from the preceeding \"magic\" bytes
-pub fn decompress(input: &[u8]) -> nom::IResult<&[u8], Vec<u8>> {
-    let (input, magic) = take(2usize)(input)?;
-    let (input, _header) = take(7usize)(input)?;
-    debug_print!("decompress scheme: {:?}", magic.iter().map(|&b| b as char).collect::<String>());
-    decode_reader(input, magic)
+/// Decompress the given buffer, which is made of one or more compressed
+/// blocks. Figures out the compression algorithm of each block from its
+/// preceeding \"magic\" bytes. Fails if the blocks are truncated or do not
+/// decompress to the sizes in their headers, and with a `ChecksumMismatch`
+/// if an LZ4 block is corrupted.
+pub fn decompress(input: &[u8]) -> Result<Vec<u8>, Error> {
+    decompress_with(input, &DecompressOptions::default())
 }
 
-fn decode_reader_into<'s, 'o>(bytes: &'s [u8], mut output: &'o mut [u8], magic: &[u8]) -> nom::IResult<&'s [u8], usize> {
+/// Like `decompress`, with the given `options`
+pub fn decompress_with(input: &[u8], options: &DecompressOptions) -> Result<Vec<u8>, Error> {
+    let mut ret = vec![];
+    for block in blocks(input) {
+        let (pos, block) = block?;
+        let decoded = decode_reader(block.data, block.magic, options)?;
+        if decoded.len() != block.uncomp_len {
+            return Err(format_err!("Block at byte {} decompressed to {} bytes, its header says {}",
+                pos, decoded.len(), block.uncomp_len));
+        }
+        ret.extend_from_slice(&decoded);
+    }
+    Ok(ret)
+}
+
+/// Fill `output` from `decoder`, which has to decompress to exactly as
+/// many bytes
+fn read_exactly<R: Read>(mut decoder: R, output: &mut [u8]) -> Result<(), Error> {
+    decoder.read_exact(output)
+        .map_err(|e| format_err!("Block does not decompress to the {} bytes of its header: {}", output.len(), e))?;
+    if decoder.read(&mut [0])? != 0 {
+        return Err(format_err!("Block decompresses to more than the {} bytes of its header", output.len()));
+    }
+    Ok(())
+}
+
+/// Decode `bytes` into `output`, which is as long as the decompressed data
+fn decode_reader_into(bytes: &[u8], output: &mut [u8], magic: &[u8], options: &DecompressOptions) -> Result<(), Error> {
     match magic {
-        b"ZL" => map_res(rest, |bytes| {
-            let mut decoder = ZlibDecoder::new(bytes);
-            let nbytes = decoder.read(output)?;
-            Ok::<_, Error>(nbytes)
-        })(bytes),
-        b"XZ" => map_res(rest, |bytes| {
+        b"ZL" => read_exactly(ZlibDecoder::new(bytes), output),
+        b"CS" => read_exactly(DeflateDecoder::new(bytes), output),
+        b"XZ" => {
             let mut reader = std::io::BufReader::new(bytes);
-            xz_decompress(&mut reader, &mut output).unwrap();
-            Ok::<_, Error>(output.len()) // XXX correct?
-        })(bytes),
+            let mut cursor = std::io::Cursor::new(&mut *output);
+            xz_decompress(&mut reader, &mut cursor).map_err(|e| format_err!("XZ decompression failed: {:?}", e))?;
+            let written = cursor.position() as usize;
+            if written != output.len() {
+                return Err(format_err!("Block decompressed to {} bytes, its header says {}", written, output.len()));
+            }
+            Ok(())
+        }
         b"L4" => {
-            let (bytes, _checksum) = be_u64(bytes)?;
-            map_res(rest, |bytes| {
-                // XXX this *sucks* but the lz4 library takes *a fixed vector type* not a *slice* as
-                // output argument :rolls_eyes:
-                let vec = lz4_decompress(bytes)?;
-                // copy vec to output
-                output.copy_from_slice(&vec);
-                Ok::<_, Error>(vec.len())
-            })(bytes)
+            // XXX this *sucks* but the lz4 library takes *a fixed vector type* not a *slice* as
+            // output argument :rolls_eyes:
+            let vec = lz4_decompress(lz4_block(bytes, options)?)?;
+            if vec.len() != output.len() {
+                return Err(format_err!("Block decompressed to {} bytes, its header says {}", vec.len(), output.len()));
+            }
+            output.copy_from_slice(&vec);
+            Ok(())
         }
-        _ => panic!(), // m => return Err(format_err!("Unsupported compression format `{}`", m)),
+        m => Err(format_err!("Unsupported compression format `{}`", String::from_utf8_lossy(m))),
     }
 }
 
-pub fn decompress_into<'s, 'o>(input: &'s [u8], output: &'o mut [u8]) -> nom::IResult<&'s [u8], usize> {
-    let (input, magic) = take(2usize)(input)?;
-    let (input, _header) = take(7usize)(input)?;
-    debug_print!("decompress_into scheme: {:?}", magic.iter().map(|&b| b as char).collect::<String>());
-    decode_reader_into(input, output, magic)
+/// Like `decompress`, into `output`; returns the number of bytes written.
+/// Fails if `output` is too small for the blocks.
+pub fn decompress_into(input: &[u8], output: &mut [u8]) -> Result<usize, Error> {
+    decompress_into_with(input, output, &DecompressOptions::default())
+}
+
+/// Like `decompress_into`, with the given `options`
+pub fn decompress_into_with(input: &[u8], output: &mut [u8], options: &DecompressOptions) -> Result<usize, Error> {
+    let mut written = 0;
+    for block in blocks(input) {
+        let (pos, block) = block?;
+        let len = output.len();
+        let out = output.get_mut(written..written + block.uncomp_len)
+            .ok_or_else(|| format_err!("Block at byte {} does not fit into the output buffer of {} bytes", pos, len))?;
+        decode_reader_into(block.data, out, block.magic, options)?;
+        written += block.uncomp_len;
+    }
+    Ok(written)
 }
 
 /// Parse a null terminated string
@@ -232,7 +377,7 @@ pub fn c_string(i: &[u8]) -> nom::IResult<&[u8], &str> {
 /// saved locally but rather in a reference to some other place in the
 /// buffer.This is modeled after ROOT's `TBufferFile::ReadObjectAny` and
 /// `TBufferFile::ReadClass`
-pub fn classinfo(i: &[u8]) -> nom::IResult<&[u8], ClassInfo> {
+pub fn classinfo(i: &[u8]) -> nom::IResult<&[u8], ClassInfo<'_>> {
     let (i, tag) = {
         let (i, bcnt) = be_u32(i)?;
         if !is_byte_count(&bcnt) || bcnt == Flags::NEW_CLASSTAG.bits() {
@@ -268,6 +413,12 @@ pub fn class_name_and_buffer<'s>(
 ) -> nom::IResult<&'s [u8], (&'s str, &'s [u8])> {
     let ctx_offset = u32::try_from(context.offset)
         .expect("Encountered pointer larger than 32 bits. Please file a bug.");
+    // the context buffer from the absolute position `abs_offset` on
+    let at = |abs_offset: u32| {
+        abs_offset.checked_sub(ctx_offset)
+            .and_then(|pos| context.s.get(pos as usize..))
+            .ok_or(nom::Err::Failure(nom::error::Error::new(i, nom::error::ErrorKind::Eof)))
+    };
     let (i, ci) = classinfo(i)?;
     Ok(match ci {
         ClassInfo::New(s) => {
@@ -277,8 +428,7 @@ pub fn class_name_and_buffer<'s>(
         ClassInfo::Exists(tag) => {
             let name = {
                 let abs_offset = tag & !Flags::CLASS_MASK.bits();
-                let s = &context.s[((abs_offset - ctx_offset) as usize)..];
-                let (_, (name, _)) = class_name_and_buffer(s, context)?;
+                let (_, (name, _)) = class_name_and_buffer(at(abs_offset)?, context)?;
                 name
             };
             let (i, buf) = length_value(checked_byte_count, rest)(i)?;
@@ -291,8 +441,7 @@ pub fn class_name_and_buffer<'s>(
                 if abs_offset == 0 {
                     ("", &context.s[..0])
                 } else {
-                    let s = &context.s[((abs_offset - ctx_offset) as usize)..];
-                    let (_, (name, buf)) = class_name_and_buffer(s, context)?;
+                    let (_, (name, buf)) = class_name_and_buffer(at(abs_offset)?, context)?;
                     (name, buf)
                 }
             };
@@ -311,7 +460,7 @@ pub fn raw<'s>(input: &'s [u8], context: &'s Context) -> nom::IResult<&'s [u8],
 /// Same as `raw` but doesn't require a `Context` as input. Panics if
 /// a `Context` is required to parse the underlying buffer (i.e., the
 /// given buffer contains a reference to some other part of the file.
-pub fn raw_no_context(input: &[u8]) -> nom::IResult<&[u8], (ClassInfo, &[u8])> {
+pub fn raw_no_context(input: &[u8]) -> nom::IResult<&[u8], (ClassInfo<'_>, &[u8])> {
     use super::ClassInfo::*;
     let (input, ci) = classinfo(input)?;
     let (input, obj) = match ci {
@@ -369,7 +518,7 @@ where
 }
 
 /// parse iobits
-pub fn tiobits<'s>(i: &'s [u8]) -> IResult<&'s [u8], u8> {
+pub fn tiobits(i: &[u8]) -> IResult<&[u8], u8> {
     let (i, _nbyte) = be_u32(i)?;
     let (i, _ver) = be_u16(i)?;
     // random 4 bytes for some reason, see
@@ -414,3 +563,89 @@ mod classinfo_test {
         assert_eq!(i.len(), 352);
     }
 }
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod decompress_test {
+    use std::io::Write;
+
+    use flate2::{write::DeflateEncoder, Compression};
+
+    use super::{decompress, decompress_into, decompress_into_with, decompress_with, lz4_checksum, ChecksumMismatch, DecompressOptions};
+
+    /// An `L4` compressed buffer holding `data`, with ROOT's header and checksum
+    fn lz4_buffer(data: &[u8]) -> Vec<u8> {
+        let block = lz4_compress::compress(data);
+        let csize = (block.len() + 8) as u32;
+        let mut buf = b"L4\x01".to_vec();
+        buf.extend_from_slice(&csize.to_le_bytes()[..3]);
+        buf.extend_from_slice(&(data.len() as u32).to_le_bytes()[..3]);
+        buf.extend_from_slice(&lz4_checksum(&block).to_be_bytes());
+        buf.extend_from_slice(&block);
+        buf
+    }
+
+    #[test]
+    fn lz4_checksums() {
+        let data: Vec<u8> = (0..1000u32).flat_map(|i| (i % 7).to_be_bytes()).collect();
+        let mut buf = lz4_buffer(&data);
+        assert_eq!(decompress(&buf).unwrap(), data);
+
+        // corrupt the checksum itself so the block stays decodable
+        buf[9] ^= 0xff;
+        let err = decompress(&buf).unwrap_err();
+        let mismatch = err.downcast_ref::<ChecksumMismatch>().expect("typed error");
+        assert_ne!(mismatch.stored, mismatch.computed);
+
+        let unchecked = DecompressOptions::default().with_lz4_checksums(false);
+        assert_eq!(decompress_with(&buf, &unchecked).unwrap(), data);
+        let mut out = vec![0; data.len()];
+        assert!(decompress_into(&buf, &mut out).is_err());
+        assert_eq!(decompress_into_with(&buf, &mut out, &unchecked).unwrap(), data.len());
+        assert_eq!(out, data);
+    }
+
+    /// A `CS` compressed buffer holding `data`
+    fn cs_buffer(data: &[u8]) -> Vec<u8> {
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(data).unwrap();
+        let block = encoder.finish().unwrap();
+        let mut buf = b"CS\x08".to_vec();
+        buf.extend_from_slice(&(block.len() as u32).to_le_bytes()[..3]);
+        buf.extend_from_slice(&(data.len() as u32).to_le_bytes()[..3]);
+        buf.extend_from_slice(&block);
+        buf
+    }
+
+    #[test]
+    fn legacy_cs() {
+        let data: Vec<u8> = (0..1000u32).flat_map(|i| (i % 7).to_be_bytes()).collect();
+        let buf = cs_buffer(&data);
+
+        assert_eq!(decompress(&buf).unwrap(), data);
+        let mut output = vec![0; data.len()];
+        assert_eq!(decompress_into(&buf, &mut output).unwrap(), data.len());
+        assert_eq!(output, data);
+    }
+
+    #[test]
+    fn multiple_blocks_into() {
+        let (first, second) = (vec![1u8; 300], (0..200u8).collect::<Vec<_>>());
+        let mut buf = cs_buffer(&first);
+        buf.extend_from_slice(&lz4_buffer(&second));
+        let mut output = vec![0; 500];
+        assert_eq!(decompress_into(&buf, &mut output).unwrap(), 500);
+        assert_eq!(output, [first.clone(), second].concat());
+        assert!(decompress_into(&buf, &mut output[..499]).is_err());
+
+        // a header claiming more data than the stream holds
+        let mut buf = cs_buffer(&first);
+        buf[6..9].copy_from_slice(&301u32.to_le_bytes()[..3]);
+        assert!(decompress_into(&buf, &mut vec![0; 301]).is_err());
+    }
+
+    #[test]
+    fn unknown_compression() {
+        assert!(decompress(b"QQ\x01\x00\x00\x00\x00\x00\x00").is_err());
+        assert!(decompress(b"L4").is_err());
+    }
+}
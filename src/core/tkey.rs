@@ -1,7 +1,9 @@
 use nom::{
-    bytes::complete::take, combinator::map, multi::length_count, number::complete::*, IResult,
+    combinator::map, multi::length_count, number::complete::*, IResult,
 };
 
+use failure::Error;
+
 use crate::core::*;
 
 #[derive(Debug, Clone)]
@@ -10,14 +12,14 @@ pub struct TKeyHeader {
     pub(crate) total_size: u32,
     version: u16,
     pub(crate) uncomp_len: u32,
-    datime: u32,
+    pub(crate) datime: u32,
     pub(crate) key_len: i16,
-    cycle: i16,
+    pub(crate) cycle: i16,
     pub(crate) seek_key: SeekPointer,
-    seek_pdir: SeekPointer,
+    pub(crate) seek_pdir: SeekPointer,
     pub(crate) class_name: String,
     pub(crate) obj_name: String,
-    obj_title: String,
+    pub(crate) obj_title: String,
 }
 
 /// A `TKey` wraps a streamed oject. The object is decompress when
@@ -70,17 +72,20 @@ fn seek_point(input: &[u8], version: u16) -> nom::IResult<&[u8], u64> {
     }
 }
 
-/// Parse a full TKey including its payload
-pub fn tkey(input: &[u8]) -> nom::IResult<&[u8], TKey> {
-    let (input, hdr) = tkey_header(input)?;
-    let (input, obj) = take(hdr.total_size - hdr.key_len as u32)(input)?;
+/// Parse a full TKey including its payload. Fails with the error of
+/// `decompress`, e.g. a `ChecksumMismatch`, if the payload is damaged.
+pub fn tkey(input: &[u8]) -> Result<TKey, Error> {
+    let (input, hdr) = tkey_header(input).map_err(|_| format_err!("Failed to parse key header"))?;
+    let obj = hdr.total_size.checked_sub(hdr.key_len.max(0) as u32)
+        .and_then(|len| input.get(..len as usize))
+        .ok_or_else(|| format_err!("Key `{}` of {} bytes is truncated", hdr.obj_name, hdr.total_size))?;
     let obj = if hdr.uncomp_len as usize > obj.len() {
         println!("decompressing tkey!");
-        decompress(obj).unwrap().1
+        decompress(obj)?
     } else {
         obj.to_vec()
     };
-    Ok((input, TKey { hdr, obj }))
+    Ok(TKey { hdr, obj })
 }
 
 /// Special thing for the keylist in the file header
@@ -54,6 +54,44 @@ pub struct TNamed {
     pub title: String,
 }
 
+/// A `TDatime` time stamp as written by ROOT, e.g. in key headers. ROOT
+/// stores the local time of the writing machine without a time zone.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Datime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl Datime {
+    /// Decode the packed representation: 6 bits of year since 1995,
+    /// followed by 4 bits month, 5 bits day, 5 bits hour, 6 bits minute
+    /// and 6 bits second
+    pub fn from_raw(raw: u32) -> Self {
+        Datime {
+            year: (raw >> 26) as u16 + 1995,
+            month: ((raw >> 22) & 0xf) as u8,
+            day: ((raw >> 17) & 0x1f) as u8,
+            hour: ((raw >> 12) & 0x1f) as u8,
+            minute: ((raw >> 6) & 0x3f) as u8,
+            second: (raw & 0x3f) as u8,
+        }
+    }
+}
+
+impl fmt::Display for Datime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
 /// A type holding nothing but the original data and a class info object
 pub struct Raw<'s> {
     pub(crate) classinfo: &'s str,
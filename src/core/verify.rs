@@ -0,0 +1,254 @@
+//! Integrity check of a whole file, see `RootFile::verify`. Every key of
+//! the file and of its directories is read and decompressed, and every
+//! basket of every branch of its trees is checked against the metadata of
+//! the tree. Checking stops at the first damaged object.
+
+use std::fmt;
+
+use failure::Fail;
+
+use crate::core::*;
+use crate::tree_reader::{basket_header, Container, TBranch, Tree};
+
+/// The first damaged object found by `RootFile::verify`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Corruption {
+    /// The damaged key or basket, e.g. ``basket 3 of branch `px` of `events;1` ``
+    pub object: String,
+    /// Position of the object in the file; for baskets held in their
+    /// `TBranch`, the position of the tree
+    pub offset: u64,
+    pub problem: String,
+}
+
+impl fmt::Display for Corruption {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at offset {}: {}", self.object, self.offset, self.problem)
+    }
+}
+
+impl Fail for Corruption {}
+
+/// Objects checked by a successful `RootFile::verify`
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct VerifyReport {
+    /// Keys of the file and of all its directories
+    pub keys: usize,
+    pub trees: usize,
+    pub baskets: usize,
+    /// Bytes of all keys and baskets as stored in the file
+    pub disk_bytes: u64,
+    /// Bytes of all keys and baskets after decompression
+    pub uncompressed_bytes: u64,
+}
+
+/// Decompress the blocks of `data`, checking that they add up to
+/// `uncomp_len`; `decompress_with` checks the blocks themselves
+fn decompress_blocks(data: &[u8], uncomp_len: usize, options: &DecompressOptions) -> Result<Vec<u8>, String> {
+    let out = decompress_with(data, options).map_err(|e| e.to_string())?;
+    if out.len() != uncomp_len {
+        return Err(format!("decompressed to {} bytes, the key says {}", out.len(), uncomp_len));
+    }
+    Ok(out)
+}
+
+/// The object stored after a key, decompressed if needed
+fn object_data(data: &[u8], uncomp_len: usize, options: &DecompressOptions) -> Result<Vec<u8>, String> {
+    if uncomp_len > data.len() {
+        decompress_blocks(data, uncomp_len, options)
+    } else {
+        Ok(data.to_vec())
+    }
+}
+
+struct Verifier<'a> {
+    file: &'a RootFile,
+    options: DecompressOptions,
+    report: VerifyReport,
+}
+
+impl Verifier<'_> {
+    fn check_keys(&mut self, items: &[FileItem], prefix: &str) -> Result<(), Corruption> {
+        for item in items {
+            let path = format!("{}{};{}", prefix, item.obj_name(), item.cycle());
+            let object = format!("key `{}` ({})", path, item.root_class());
+            let corrupt = |problem: String| Corruption { object: object.clone(), offset: item.seek_key(), problem };
+            self.check_key(item).map_err(corrupt)?;
+            if item.is_directory() {
+                let items = self.file.directory_items(item).map_err(|e| corrupt(e.to_string()))?;
+                self.check_keys(&items, &format!("{}{}/", prefix, item.obj_name()))?;
+            } else if item.root_class() == "TTree" {
+                let tree = item.as_tree().map_err(|e| corrupt(e.to_string()))?;
+                self.check_tree(&tree, &path, item.seek_key())?;
+            }
+        }
+        Ok(())
+    }
+
+    fn check_key(&mut self, item: &FileItem) -> Result<(), String> {
+        let listed = item.tkey_hdr();
+        let buf = self.file.source().fetch(listed.seek_key, u64::from(listed.total_size))
+            .map_err(|e| format!("cannot read {} bytes: {}", listed.total_size, e))?;
+        let (_, hdr) = tkey_header(&buf).map_err(|_| "cannot parse the key".to_string())?;
+        if (&hdr.class_name, &hdr.obj_name, hdr.cycle, hdr.key_len) != (&listed.class_name, &listed.obj_name, listed.cycle, listed.key_len) {
+            return Err(format!("key `{};{}` ({}) does not match its entry in the key list", hdr.obj_name, hdr.cycle, hdr.class_name));
+        }
+        let data = buf.get(hdr.key_len.max(0) as usize..)
+            .ok_or_else(|| format!("key length {} exceeds the record", hdr.key_len))?;
+        let object = object_data(data, hdr.uncomp_len as usize, &self.options)?;
+        self.report.keys += 1;
+        self.report.disk_bytes += buf.len() as u64;
+        self.report.uncompressed_bytes += object.len() as u64;
+        Ok(())
+    }
+
+    fn check_tree(&mut self, tree: &Tree, path: &str, offset: u64) -> Result<(), Corruption> {
+        let mut branches = tree.branches();
+        // leaf branches are listed twice, as main branch and as their own end point
+        branches.dedup_by(|a, b| std::ptr::eq(*a, *b));
+        for branch in branches {
+            let corrupt = |problem: String| Corruption { object: format!("branch `{}` of `{}`", branch.name, path), offset, problem };
+            check_basket_entries(branch).map_err(corrupt)?;
+            for (idx, container) in branch.containers().iter().enumerate() {
+                let expected = branch.container_start_indices().get(idx + 1).copied()
+                    .unwrap_or(branch.entries() as Tid) - branch.container_start_indices()[idx];
+                let object = format!("basket {} of branch `{}` of `{}`", idx, branch.name, path);
+                let (offset, result) = match container {
+                    Container::OnDisk(source, seek, len) => (*seek, source.fetch(*seek, *len)
+                        .map_err(|e| format!("cannot read {} bytes: {}", len, e))
+                        .and_then(|buf| self.check_basket(&buf, Some(*len), expected))),
+                    Container::InMemory(buf) => (offset, self.check_basket(buf, None, expected)),
+                };
+                result.map_err(|problem| Corruption { object, offset, problem })?;
+            }
+        }
+        self.report.trees += 1;
+        Ok(())
+    }
+
+    /// Check a basket holding `expected` entries, stored in `len` bytes if on disk
+    fn check_basket(&mut self, buf: &[u8], len: Option<u64>, expected: Tid) -> Result<(), String> {
+        let (_, basket) = basket_header(buf).map_err(|_| "cannot parse the basket header".to_string())?;
+        if basket.header.class_name != "TBasket" {
+            return Err(format!("expected a TBasket, found a key of class `{}`", basket.header.class_name));
+        }
+        if let Some(len) = len.filter(|len| *len != u64::from(basket.header.total_size)) {
+            return Err(format!("basket key spans {} bytes, the branch says {}", basket.header.total_size, len));
+        }
+        if Tid::try_from(basket.n_entry_buf).ok() != Some(expected) {
+            return Err(format!("basket holds {} entries, fBasketEntry says {}", basket.n_entry_buf, expected));
+        }
+        let useful = (basket.last as usize).checked_sub(basket.header.key_len.max(0) as usize)
+            .ok_or_else(|| format!("fLast {} is before the end of the key", basket.last))?;
+        let data = if basket.is_compressed() {
+            decompress_blocks(basket.buf, basket.header.uncomp_len as usize, &self.options)?
+        } else {
+            basket.buf.to_vec()
+        };
+        if useful > data.len() {
+            return Err(format!("fLast needs {} bytes of data, the basket holds {}", useful, data.len()));
+        }
+        self.report.baskets += 1;
+        self.report.disk_bytes += buf.len() as u64;
+        self.report.uncompressed_bytes += data.len() as u64;
+        Ok(())
+    }
+}
+
+/// Check the basket layout of `branch` against its entry count
+fn check_basket_entries(branch: &TBranch) -> Result<(), String> {
+    let starts = branch.container_start_indices();
+    if starts.len() != branch.containers().len() {
+        return Err(format!("{} baskets, but {} fBasketEntry offsets", branch.containers().len(), starts.len()));
+    }
+    if let Some(first) = starts.first().filter(|first| **first != 0) {
+        return Err(format!("first basket starts at entry {}", first));
+    }
+    if let Some(idx) = starts.windows(2).position(|w| w[0] >= w[1]) {
+        return Err(format!("fBasketEntry is not increasing at basket {}", idx + 1));
+    }
+    if let Some(last) = starts.last().filter(|last| **last as i64 >= branch.entries() && branch.entries() > 0) {
+        return Err(format!("last basket starts at entry {}, but the branch has {} entries", last, branch.entries()));
+    }
+    Ok(())
+}
+
+pub(crate) fn verify(file: &RootFile, options: &DecompressOptions) -> Result<VerifyReport, Corruption> {
+    let mut verifier = Verifier { file, options: *options, report: VerifyReport::default() };
+    verifier.check_keys(file.items(), "")?;
+    Ok(verifier.report)
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    #[test]
+    fn fixtures_are_intact() {
+        for entry in std::fs::read_dir("./src/test_data").unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().is_some_and(|ext| ext == "root") {
+                let file = RootFile::new(path.as_path()).unwrap();
+                if let Err(e) = file.verify() {
+                    panic!("{}: {}", path.display(), e);
+                }
+            }
+        }
+        let report = RootFile::new(Path::new("./src/test_data/foriter.root")).unwrap().verify().unwrap();
+        assert_eq!((report.keys, report.trees, report.baskets), (1, 1, 8));
+    }
+
+    /// A damaged copy of the fixture `name`, with `damage` applied to the
+    /// bytes of its biggest basket
+    fn damaged<F: FnOnce(&mut [u8])>(name: &str, damage: F) -> (RootFile, u64) {
+        let path = format!("./src/test_data/{}", name);
+        let basket = RootFile::new(Path::new(&path)).unwrap().byte_map().unwrap().into_iter()
+            .filter(|entry| matches!(entry.region, Region::Basket { .. }))
+            .max_by_key(|entry| entry.len)
+            .unwrap();
+        let mut data = std::fs::read(&path).unwrap();
+        damage(&mut data[basket.start as usize..(basket.start + basket.len) as usize]);
+        (RootFile::new(&*data.leak()).unwrap(), basket.start)
+    }
+
+    #[test]
+    fn lz4_checksum_mismatch() {
+        let (file, offset) = damaged("HZZ-lz4.root", |basket| *basket.last_mut().unwrap() ^= 0xff);
+        let err = file.verify().unwrap_err();
+        assert_eq!(err.offset, offset);
+        assert!(err.object.starts_with("basket "));
+        assert!(err.problem.contains("LZ4 checksum mismatch"), "{}", err);
+        let unchecked = DecompressOptions::default().with_lz4_checksums(false);
+        if let Err(err) = file.verify_with(&unchecked) {
+            assert!(!err.problem.contains("checksum"), "{}", err);
+        }
+    }
+
+    #[test]
+    fn damaged_tree() {
+        let path = "./src/test_data/Zmumu-uncompressed.root";
+        let file = RootFile::new(Path::new(path)).unwrap();
+        let item = file.items().iter().find(|item| item.root_class() == "TTree").unwrap();
+        let (start, end) = (item.seek_key() as usize, (item.seek_key() + u64::from(item.tkey_hdr().total_size)) as usize);
+        let mut data = std::fs::read(path).unwrap();
+        // a leaf of a class the parser does not know
+        let leaf = start + data[start..end].windows(6).position(|w| w == b"TLeafD").unwrap();
+        data[leaf + 5] = b'Q';
+        let err = RootFile::new(&*data.leak()).unwrap().verify().unwrap_err();
+        assert_eq!(err.object, "key `events;1` (TTree)");
+        assert_eq!(err.offset, start as u64);
+    }
+
+    #[test]
+    fn entry_count_mismatch() {
+        let (file, offset) = damaged("foriter.root", |basket| {
+            // fNevBuf follows the key, the basket version, fBufferSize and fNevBufSize
+            let pos = basket.len() - tkey_header(basket).unwrap().0.len() + 10;
+            basket[pos..pos + 4].copy_from_slice(&99u32.to_be_bytes());
+        });
+        let err = file.verify().unwrap_err();
+        assert_eq!(err.offset, offset);
+        assert!(err.problem.starts_with("basket holds 99 entries"), "{}", err);
+    }
+}
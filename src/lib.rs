@@ -10,6 +10,8 @@ extern crate nom;
 extern crate quote;
 #[macro_use]
 extern crate failure;
+#[macro_use]
+extern crate log;
 extern crate flate2;
 extern crate lzma_rs;
 
@@ -32,7 +34,13 @@ pub mod tree_reader;
 // anyblox-specific
 pub mod anyblox;
 
-pub use crate::core::{FileItem, RootFile, Source, Tid};
+#[cfg(all(feature = "capi", not(target_arch = "wasm32")))]
+pub mod capi;
+
+#[cfg(all(feature = "python", not(target_arch = "wasm32")))]
+pub mod python;
+
+pub use crate::core::{DataSource, FileItem, RootFile, Source, Tid};
 
 /// Offset when using Context; should be in `Context`, maybe?
 const MAP_OFFSET: u64 = 2;
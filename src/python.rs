@@ -0,0 +1,343 @@
+//! Python bindings, built as the `anyroot` extension module (e.g. with
+//! `maturin develop --features python`).
+//!
+//! ```python
+//! import anyroot, pyarrow as pa
+//! f = anyroot.open("events.root")
+//! f.keys()                     # [(name, class, cycle, title), ..]
+//! tree = f.tree("events")      # f.tree() picks the biggest tree
+//! tree.schema()                # pyarrow.Schema
+//! for batch in tree.batches(["px", "py"]):
+//!     ...                      # one pyarrow.RecordBatch per row group
+//! table = pa.table(tree)       # all row groups
+//! ```
+//!
+//! Batches cross over through the [Arrow PyCapsule interface](https://arrow.apache.org/docs/format/CDataInterface/PyCapsuleInterface.html):
+//! `Tree` implements `__arrow_c_schema__` and `__arrow_c_stream__`, the
+//! batches `__arrow_c_array__`, so that any Arrow library can import them
+//! without going through pyarrow. Numeric columns are stored big-endian in
+//! ROOT files, so in the default build every numeric column is copied once
+//! more after decoding, to swap it to native byte order (see
+//! `to_native_endian`). With the `slow_endian_parsing` feature they are
+//! swapped while decoding instead, which saves that copy. Only columns of
+//! types `rowgroup_to_record_batch` can decode are exposed.
+
+use std::ffi::CString;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use arrow::array::{Array, StructArray};
+use arrow::datatypes::Schema;
+use arrow::error::ArrowError;
+use arrow::ffi::{to_ffi, FFI_ArrowSchema};
+use arrow::ffi_stream::FFI_ArrowArrayStream;
+use arrow::record_batch::{RecordBatch, RecordBatchIterator};
+use pyo3::exceptions::{PyIOError, PyKeyError, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::PyCapsule;
+
+use crate::anyblox::{
+    branches_to_arrow_schema, export_schema, export_tree_item, rowgroup_to_record_batch_from_source, supported_columns,
+    to_native_endian, RowGroupIndex, DEFAULT_MAX_GAP,
+};
+use crate::core::{RootFile, Source};
+
+fn value_err<E: std::fmt::Display>(e: E) -> PyErr {
+    PyValueError::new_err(e.to_string())
+}
+
+fn schema_capsule<'py>(py: Python<'py>, schema: &Schema) -> PyResult<Bound<'py, PyCapsule>> {
+    let schema = FFI_ArrowSchema::try_from(schema).map_err(value_err)?;
+    PyCapsule::new(py, schema, Some(CString::new("arrow_schema")?))
+}
+
+/// An open ROOT file
+#[pyclass(name = "File", module = "anyroot", frozen)]
+pub struct PyRootFile {
+    file: RootFile,
+}
+
+#[pymethods]
+impl PyRootFile {
+    /// `(name, class, cycle, title)` of every object in the file
+    fn keys(&self) -> Vec<(String, String, i16, String)> {
+        self.file.items().iter()
+            .map(|item| (item.obj_name().to_string(), item.root_class(), item.cycle(), item.title().to_string()))
+            .collect()
+    }
+
+    /// Names of the trees in the file, highest cycles only
+    fn trees(&self) -> Vec<String> {
+        self.file.latest_items().into_iter()
+            .filter(|item| item.root_class() == "TTree")
+            .map(|item| item.obj_name().to_string())
+            .collect()
+    }
+
+    /// The tree `name` (`name` or `name;cycle`), or the biggest tree
+    #[pyo3(signature = (name=None))]
+    fn tree(&self, name: Option<&str>) -> PyResult<PyTree> {
+        PyTree::new(&self.file, name).map_err(|e| PyKeyError::new_err(e.to_string()))
+    }
+}
+
+/// A tree of an open file, with its row group layout
+#[pyclass(name = "Tree", module = "anyroot", frozen)]
+pub struct PyTree {
+    source: Source,
+    index: Arc<RowGroupIndex>,
+    /// export schema of all supported columns, see `export_schema`
+    schema: Arc<Schema>,
+    name: String,
+}
+
+impl PyTree {
+    fn new(file: &RootFile, name: Option<&str>) -> Result<Self, failure::Error> {
+        let item = export_tree_item(file, name)?;
+        let index = RowGroupIndex::from_tree(&item.as_tree()?)?;
+        let schema = export_schema(item, &index, supported_columns(&index.columns));
+        Ok(PyTree {
+            source: file.source().clone(),
+            index: Arc::new(index),
+            schema: Arc::new(schema),
+            name: item.obj_name().to_string(),
+        })
+    }
+
+    /// Column mask of `columns`, all supported columns if `None`
+    fn colmask(&self, columns: Option<Vec<String>>) -> PyResult<u64> {
+        self.index.colmask(columns.as_deref()).map_err(|e| PyKeyError::new_err(e.to_string()))
+    }
+
+    fn row_group_batches(&self, colmask: u64) -> RowGroupBatches {
+        let fields = self.schema.fields().iter()
+            .filter(|field| colmask & 1 << self.index.columns.iter().position(|(col, _)| col == field.name()).unwrap() != 0)
+            .cloned()
+            .collect::<Vec<_>>();
+        RowGroupBatches {
+            source: self.source.clone(),
+            index: self.index.clone(),
+            colmask,
+            decode_schema: Arc::new(branches_to_arrow_schema(&self.index.columns, colmask)),
+            schema: Arc::new(Schema::new_with_metadata(fields, self.schema.metadata().clone())),
+            next: 0,
+        }
+    }
+}
+
+#[pymethods]
+impl PyTree {
+    #[getter]
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    #[getter]
+    fn num_entries(&self) -> u64 {
+        self.index.tuples as u64
+    }
+
+    #[getter]
+    fn num_row_groups(&self) -> usize {
+        self.index.rowgroups.len()
+    }
+
+    /// `(name, ROOT type)` of all columns, including unsupported ones
+    #[getter]
+    fn columns(&self) -> Vec<(String, String)> {
+        self.index.columns.clone()
+    }
+
+    /// The Arrow schema of the supported columns as a `pyarrow.Schema`
+    fn schema<'py>(slf: &Bound<'py, Self>) -> PyResult<Bound<'py, PyAny>> {
+        slf.py().import("pyarrow")?.call_method1("schema", (slf,))
+    }
+
+    /// Iterator over the row groups of `columns` (all supported columns if
+    /// `None`) as `pyarrow.RecordBatch`es. Numeric columns are copied to
+    /// swap them to native byte order, unless built with `slow_endian_parsing`.
+    #[pyo3(signature = (columns=None))]
+    fn batches(&self, columns: Option<Vec<String>>) -> PyResult<PyBatchIterator> {
+        let colmask = self.colmask(columns)?;
+        Ok(PyBatchIterator { batches: Mutex::new(self.row_group_batches(colmask)) })
+    }
+
+    fn __arrow_c_schema__<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyCapsule>> {
+        schema_capsule(py, &self.schema)
+    }
+
+    /// All row groups as an `ArrowArrayStream`, copied like in `batches`;
+    /// `requested_schema` is ignored, as the protocol allows
+    #[pyo3(signature = (requested_schema=None))]
+    fn __arrow_c_stream__<'py>(&self, py: Python<'py>, requested_schema: Option<Bound<'py, PyAny>>) -> PyResult<Bound<'py, PyCapsule>> {
+        let _ = requested_schema;
+        let mut batches = self.row_group_batches(supported_columns(&self.index.columns));
+        let schema = batches.schema.clone();
+        let reader = RecordBatchIterator::new(
+            std::iter::from_fn(move || batches.next_batch().transpose())
+                .map(|batch| batch.map_err(|e| ArrowError::ExternalError(Box::new(e.compat())))),
+            schema,
+        );
+        let stream = FFI_ArrowArrayStream::new(Box::new(reader));
+        PyCapsule::new(py, stream, Some(CString::new("arrow_array_stream")?))
+    }
+
+    fn __repr__(&self) -> String {
+        format!("<anyroot.Tree '{}' with {} entries in {} row groups>", self.name, self.index.tuples, self.index.rowgroups.len())
+    }
+}
+
+/// The row groups of a tree, decoded one at a time
+struct RowGroupBatches {
+    source: Source,
+    index: Arc<RowGroupIndex>,
+    colmask: u64,
+    decode_schema: Arc<Schema>,
+    schema: Arc<Schema>,
+    next: usize,
+}
+
+impl RowGroupBatches {
+    fn next_batch(&mut self) -> Result<Option<RecordBatch>, failure::Error> {
+        let Some(rg) = self.index.rowgroups.get(self.next) else {
+            return Ok(None);
+        };
+        self.next += 1;
+        let batch = rowgroup_to_record_batch_from_source(&self.source, self.colmask, rg, self.decode_schema.clone(), DEFAULT_MAX_GAP)?;
+        let batch = to_native_endian(&batch)?;
+        Ok(Some(RecordBatch::try_new(self.schema.clone(), batch.columns().to_vec())?))
+    }
+}
+
+/// Iterator returned by `Tree.batches`
+#[pyclass(name = "BatchIterator", module = "anyroot", frozen)]
+pub struct PyBatchIterator {
+    batches: Mutex<RowGroupBatches>,
+}
+
+impl PyBatchIterator {
+    /// Decode the next row group without holding the GIL
+    fn next_batch(&self, py: Python<'_>) -> PyResult<Option<PyRecordBatch>> {
+        py.detach(|| self.batches.lock().unwrap().next_batch())
+            .map(|batch| batch.map(PyRecordBatch))
+            .map_err(|e| PyIOError::new_err(e.to_string()))
+    }
+}
+
+#[pymethods]
+impl PyBatchIterator {
+    fn __iter__(slf: Bound<'_, Self>) -> Bound<'_, Self> {
+        slf
+    }
+
+    fn __next__<'py>(&self, py: Python<'py>) -> PyResult<Option<Bound<'py, PyAny>>> {
+        let Some(batch) = self.next_batch(py)? else {
+            return Ok(None);
+        };
+        let pyarrow = py.import("pyarrow")?;
+        Ok(Some(pyarrow.call_method1("record_batch", (batch,))?))
+    }
+}
+
+/// A decoded row group, importable by any library implementing the
+/// PyCapsule interface
+#[pyclass(name = "RecordBatch", module = "anyroot", frozen)]
+pub struct PyRecordBatch(RecordBatch);
+
+#[pymethods]
+impl PyRecordBatch {
+    #[getter]
+    fn num_rows(&self) -> usize {
+        self.0.num_rows()
+    }
+
+    fn __arrow_c_schema__<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyCapsule>> {
+        schema_capsule(py, &self.0.schema())
+    }
+
+    /// The batch as a struct array; `requested_schema` is ignored
+    #[pyo3(signature = (requested_schema=None))]
+    fn __arrow_c_array__<'py>(&self, py: Python<'py>, requested_schema: Option<Bound<'py, PyAny>>) -> PyResult<(Bound<'py, PyCapsule>, Bound<'py, PyCapsule>)> {
+        let _ = requested_schema;
+        let array = StructArray::from(self.0.clone());
+        let (array, _) = to_ffi(&array.to_data()).map_err(value_err)?;
+        // the schema of the struct array lacks the metadata of the batch schema
+        let schema = schema_capsule(py, &self.0.schema())?;
+        Ok((schema, PyCapsule::new(py, array, Some(CString::new("arrow_array")?))?))
+    }
+}
+
+/// Open the ROOT file at `path`
+#[pyfunction]
+fn open(path: PathBuf) -> PyResult<PyRootFile> {
+    let file = RootFile::open(&path).map_err(|e| PyIOError::new_err(format!("opening {}: {}", path.display(), e)))?;
+    Ok(PyRootFile { file })
+}
+
+#[pymodule]
+fn anyroot(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_function(wrap_pyfunction!(open, m)?)?;
+    m.add_class::<PyRootFile>()?;
+    m.add_class::<PyTree>()?;
+    m.add_class::<PyBatchIterator>()?;
+    m.add_class::<PyRecordBatch>()?;
+    m.add("__version__", env!("CARGO_PKG_VERSION"))?;
+    Ok(())
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use super::*;
+    use arrow::array::{AsArray, RecordBatchReader};
+    use arrow::datatypes::Int32Type;
+    use arrow::ffi::{from_ffi, FFI_ArrowArray};
+    use arrow::ffi_stream::ArrowArrayStreamReader;
+
+    fn foriter() -> PyRootFile {
+        open(PathBuf::from("./src/test_data/foriter.root")).unwrap()
+    }
+
+    /// Move the struct out of a capsule, leaving a released one behind
+    unsafe fn take<T>(capsule: &Bound<'_, PyCapsule>, name: &str, empty: T) -> T {
+        let ptr = capsule.pointer_checked(Some(&CString::new(name).unwrap())).unwrap();
+        std::ptr::replace(ptr.as_ptr() as *mut T, empty)
+    }
+
+    #[test]
+    fn keys_and_trees() {
+        let file = foriter();
+        assert_eq!(file.trees(), ["foriter"]);
+        assert!(file.keys().iter().any(|(name, class, _, _)| name == "foriter" && class == "TTree"));
+        let tree = file.tree(None).unwrap();
+        assert_eq!((tree.num_entries(), tree.num_row_groups()), (46, 8));
+        assert!(file.tree(Some("missing")).is_err());
+        assert!(tree.batches(Some(vec!["missing".to_string()])).is_err());
+    }
+
+    #[test]
+    fn batches_through_capsules() {
+        Python::initialize();
+        Python::attach(|py| {
+            let tree = foriter().tree(Some("foriter")).unwrap();
+            let iter = tree.batches(None).unwrap();
+            let mut values = Vec::new();
+            while let Some(batch) = iter.next_batch(py).unwrap() {
+                let (schema, array) = batch.__arrow_c_array__(py, None).unwrap();
+                let (schema, array) = unsafe {
+                    (take(&schema, "arrow_schema", FFI_ArrowSchema::empty()), take(&array, "arrow_array", FFI_ArrowArray::empty()))
+                };
+                let data = unsafe { from_ffi(array, &schema) }.unwrap();
+                let array = StructArray::from(data);
+                values.extend(array.column(0).as_primitive::<Int32Type>().values().iter().copied());
+            }
+            assert_eq!(values, (0..46).collect::<Vec<_>>());
+
+            let stream = tree.__arrow_c_stream__(py, None).unwrap();
+            let stream = unsafe { take(&stream, "arrow_array_stream", FFI_ArrowArrayStream::empty()) };
+            let reader = ArrowArrayStreamReader::try_new(stream).unwrap();
+            assert_eq!(reader.schema().metadata()[crate::anyblox::META_TREE_NAME], "foriter");
+            let rows: usize = reader.map(|b| b.unwrap().num_rows()).sum();
+            assert_eq!(rows, 46);
+        });
+    }
+}
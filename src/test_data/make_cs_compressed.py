@@ -0,0 +1,58 @@
+#!/usr/bin/env python3
+"""Derive sample-5.23.02-cs.root from sample-5.23.02-zlib.root.
+
+Every ZLIB block is turned into a block of ROOT's legacy "CS" format, the
+algorithm `kOldCompressionAlgo` of the file header: a raw deflate stream
+without the zlib header and Adler-32 checksum. The deflate stream written by
+ROOT is kept as it is. The 6 bytes of the dropped zlib framing are made up
+for by two empty deflate blocks in front of the stream (a fixed Huffman
+block and a stored block), so every block keeps its compressed size and all
+keys and offsets stay the same, without any padding after the stream.
+"""
+import struct
+import zlib
+
+SRC = "sample-5.23.02-zlib.root"
+DST = "sample-5.23.02-cs.root"
+
+# an empty non-final fixed Huffman block (bits 0, 01, 0000000), followed by
+# an empty non-final stored block (bits 0, 00, padding, LEN 0, NLEN 0xffff)
+EMPTY_BLOCKS = bytes([0x02, 0x00, 0x00, 0x00, 0xFF, 0xFF])
+HEADER_SIZE = 9
+
+data = bytearray(open(SRC, "rb").read())
+le24 = lambda b, p: int.from_bytes(b[p:p + 3], "little")
+
+version, fbegin, fend = struct.unpack_from(">iII", data, 4)
+assert version < 1000000, "small file header expected"
+# fCompress, after fSeekFree, fNbytesFree, nfree, fNbytesName and fUnits
+fcompress = struct.unpack_from(">i", data, 33)[0]
+struct.pack_into(">i", data, 33, 300 + fcompress % 100)
+
+blocks = 0
+p = fbegin
+while p < fend:
+    nbytes, _version, objlen, _datime, keylen = struct.unpack_from(">iHIIh", data, p)
+    if nbytes < 0:
+        # deleted record
+        p -= nbytes
+        continue
+    if nbytes - keylen < objlen:
+        q, end, total = p + keylen, p + nbytes, 0
+        while total < objlen:
+            assert data[q:q + 2] == b"ZL", "only ZLIB blocks are expected"
+            csize, usize = le24(data, q + 3), le24(data, q + 6)
+            payload = bytes(data[q + HEADER_SIZE:q + HEADER_SIZE + csize])
+            stream = EMPTY_BLOCKS + payload[2:-4]
+            assert len(stream) == csize
+            assert zlib.decompress(stream, -15) == zlib.decompress(payload)
+            data[q:q + 2] = b"CS"
+            data[q + HEADER_SIZE:q + HEADER_SIZE + csize] = stream
+            q += HEADER_SIZE + csize
+            total += usize
+            blocks += 1
+        assert q == end and total == objlen
+    p += nbytes
+
+open(DST, "wb").write(data)
+print("re-encoded {} blocks".format(blocks))
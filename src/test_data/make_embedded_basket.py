@@ -0,0 +1,187 @@
+#!/usr/bin/env python3
+"""Derive foriter-embedded.root from foriter.root.
+
+The last basket of the `data` branch is moved from the disk into the
+`fBaskets` array of its `TBranch`, as if the file had been autosaved before
+that basket was flushed. The basket is streamed like `TBasket::Streamer`
+does for a basket that was never written: its key and basket header
+(`fNbytes` and `fSeekKey` still 0, `fObjlen` the free buffer space), flag 12
+(a buffer and no entry offsets) and the first `fLast` bytes of the buffer,
+which begin with the copy of the key written when the basket was created.
+
+The TTree key is rewritten in place of the dropped basket, the remaining
+gap is marked as deleted and added to the free segments, as ROOT does.
+"""
+import struct
+import zlib
+
+SRC = "foriter.root"
+DST = "foriter-embedded.root"
+
+data = bytearray(open(SRC, "rb").read())
+u32 = lambda b, p: struct.unpack_from(">I", b, p)[0]
+i64 = lambda b, p: struct.unpack_from(">q", b, p)[0]
+
+
+def put(b, fmt, p, v):
+    struct.pack_into(fmt, b, p, v)
+
+
+def string(b, p):
+    n = b[p]
+    return bytes(b[p + 1:p + 1 + n]), p + 1 + n
+
+
+def key(p):
+    """Key header fields of the key at `p` in the file"""
+    nbytes, version, objlen, _datime, keylen, _cycle = struct.unpack_from(">IHIIhh", data, p)
+    seek_size = 8 if version > 1000 else 4
+    q = p + 18
+    seekkey = int.from_bytes(data[q:q + seek_size], "big")
+    q += 2 * seek_size
+    names = []
+    for _ in range(3):
+        s, q = string(data, q)
+        names.append(s.decode())
+    return dict(pos=p, nbytes=nbytes, version=version, objlen=objlen, keylen=keylen,
+                seekkey=seekkey, seek_size=seek_size, cls=names[0], name=names[1])
+
+
+fbegin, fend, fseekfree, fnbytesfree, nfree = struct.unpack_from(">IIIII", data, 8)
+keys = []
+p = fbegin
+while p < fend:
+    k = key(p)
+    keys.append(k)
+    p += k["nbytes"]
+baskets = [k for k in keys if k["cls"] == "TBasket"]
+last = max(baskets, key=lambda k: k["pos"])
+tree_key = next(k for k in keys if k["cls"] == "TTree")
+assert last["pos"] + last["nbytes"] == tree_key["pos"], "the tree must follow the last basket"
+assert last["nbytes"] == last["keylen"] + last["objlen"], "the basket must be uncompressed"
+
+# the uncompressed TTree object
+block = data[tree_key["pos"] + tree_key["keylen"]:tree_key["pos"] + tree_key["nbytes"]]
+assert block[:2] == b"ZL"
+tree = bytearray(zlib.decompress(bytes(block[9:])))
+
+# walk the TTree up to the single TBranch and its fields
+def versioned(b, p):
+    assert u32(b, p) & 0x40000000, p
+    return p + 6
+
+
+def tnamed(b, p):
+    p = versioned(b, p) + 10  # TObject
+    _, p = string(b, p)
+    _, p = string(b, p)
+    return p
+
+
+bytecounts = [0]  # objects enclosing the basket, starting with the TTree
+p = versioned(tree, 0)
+p = tnamed(tree, p)
+for _ in range(3):  # TAttLine, TAttFill, TAttMarker
+    p += 4 + (u32(tree, p) & ~0x40000000)
+tree_totbytes, tree_zipbytes = p + 8, p + 16
+
+branch_tag = tree.index(b"\xff\xff\xff\xffTBranch\x00")
+branches = branch_tag - 4 - 25  # TObjArray: byte count, version, TObject, name, size, low
+assert u32(tree, branches + 17) == 1, "exactly one branch"
+bytecounts += [branches, branch_tag - 4]
+p = branch_tag + 12
+bytecounts.append(p)
+branch_version = struct.unpack_from(">H", tree, p + 4)[0]
+assert branch_version == 12
+p = tnamed(tree, p + 6)
+p += 4 + (u32(tree, p) & ~0x40000000)  # TAttFill
+fwritebasket = p + 12
+p += 24  # fCompress, fBasketSize, fEntryOffsetLen, fWriteBasket, fEntryNumber
+fmaxbaskets = struct.unpack_from(">i", tree, p + 4)[0]
+branch_totbytes, branch_zipbytes = p + 28, p + 36
+p += 44
+for _ in range(2):  # fBranches, fLeaves
+    p += 4 + (u32(tree, p) & ~0x40000000)
+fbaskets = p
+bytecounts.append(fbaskets)
+p += 4 + (u32(tree, p) & ~0x40000000)
+fbasketbytes = p + 1
+fbasketentry = fbasketbytes + 4 * fmaxbaskets + 1
+fbasketseek = fbasketentry + 8 * fmaxbaskets + 1
+
+nbaskets = u32(tree, fwritebasket)
+idx = nbaskets - 1
+assert int.from_bytes(tree[fbasketseek + 8 * idx:fbasketseek + 8 * idx + 8], "big") == last["pos"]
+entries = i64(tree, fbasketentry + 8 * nbaskets) - i64(tree, fbasketentry + 8 * idx)
+
+# the basket as streamed by TBasket::Streamer
+disk = data[last["pos"]:last["pos"] + last["nbytes"]]
+keylen = last["keylen"]
+header_at = keylen - 19  # version, fBufferSize, fNevBufSize, fNevBuf, fLast, flag
+version, buffer_size, entry_size = struct.unpack_from(">HII", disk, header_at)
+payload = disk[keylen:]
+assert len(payload) == entries * entry_size
+
+
+def basket_key(nevbuf, flast, flag):
+    k = bytearray(disk[:keylen])
+    put(k, ">I", 0, 0)  # fNbytes
+    put(k, ">I", 6, buffer_size - keylen)  # fObjlen
+    k[18:18 + last["seek_size"]] = bytes(last["seek_size"])  # fSeekKey
+    struct.pack_into(">HIIIIB", k, header_at, version, buffer_size, entry_size, nevbuf, flast, flag)
+    return k
+
+
+streamed = basket_key(entries, keylen + len(payload), 12) + basket_key(0, 0, 0) + payload
+obj = b"\xff\xff\xff\xffTBasket\x00" + streamed
+slot = struct.pack(">I", 0x40000000 | len(obj)) + obj
+slot_at = fbaskets + 25 + 4 * idx
+assert tree[slot_at:slot_at + 4] == bytes(4)
+delta = len(slot) - 4
+
+put(tree, ">I", fwritebasket, nbaskets - 1)
+put(tree, ">i", fbasketbytes + 4 * idx, 0)
+put(tree, ">q", fbasketseek + 8 * idx, 0)
+for pos in (branch_totbytes, branch_zipbytes, tree_totbytes, tree_zipbytes):
+    put(tree, ">q", pos, i64(tree, pos) - last["nbytes"])
+for pos in bytecounts:
+    put(tree, ">I", pos, u32(tree, pos) + delta)
+tree[slot_at:slot_at + 4] = slot
+
+# the new TTree key takes the place of the basket
+comp = zlib.compress(bytes(tree), 1)
+block = b"ZL\x08" + len(comp).to_bytes(3, "little") + len(tree).to_bytes(3, "little") + comp
+start = last["pos"]
+header = bytearray(data[tree_key["pos"]:tree_key["pos"] + tree_key["keylen"]])
+old_header = bytes(header)
+put(header, ">I", 0, len(header) + len(block))
+put(header, ">I", 6, len(tree))
+header[18:18 + tree_key["seek_size"]] = start.to_bytes(tree_key["seek_size"], "big")
+record = header + block
+gap_start, gap_end = start + len(record), tree_key["pos"] + tree_key["nbytes"]
+assert gap_end - gap_start >= 4
+data[start:gap_end] = record + bytes(gap_end - gap_start)
+put(data, ">i", gap_start, gap_start - gap_end)  # deleted record
+
+# the directory's key list points to the new TTree key
+keys_list = next(k for k in keys if k["name"] == SRC and k["pos"] not in (fbegin, fseekfree))
+at = data.index(old_header, keys_list["pos"] + keys_list["keylen"])
+data[at:at + len(header)] = header
+
+# free segments: the gap, then everything after the end of the file
+free = key(fseekfree)
+assert free["pos"] + free["nbytes"] == fend
+records = data[fseekfree + free["keylen"]:fend]
+free_version, _first, free_last = struct.unpack_from(">HII", records)
+new_end = fend + 10
+freekey = bytearray(data[fseekfree:fseekfree + free["keylen"]])
+put(freekey, ">I", 0, free["keylen"] + 20)
+put(freekey, ">I", 6, 20)
+records = struct.pack(">HII", free_version, gap_start, gap_end - 1) + struct.pack(">HII", free_version, new_end, free_last)
+data[fseekfree:] = freekey + records
+put(data, ">I", 12, new_end)  # fEND
+put(data, ">I", 20, len(freekey) + 20)  # fNbytesFree
+put(data, ">I", 24, 2)  # nfree
+
+open(DST, "wb").write(data)
+print(f"{DST}: {entries} entries of basket {idx} embedded, {len(data)} bytes")
@@ -10,7 +10,7 @@ use nom::{
 
 use crate::{
     code_gen::rust::ToRustType, core::parsers::*, core::types::*,
-    tree_reader::container::Container, tree_reader::leafs::TLeaf,
+    tree_reader::container::{embedded_basket, Container}, tree_reader::leafs::TLeaf,
 };
 
 /// A `TBranch` describes one "Column" of a `TTree`
@@ -95,9 +95,8 @@ impl TBranch {
         where
             P: Fn(&[u8]) -> IResult<&[u8], T>,
             F: Fn(T, usize) -> bool {
-        let mut i = 0;
         let mut j = 0;
-       for c in self.containers().to_owned() {
+       for (i, c) in self.containers().iter().cloned().enumerate() {
            let (n_elems, buffer) = c.raw_data().unwrap();
            println!("Container {}", i);
            let res = count(&parser, n_elems as usize)(&buffer);
@@ -113,7 +112,6 @@ impl TBranch {
                },
                Err(e) => panic!("Parser failed unexpectedly {:?}", e),
            }
-           i += 1;
        }
     }
 
@@ -214,10 +212,35 @@ pub fn tbranch_hdr<'s>(raw: &Raw<'s>, ctxt: &'s Context) -> IResult<&'s [u8], TB
             length_value(checked_byte_count, |i| tbranch(i, ctxt))(i)
         }
         "TBranch" => tbranch(raw.obj, ctxt),
-        name => {
-            panic!("Unexpected Branch type {}", name);
-        },
+        // unexpected branch type
+        _ => Err(nom::Err::Failure(nom::error::Error::new(raw.obj, nom::error::ErrorKind::Tag))),
+    }
+}
+
+/// Parse `fBaskets`, a `TObjArray` of null pointers and the `TBasket`s
+/// which were never written to disk. `TBasket::Streamer` writes no byte
+/// count and version of its own, so each basket is delimited by the byte
+/// count in front of its class tag.
+fn tbaskets(i: &[u8]) -> IResult<&[u8], Vec<&[u8]>> {
+    let (i, _ver) = be_u16(i)?;
+    let (i, _tobj) = tobject(i)?;
+    let (i, _name) = c_string(i)?;
+    let (i, size) = be_i32(i)?;
+    let (i, _low) = be_i32(i)?;
+    count(tbasket_slot, size as usize)(i)
+}
+
+/// The streamed basket in a slot of `fBaskets`, empty for a null pointer
+fn tbasket_slot(i: &[u8]) -> IResult<&[u8], &[u8]> {
+    let (rest, tag) = be_u32(i)?;
+    if tag & Flags::BYTE_COUNT_MASK.bits() == 0 {
+        // null pointer or reference to a basket read before
+        return Ok((rest, &i[..0]));
     }
+    let (rest, slot) = length_data(checked_byte_count)(i)?;
+    let (obj, tag) = be_u32(slot)?;
+    let (obj, _class) = cond(tag == Flags::NEW_CLASSTAG.bits(), c_string)(obj)?;
+    Ok((rest, obj))
 }
 
 pub fn tbranch<'s>(i: &'s [u8], context: &'s Context) -> IResult<&'s [u8], TBranch> {
@@ -243,25 +266,36 @@ pub fn tbranch<'s>(i: &'s [u8], context: &'s Context) -> IResult<&'s [u8], TBran
     let (i, fleaves) = length_value(checked_byte_count, |i| {
         tobjarray(TLeaf::parse_from_raw, i, context)
     })(i)?;
-    let (i, fbaskets) = length_value(checked_byte_count, |i| {
-        tobjarray(|r, _context| Ok((&[], r.obj)), i, context)
-    })(i)?;
+    let (i, fbaskets) = length_value(checked_byte_count, tbaskets)(i)?;
     let (i, fbasketbytes) = preceded(be_u8, count(be_i32, fmaxbaskets as usize))(i)?;
     let (i, fbasketentry) = preceded(be_u8, count(be_i64, fmaxbaskets as usize))(i)?;
     let (i, fbasketseek) = preceded(be_u8, count(be_u64, fmaxbaskets as usize))(i)?;
     let (i, ffilename) = string(i)?;
 
     let name = tnamed.name;
-    let fbaskets = fbaskets
-        .into_iter()
-        .filter(|s| !s.is_empty())
-        .map(|s| Container::InMemory(s.to_vec()));
     let nbaskets = fwritebasket as usize;
+    // Baskets which were never written to disk (e.g. the last basket of a
+    // file that was not closed properly) are embedded in `fbaskets` at their
+    // basket index; they hold the entries after the on-disk baskets.
+    let fbaskets: Vec<(usize, Container)> = fbaskets
+        .into_iter()
+        .enumerate()
+        .filter(|(idx, s)| !s.is_empty() && *idx >= nbaskets && *idx < fbasketentry.len())
+        .map(|(idx, s)| match embedded_basket(s) {
+            Ok(basket) => Ok((idx, Container::InMemory(basket))),
+            Err(_) => Err(nom::Err::Failure(nom::error::Error::new(s, nom::error::ErrorKind::Verify))),
+        })
+        .collect::<Result<_, _>>()?;
     let fbasketbytes = fbasketbytes
         .into_iter()
         .take(nbaskets)
         .map(|val| val as usize);
-    let fbasketentry = fbasketentry.into_iter().map(|e| e as Tid).take(nbaskets).collect();
+    let fbasketentry = fbasketentry
+        .iter()
+        .take(nbaskets)
+        .chain(fbaskets.iter().map(|(idx, _)| &fbasketentry[*idx]))
+        .map(|e| *e as Tid)
+        .collect();
     let fbasketseek = fbasketseek.into_iter().take(nbaskets);
     let source = if ffilename.is_empty() {
         context.source.to_owned()
@@ -271,8 +305,7 @@ pub fn tbranch<'s>(i: &'s [u8], context: &'s Context) -> IResult<&'s [u8], TBran
     let containers_disk = fbasketseek
         .zip(fbasketbytes)
         .map(|(seek, len)| Container::OnDisk(source.clone(), seek, len as u64));
-    // let containers = Vec::new();
-    let containers = fbaskets.chain(containers_disk).collect();
+    let containers = containers_disk.chain(fbaskets.into_iter().map(|(_, c)| c)).collect();
     Ok((
         i,
         TBranch {
@@ -7,7 +7,8 @@ use crate::core::*;
 
 #[derive(Debug, Clone)]
 pub enum Container {
-    /// Decompressed content of a `TBasket`
+    /// Uncompressed `TBasket` which was embedded in its `TBranch`,
+    /// in the layout of an on-disk basket
     InMemory(Vec<u8>),
     /// Filename, start byte, and len of a `TBasket` on disk
     OnDisk(Source, u64, u64),
@@ -17,13 +18,10 @@ impl Container {
     /// Return the number of entries and the data; reading it from disk if necessary
     pub fn raw_data(self) -> Result<(u32, Vec<u8>), Error> {
         let buf = match self {
-            Container::InMemory(buf) => buf,
+            Container::InMemory(buf) => buf.into(),
             Container::OnDisk(source, seek, len) => source.fetch(seek, len)?,
         };
-        match tbasket2vec(buf.as_slice()) {
-            Ok((_, v)) => Ok(v),
-            _ => Err(format_err!("tbasket2vec parser failed")),
-        }
+        tbasket2vec(&buf)
     }
     // /// For debugging: Try to find the file of this container. Out of luck if the container was inlined
     // pub(crate) fn file(&self) -> Option<PathBuf> {
@@ -51,29 +49,62 @@ impl BasketHeader<'_> {
         // Not the whole buffer is filled, no, no, no, that
         // would be to easy! Its only filled up to `last`,
         // whereby we have to take the key_len into account...
-        (self.last - self.header.key_len as u32) as usize
+        (self.last as usize).saturating_sub(self.key_len())
+    }
+
+    fn key_len(&self) -> usize {
+        self.header.key_len.max(0) as usize
+    }
+
+    /// `useful_bytes`, failing if `fLast` points into the key
+    fn checked_useful_bytes(&self) -> Result<usize, Error> {
+        if (self.last as usize) < self.key_len() {
+            return Err(format_err!("basket fLast {} is before the end of its {} byte key", self.last, self.key_len()));
+        }
+        Ok(self.useful_bytes())
+    }
 
+    /// Size of the output buffer `decode_into` needs. Baskets which were
+    /// never written to disk do not have a valid `uncomp_len` yet.
+    pub fn decoded_size(&self) -> usize {
+        (self.header.uncomp_len as usize).max(self.useful_bytes())
     }
 
     pub fn is_compressed(&self) -> bool {
         self.header.uncomp_len as usize > self.buf.len()
     }
 
-    pub fn decode_into(&self, output: &mut [u8]) -> usize {
+    /// Decode the basket into `output`, returning the number of useful
+    /// bytes. Fails on corrupted data, e.g. with a `ChecksumMismatch`.
+    pub fn decode_into(&self, output: &mut [u8]) -> Result<usize, Error> {
+        self.decode_into_with(output, &DecompressOptions::default())
+    }
+
+    /// Like `decode_into`, decompressing with the given `options`
+    pub fn decode_into_with(&self, output: &mut [u8], options: &DecompressOptions) -> Result<usize, Error> {
+        let useful = self.checked_useful_bytes()?;
+        let out_len = output.len();
         if self.is_compressed() {
             let max_size = self.header.uncomp_len as usize;
-            let mut outbuf = &mut output[..max_size];
-            let nbyte = decompress_into(&self.buf, &mut outbuf).unwrap().1;
-            assert!(nbyte <= outbuf.len());
-            assert!(nbyte <= self.useful_bytes());
+            let outbuf = output.get_mut(..max_size)
+                .ok_or_else(|| format_err!("basket of {} bytes does not fit into the output buffer of {} bytes", max_size, out_len))?;
+            // the entry offsets, if any, follow the useful bytes
+            let nbyte = decompress_into_with(self.buf, outbuf, options)?;
+            if nbyte < useful {
+                return Err(format_err!("basket decompressed to {} bytes, fLast needs {}", nbyte, useful));
+            }
         } else {
-            output.copy_from_slice(&self.buf[..self.useful_bytes()]);
+            let data = self.buf.get(..useful)
+                .ok_or_else(|| format_err!("basket holds {} bytes, fLast needs {}", self.buf.len(), useful))?;
+            output.get_mut(..useful)
+                .ok_or_else(|| format_err!("basket of {} bytes does not fit into the output buffer of {} bytes", useful, out_len))?
+                .copy_from_slice(data);
         }
-        self.useful_bytes()
+        Ok(useful)
     }
 }
 
-pub fn basket_header(input: &[u8]) -> IResult<&[u8], BasketHeader> {
+pub fn basket_header(input: &[u8]) -> IResult<&[u8], BasketHeader<'_>> {
     let (input, header) = tkey_header(input)?;
     let (input, version) = be_u16(input)?;
     let (input, buf_size) = be_u32(input)?;
@@ -95,20 +126,56 @@ pub fn basket_header(input: &[u8]) -> IResult<&[u8], BasketHeader> {
 }
 
 
+/// Bring a `TBasket` streamed into the `fBaskets` of its `TBranch` (a
+/// basket that was never written to disk) into the layout of an
+/// uncompressed on-disk basket. `TBasket::Streamer` writes the key and the
+/// basket header, the entry offsets and displacements (if any) and then the
+/// first `fLast` bytes of the basket buffer, which begins with another copy
+/// of the key.
+pub(crate) fn embedded_basket(obj: &[u8]) -> Result<Vec<u8>, Error> {
+    let (_, hdr) = basket_header(obj).map_err(|_| format_err!("embedded basket has a damaged header"))?;
+    let key_len = hdr.header.key_len as usize;
+    if obj.len() - hdr.buf.len() != key_len {
+        return Err(format_err!("embedded basket header is {} bytes, its key says {}", obj.len() - hdr.buf.len(), key_len));
+    }
+    let mut flag = hdr.flag as u8;
+    let generate_offsets = flag >= 80;
+    flag -= 80 * generate_offsets as u8;
+    let displacement = flag >= 40;
+    flag -= 40 * displacement as u8;
+    if flag < 10 {
+        return Err(format_err!("embedded basket was streamed without its buffer (flag {})", hdr.flag as u8));
+    }
+    // each array is written as its length followed by `fNevBuf` integers
+    let n = hdr.n_entry_buf as usize;
+    let arrays = if flag == 11 && !generate_offsets && n > 0 { (1 + displacement as usize) * 4 * (1 + n) } else { 0 };
+    let data = hdr.buf.get(arrays..)
+        .and_then(|buffer| buffer.get(key_len..hdr.last as usize))
+        .ok_or_else(|| format_err!("embedded basket is shorter than its fLast of {} bytes", hdr.last))?;
+    let objlen = (data.len() + arrays) as u32;
+    let mut basket = obj[..key_len].to_vec();
+    basket[..4].copy_from_slice(&(key_len as u32 + objlen).to_be_bytes());
+    basket[6..10].copy_from_slice(&objlen.to_be_bytes());
+    basket.extend_from_slice(data);
+    // on disk, the entry offsets follow the data
+    basket.extend_from_slice(&hdr.buf[..arrays]);
+    Ok(basket)
+}
+
 /// Return a tuple indicating the number of elements in this basket
 /// and the content as a Vec<u8>
-fn tbasket2vec(input: &[u8]) -> IResult<&[u8], (u32, Vec<u8>)> {
-    let (input, hdr) = basket_header(input)?;
+fn tbasket2vec(input: &[u8]) -> Result<(u32, Vec<u8>), Error> {
+    let (_, hdr) = basket_header(input).map_err(|_| format_err!("tbasket2vec parser failed"))?;
     let buf = if hdr.header.uncomp_len as usize > hdr.buf.len() {
         // println!("decompressing container!");
-        decompress(hdr.buf).unwrap().1
+        decompress(hdr.buf)?
     } else {
         hdr.buf.to_vec()
     };
-    Ok((
-        input,
-        (hdr.n_entry_buf, buf.as_slice()[..hdr.useful_bytes()].to_vec()),
-    ))
+    let useful = hdr.checked_useful_bytes()?;
+    let data = buf.get(..useful)
+        .ok_or_else(|| format_err!("basket holds {} bytes, fLast needs {}", buf.len(), useful))?;
+    Ok((hdr.n_entry_buf, data.to_vec()))
 }
 
 #[cfg(test)]
@@ -64,7 +64,8 @@ impl TLeafVariant {
             "TLeafElement" => {
                 TLeafElement::parse(i, context).map(|(i, l)| (i, TLeafVariant::TLeafElement(l)))
             }
-            name => unimplemented!("Unexpected Leaf type {}", name),
+            // unexpected leaf type
+            _ => Err(nom::Err::Failure(nom::error::Error::new(i, nom::error::ErrorKind::Tag))),
         }
     }
 }
@@ -95,7 +96,8 @@ macro_rules! make_tleaf_variant {
                     fminimum,
                     fmaximum,
                 };
-                obj.verify_consistency().unwrap();
+                obj.verify_consistency()
+                    .map_err(|_| nom::Err::Failure(nom::error::Error::new(i, nom::error::ErrorKind::Verify)))?;
                 Ok((i, obj))
             }
 
@@ -104,7 +104,7 @@ impl Tree {
     pub fn branches(&self) -> Vec<&TBranch> {
         self.fbranches
             .iter()
-            .flat_map(|b| vec![b].into_iter().chain(b.branches().into_iter()))
+            .flat_map(|b| vec![b].into_iter().chain(b.branches()))
             .collect()
     }
 
@@ -121,7 +121,7 @@ impl Tree {
     pub fn branch_names_and_types(&self) -> Vec<(String, Vec<String>)> {
         self.fbranches
             .iter()
-            .flat_map(|b| vec![b].into_iter().chain(b.branches().into_iter()))
+            .flat_map(|b| vec![b].into_iter().chain(b.branches()))
             .map(|b| (b.name(), b.element_types()))
             .collect()
     }